@@ -0,0 +1,161 @@
+//! A diagnostics tab showing the log the plugin keeps in our local data, as it's written.
+//!
+//! The plugin's ETW events can't be subscribed to from within the app container, so this is the
+//! connection history it records alongside them. Capturing the provider with `wpr` or PerfView is
+//! still the way to see everything.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use windows::{
+    core::*,
+    ApplicationModel::DataTransfer::{Clipboard, DataPackage},
+    Foundation::{IReference, PropertyValue},
+    UI::Xaml::Controls::{Button, CheckBox, Orientation, ScrollViewer, StackPanel, TextBlock},
+    UI::Xaml::{RoutedEventHandler, Thickness},
+};
+use wireguard_uwp_plugin::history::{ConnectionHistory, HistoryEntry, HistoryEvent};
+
+use crate::start_timer;
+
+/// How tall the log gets before it scrolls.
+const LOG_HEIGHT: f64 = 400.;
+
+/// Build the diagnostics tab: the plugin's log, newest at the bottom and checked for new lines
+/// every second, along with buttons to copy or clear it.
+///
+/// Must be called from the UI thread.
+pub fn diagnostics_panel() -> Result<StackPanel> {
+    let panel = StackPanel::new()?;
+
+    let row = StackPanel::new()?;
+    row.SetOrientation(Orientation::Horizontal)?;
+    let button = |label: &str| -> Result<Button> {
+        let button = Button::new()?;
+        button.SetContent(IInspectable::try_from(label)?)?;
+        button.SetMargin(Thickness {
+            Left: 0.,
+            Top: 0.,
+            Right: 10.,
+            Bottom: 0.,
+        })?;
+        row.Children()?.Append(button.clone())?;
+        Ok(button)
+    };
+    let copy = button("Copy to Clipboard")?;
+    let clear = button("Clear")?;
+    let stats = CheckBox::new()?;
+    stats.SetContent(IInspectable::try_from("Show traffic stats")?)?;
+    row.Children()?.Append(stats.clone())?;
+    panel.Children()?.Append(row)?;
+
+    let log = TextBlock::new()?;
+    log.SetIsTextSelectionEnabled(true)?;
+    let scroller = ScrollViewer::new()?;
+    scroller.SetMaxHeight(LOG_HEIGHT)?;
+    scroller.SetContent(log.clone())?;
+    panel.Children()?.Append(scroller.clone())?;
+
+    // The newest entry in the log & the one it was last cleared at, if any
+    let latest = Rc::new(RefCell::new(None::<HistoryEntry>));
+    let cleared = Rc::new(RefCell::new(None::<HistoryEntry>));
+    // The lines currently shown & whether they include the traffic stats
+    let shown = Rc::new(RefCell::new(String::new()));
+    let show_stats = Rc::new(Cell::new(false));
+
+    let refresh = Rc::new({
+        let (latest, cleared, shown) = (latest.clone(), cleared.clone(), shown.clone());
+        let show_stats = show_stats.clone();
+        move || -> Result<()> {
+            let text = match ConnectionHistory::load() {
+                Ok(history) => {
+                    let entries = history.entries().cloned().collect::<Vec<_>>();
+                    *latest.borrow_mut() = entries.last().cloned();
+                    since(&entries, cleared.borrow().as_ref())
+                        .iter()
+                        .map(|entry| log_line(entry, show_stats.get()))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+                Err(err) => format!("Failed to load the plugin's log: {}", err),
+            };
+
+            // Leave the log be unless it changed, lest we keep scrolling the user back down
+            if *shown.borrow() != text {
+                log.SetText(text.as_str())?;
+                scroller.UpdateLayout()?;
+                let bottom = PropertyValue::CreateDouble(scroller.ScrollableHeight()?)?;
+                scroller.ChangeView(
+                    None::<IReference<f64>>,
+                    bottom.cast::<IReference<f64>>()?,
+                    None::<IReference<f32>>,
+                )?;
+                *shown.borrow_mut() = text;
+            }
+            Ok(())
+        }
+    });
+    refresh()?;
+    start_timer(1, {
+        let refresh = refresh.clone();
+        move || refresh()
+    })?;
+
+    copy.Click(RoutedEventHandler::new(move |_, _| {
+        let package = DataPackage::new()?;
+        package.SetText(shown.borrow().as_str())?;
+        Clipboard::SetContent(package)
+    }))?;
+    clear.Click(RoutedEventHandler::new({
+        let refresh = refresh.clone();
+        move |_, _| {
+            *cleared.borrow_mut() = latest.borrow().clone();
+            refresh()
+        }
+    }))?;
+    // Go through the sender rather than capture the box, lest it keep itself alive
+    stats.Click(RoutedEventHandler::new(move |sender, _| {
+        if let Some(stats) = sender {
+            // An unset (i.e. null) `IsChecked` comes back as an error
+            let checked = stats
+                .cast::<CheckBox>()?
+                .IsChecked()
+                .and_then(|checked| checked.Value())
+                .unwrap_or(false);
+            show_stats.set(checked);
+        }
+        refresh()
+    }))?;
+
+    Ok(panel)
+}
+
+/// The entries after `cleared`, or all of them if it's no longer in the log.
+fn since<'a>(entries: &'a [HistoryEntry], cleared: Option<&HistoryEntry>) -> &'a [HistoryEntry] {
+    let start = cleared
+        .and_then(|cleared| entries.iter().rposition(|entry| entry == cleared))
+        .map_or(0, |idx| idx + 1);
+    &entries[start..]
+}
+
+/// Format an entry as a log line: its timestamp, the event and then its fields as `key=value`.
+///
+/// The traffic stats of a disconnect are only included if `verbose`.
+fn log_line(entry: &HistoryEntry, verbose: bool) -> String {
+    let event = match entry.event {
+        HistoryEvent::Connected => "connected",
+        HistoryEvent::Disconnected => "disconnected",
+        HistoryEvent::Failed => "failed",
+    };
+    let mut line = format!("{} {} server={}", entry.timestamp, event, entry.server);
+    if let Some(reason) = &entry.reason {
+        line.push_str(&format!(" reason={:?}", reason));
+    }
+    if verbose && entry.event == HistoryEvent::Disconnected {
+        line.push_str(&format!(
+            " duration_secs={} bytes_sent={} bytes_received={}",
+            entry.duration_secs, entry.bytes_sent, entry.bytes_received
+        ));
+    }
+    line
+}
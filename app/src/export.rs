@@ -0,0 +1,93 @@
+//! Exporting a profile's config to the clipboard in the standard WireGuard INI format.
+
+use windows::{
+    core::*,
+    ApplicationModel::DataTransfer::{Clipboard, DataPackage},
+    Foundation::TypedEventHandler,
+    UI::Xaml::Controls::{CheckBox, ContentDialog, StackPanel, TextBlock},
+    UI::Xaml::TextWrapping,
+};
+use wireguard_uwp_plugin::config::WireGuardConfig;
+
+use crate::profiles::Profile;
+use crate::{render, show_error};
+
+/// Offer to copy the profile's config to the clipboard as a `.conf` usable with `wg-quick`.
+///
+/// The private key is only included if the user ticks the box for it in the dialog.
+///
+/// Must be called from the UI thread.
+pub fn export_to_clipboard(profile: &Profile) -> Result<()> {
+    let mut config = match profile.config() {
+        Some(Ok(config)) => config,
+        Some(Err(err)) => return show_error("Export failed", &err),
+        None => return show_error("Export failed", "The profile doesn't have a config."),
+    };
+    fill_endpoints(&mut config, &profile.servers());
+
+    let dialog = ContentDialog::new()?;
+    dialog.SetTitle(IInspectable::try_from("Export to Clipboard")?)?;
+    dialog.SetCloseButtonText("Cancel")?;
+
+    let panel = StackPanel::new()?;
+    let line = |text: &str| -> Result<()> {
+        let line = TextBlock::new()?;
+        line.SetText(text)?;
+        line.SetTextWrapping(TextWrapping::Wrap)?;
+        panel.Children()?.Append(line)
+    };
+    line(&format!(
+        "Copy the config of {} in the standard WireGuard format.",
+        profile.name
+    ))?;
+    line(
+        "The private key is left out unless you include it below. Anyone with it can \
+         impersonate this device to its peers, so only include it if you're moving the \
+         config somewhere you trust.",
+    )?;
+
+    let include_key = CheckBox::new()?;
+    include_key.SetContent(IInspectable::try_from(
+        "I understand the risks, include the private key",
+    )?)?;
+    panel.Children()?.Append(include_key.clone())?;
+
+    line("Other apps can read the clipboard, so clear it once you're done with the config.")?;
+
+    dialog.SetPrimaryButtonText("Copy")?;
+    dialog.PrimaryButtonClick(TypedEventHandler::new(move |_, _| {
+        // An unset (i.e. null) `IsChecked` comes back as an error
+        let private_key = include_key
+            .IsChecked()
+            .and_then(|checked| checked.Value())
+            .unwrap_or(false);
+
+        let package = DataPackage::new()?;
+        package.SetText(config.to_ini_string(private_key).as_str())?;
+        Clipboard::SetContent(package)?;
+        render(Some(if private_key {
+            "Config copied to the clipboard, including the private key."
+        } else {
+            "Config copied to the clipboard."
+        }))
+    }))?;
+
+    dialog.SetContent(panel)?;
+    dialog.ShowAsync()?;
+    Ok(())
+}
+
+/// Fill in the endpoints of any peers relying on the profile's servers, the same way the plugin
+/// picks them when connecting: the server at the peer's own index.
+fn fill_endpoints(config: &mut WireGuardConfig, servers: &[String]) {
+    for (peer, server) in config.peers.iter_mut().zip(servers) {
+        if peer.endpoint.is_none() {
+            // IPv6 addresses need brackets to tell them apart from the port
+            peer.endpoint = Some(if server.contains(':') && !server.starts_with('[') {
+                format!("[{}]:{}", server, peer.port)
+            } else {
+                format!("{}:{}", server, peer.port)
+            });
+        }
+    }
+}
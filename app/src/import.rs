@@ -0,0 +1,322 @@
+//! Importing a WireGuard config as a new VPN profile, from the clipboard or a `.conf` file
+//! opened with or dropped onto the app.
+
+use windows::{
+    core::*,
+    ApplicationModel::DataTransfer::{Clipboard, DataPackageOperation, StandardDataFormats},
+    Foundation::Collections::IVectorView,
+    Foundation::{
+        AsyncOperationCompletedHandler, IAsyncOperation, IReference, PropertyValue,
+        TypedEventHandler,
+    },
+    Networking::Vpn::VpnManagementErrorStatus,
+    Storage::{FileIO, IStorageItem, StorageFile},
+    UI::Core::{CoreDispatcherPriority, DispatchedHandler},
+    UI::Xaml::Controls::{
+        CheckBox, ContentDialog, ContentDialogButtonClickEventArgs, Page, StackPanel, TextBlock,
+        TextBox,
+    },
+    UI::Xaml::{DragEventHandler, TextWrapping, Window},
+};
+use wireguard_uwp_plugin::config::{copy_private_key, WireGuardConfig};
+use wireguard_uwp_plugin::keystore::{self, PasswordVaultStore};
+
+use crate::{profiles, render};
+
+/// The titles of the import dialog, depending on where the config came from.
+const CLIPBOARD_TITLE: &str = "Import from Clipboard";
+const FILE_TITLE: &str = "Import from File";
+
+/// A config that parsed and validated fine.
+struct Imported {
+    config: WireGuardConfig,
+    /// The config as XML, ready to be used as the profile's custom configuration
+    xml: String,
+}
+
+/// Read a config off the clipboard and offer to install it as a new profile.
+///
+/// Must be called from the UI thread.
+pub fn import_from_clipboard() -> Result<()> {
+    let content = Clipboard::GetContent()?;
+    if !content.Contains(StandardDataFormats::Text()?)? {
+        return show_dialog(
+            CLIPBOARD_TITLE,
+            Err("The clipboard doesn't contain any text.".into()),
+            None,
+        );
+    }
+
+    // Reading the text completes off the UI thread so we need the dispatcher to get back onto it
+    let dispatcher = Window::Current()?.Dispatcher()?;
+    content
+        .GetTextAsync()?
+        .SetCompleted(AsyncOperationCompletedHandler::<HSTRING>::new(
+            move |op, _| {
+                let mut imported = Some(match op {
+                    Some(op) => parse(&op.GetResults()?.to_string()),
+                    None => Err("Failed to read the clipboard.".into()),
+                });
+                dispatcher.RunAsync(
+                    CoreDispatcherPriority::Normal,
+                    DispatchedHandler::new(move || match imported.take() {
+                        Some(imported) => show_dialog(CLIPBOARD_TITLE, imported, None),
+                        None => Ok(()),
+                    }),
+                )?;
+                Ok(())
+            },
+        ))
+}
+
+/// Offer to install the config from the first of `items`, i.e. the files the app was opened with
+/// or that were dropped onto it.
+///
+/// Must be called from the UI thread.
+pub fn import_from_items(items: IVectorView<IStorageItem>) -> Result<()> {
+    // Only the one profile can be installed at a time
+    let file = items
+        .into_iter()
+        .find_map(|item| item.cast::<StorageFile>().ok());
+    let file = match file {
+        Some(file) if file.FileType()?.to_string().eq_ignore_ascii_case(".conf") => file,
+        _ => {
+            return show_dialog(
+                FILE_TITLE,
+                Err("Only WireGuard .conf files can be imported.".into()),
+                None,
+            )
+        }
+    };
+
+    // The usual WireGuard convention is to name the file after the tunnel, e.g. wg0.conf
+    let name = file.DisplayName()?.to_string();
+
+    // Reading the file completes off the UI thread so we need the dispatcher to get back onto it
+    let dispatcher = Window::Current()?.Dispatcher()?;
+    FileIO::ReadTextAsync(&file)?.SetCompleted(AsyncOperationCompletedHandler::new(move |op, _| {
+        let text = op.as_ref().map(IAsyncOperation::<HSTRING>::GetResults);
+        let mut imported = Some(match text {
+            Some(Ok(text)) => parse(&text.to_string()),
+            _ => Err("Failed to read the file, it must be UTF-8 text.".into()),
+        });
+        let name = name.clone();
+        dispatcher.RunAsync(
+            CoreDispatcherPriority::Normal,
+            DispatchedHandler::new(move || match imported.take() {
+                Some(imported) => show_dialog(FILE_TITLE, imported, Some(&name)),
+                None => Ok(()),
+            }),
+        )?;
+        Ok(())
+    }))
+}
+
+/// Let files be dropped onto `page` to import them.
+pub fn accept_drops(page: &Page) -> Result<()> {
+    page.SetAllowDrop(true)?;
+
+    // Only offer to take files, we can't tell what's in them until they're dropped
+    page.DragOver(DragEventHandler::new(|_, args| {
+        if let Some(args) = args {
+            if args
+                .DataView()?
+                .Contains(StandardDataFormats::StorageItems()?)?
+            {
+                args.SetAcceptedOperation(DataPackageOperation::Copy)?;
+            }
+        }
+        Ok(())
+    }))?;
+
+    let dispatcher = Window::Current()?.Dispatcher()?;
+    page.Drop(DragEventHandler::new(move |_, args| {
+        let view = match args {
+            Some(args) => args.DataView()?,
+            None => return Ok(()),
+        };
+        if !view.Contains(StandardDataFormats::StorageItems()?)? {
+            return Ok(());
+        }
+
+        let dispatcher = dispatcher.clone();
+        view.GetStorageItemsAsync()?
+            .SetCompleted(AsyncOperationCompletedHandler::new(move |op, _| {
+                let mut items = op.as_ref().and_then(|op| op.GetResults().ok());
+                dispatcher.RunAsync(
+                    CoreDispatcherPriority::Normal,
+                    DispatchedHandler::new(move || match items.take() {
+                        Some(items) => import_from_items(items),
+                        None => Ok(()),
+                    }),
+                )?;
+                Ok(())
+            }))
+    }))?;
+
+    Ok(())
+}
+
+/// Parse and validate the config, in whichever format it's in.
+fn parse(text: &str) -> std::result::Result<Imported, String> {
+    let config =
+        WireGuardConfig::from_str(text).map_err(|e| format!("Failed to parse config: {}", e))?;
+
+    if let Err(errors) = config.validate() {
+        let errors = errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n");
+        return Err(format!("Invalid config:\n{}", errors));
+    }
+
+    // The plugin can parse INI just fine but stick to XML for anything we install ourselves
+    let xml = if text.trim_start().starts_with('<') {
+        text.to_owned()
+    } else {
+        config.to_xml_string()
+    };
+
+    Ok(Imported { config, xml })
+}
+
+/// Show what we're about to import and let the user confirm it, or why we can't.
+///
+/// The profile name is filled in with `name_hint` if given.
+fn show_dialog(
+    title: &str,
+    imported: std::result::Result<Imported, String>,
+    name_hint: Option<&str>,
+) -> Result<()> {
+    let dialog = ContentDialog::new()?;
+    dialog.SetTitle(IInspectable::try_from(title)?)?;
+    dialog.SetCloseButtonText("Cancel")?;
+
+    let panel = StackPanel::new()?;
+    let line = |text: &str| -> Result<TextBlock> {
+        let line = TextBlock::new()?;
+        line.SetText(text)?;
+        line.SetTextWrapping(TextWrapping::Wrap)?;
+        panel.Children()?.Append(line.clone())?;
+        Ok(line)
+    };
+
+    let imported = match imported {
+        Ok(imported) => imported,
+        Err(err) => {
+            line(&err)?;
+            dialog.SetContent(panel)?;
+            dialog.ShowAsync()?;
+            return Ok(());
+        }
+    };
+
+    let config = &imported.config;
+    let addresses = config
+        .interface
+        .address
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    line(&format!("Address: {}", addresses))?;
+    for peer in &config.peers {
+        let fingerprint = peer.public_key.as_bytes()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        let allowed_ips = peer
+            .allowed_ips
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        line(&format!("Peer {}: AllowedIPs {}", fingerprint, allowed_ips))?;
+    }
+
+    // The config doesn't say where to find the server, that's part of the profile
+    let name = TextBox::new()?;
+    name.SetHeader(IInspectable::try_from("Profile name")?)?;
+    if let Some(default) = name_hint {
+        name.SetText(default)?;
+    }
+    panel.Children()?.Append(name.clone())?;
+    let server = TextBox::new()?;
+    server.SetHeader(IInspectable::try_from("Server")?)?;
+    panel.Children()?.Append(server.clone())?;
+
+    // Keep the key out of the profile itself unless the user would rather not
+    let use_vault = CheckBox::new()?;
+    use_vault.SetContent(IInspectable::try_from(
+        "Store the private key in the Credential Manager",
+    )?)?;
+    use_vault.SetIsChecked(PropertyValue::CreateBoolean(true)?.cast::<IReference<bool>>()?)?;
+    panel.Children()?.Append(use_vault.clone())?;
+    let error = line("")?;
+
+    let Imported { config, xml } = imported;
+    dialog.SetPrimaryButtonText("Install")?;
+    dialog.PrimaryButtonClick(TypedEventHandler::new(move |_, args| {
+        let args: &ContentDialogButtonClickEventArgs = match args {
+            Some(args) => args,
+            None => return Ok(()),
+        };
+
+        let (name, server) = (name.Text()?.to_string(), server.Text()?.to_string());
+        if name.trim().is_empty() || server.trim().is_empty() {
+            // Keep the dialog open so the user can fill them in
+            args.SetCancel(true)?;
+            return error.SetText("Both a profile name and server are required.");
+        }
+
+        // An unset (i.e. null) `IsChecked` comes back as an error
+        let use_vault = use_vault
+            .IsChecked()
+            .and_then(|checked| checked.Value())
+            .unwrap_or(false);
+        // The profile's name doubles as the key's name in the vault
+        let (xml, vault_key) = if use_vault {
+            (
+                keystore::with_key_reference(&config, name.trim()),
+                Some(copy_private_key(&config.interface.private_key)),
+            )
+        } else {
+            (xml.clone(), None)
+        };
+
+        let dispatcher = Window::Current()?.Dispatcher()?;
+        let resource = name.trim().to_owned();
+        profiles::add_profile(name.trim(), server.trim(), &xml)?.SetCompleted(
+            AsyncOperationCompletedHandler::new(move |op, _| {
+                let status = profiles::op_status(op)?;
+                let mut message = profiles::error_message(status)
+                    .unwrap_or("Profile installed.")
+                    .to_owned();
+
+                // Only store the key once the profile's in place, lest we clobber the key of
+                // another profile by the same name
+                if let (VpnManagementErrorStatus::Ok, Some(key)) = (status, &vault_key) {
+                    let stored = PasswordVaultStore::new()
+                        .and_then(|store| keystore::store_private_key(key, &resource, &store));
+                    if let Err(err) = stored {
+                        message = format!(
+                            "Profile installed, but storing its private key failed: {}",
+                            err.message()
+                        );
+                    }
+                }
+
+                dispatcher.RunAsync(
+                    CoreDispatcherPriority::Normal,
+                    DispatchedHandler::new(move || render(Some(&message))),
+                )?;
+                Ok(())
+            }),
+        )
+    }))?;
+
+    dialog.SetContent(panel)?;
+    dialog.ShowAsync()?;
+    Ok(())
+}
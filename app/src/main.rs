@@ -5,53 +5,158 @@
 #![windows_subsystem = "windows"]
 #![allow(non_snake_case)] // Windows naming conventions
 
+mod diagnostics;
+mod export;
+mod import;
+mod profiles;
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
 use windows::{
     self as Windows,
     core::*,
-    ApplicationModel::Activation::LaunchActivatedEventArgs,
-    Foundation::Uri,
+    ApplicationModel::Activation::{FileActivatedEventArgs, LaunchActivatedEventArgs},
+    Foundation::{AsyncOperationCompletedHandler, EventHandler, TimeSpan, TypedEventHandler, Uri},
+    Networking::Vpn::VpnManagementErrorStatus,
     Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED},
-    UI::Xaml::{Application, ApplicationInitializationCallback},
+    UI::Core::{CoreDispatcherPriority, DispatchedHandler},
+    UI::ViewManagement::{UIColorType, UISettings},
+    UI::Xaml::Controls::{Page, PivotItem, StackPanel, TextBlock},
+    UI::Xaml::Media::SolidColorBrush,
+    UI::Xaml::{
+        Application, ApplicationInitializationCallback, DispatcherTimer, FrameworkElement,
+        RoutedEventHandler,
+    },
 };
+use wireguard_uwp_plugin::app_service::{Command, Response, VpnAppService};
+use wireguard_uwp_plugin::background::TunnelStats;
+use wireguard_uwp_plugin::history::{ConnectionHistory, HistoryEntry, HistoryEvent};
+
+use profiles::{Profile, ProfileState};
+
+thread_local! {
+    /// The timers refreshing the profile details currently shown, stopped whenever we re-render.
+    static TIMERS: RefCell<Vec<DispatcherTimer>> = const { RefCell::new(vec![]) };
+
+    /// Our connection to the plugin in the background, if we managed to set one up.
+    static APP_SERVICE: RefCell<Option<VpnAppService>> = const { RefCell::new(None) };
+
+    /// The pivot tab last selected, so re-rendering doesn't take the user back to the first.
+    static SELECTED_TAB: Cell<i32> = const { Cell::new(0) };
+}
 
 /// Encapsulates our app and overrides the relevant lifecycle management methods.
 #[implement(
     extend Windows::UI::Xaml::Application,
-    override OnLaunched
+    override OnLaunched OnFileActivated
 )]
 struct App;
 
 impl App {
     /// This method get invoked when the app is initially launched.
     fn OnLaunched(&self, _args: &Option<LaunchActivatedEventArgs>) -> Result<()> {
-        use Windows::{
-            UI::Xaml::Controls::{Grid, Page, TextBlock},
-            UI::Xaml::Documents::{Hyperlink, LineBreak, Run},
-            UI::Xaml::Media::SolidColorBrush,
-            UI::Xaml::Thickness,
-            UI::Xaml::Window,
-        };
+        start()
+    }
+
+    /// This method gets invoked when the user opens a `.conf` file with our app, whether or
+    /// not it's already running.
+    fn OnFileActivated(&self, args: &Option<FileActivatedEventArgs>) -> Result<()> {
+        // Launching the app for a file skips `OnLaunched`, leaving the window empty
+        if Windows::UI::Xaml::Window::Current()?.Content().is_err() {
+            start()?;
+        }
+
+        match args {
+            Some(args) => import::import_from_items(args.Files()?),
+            None => Ok(()),
+        }
+    }
+}
 
-        // Create the initial UI
-        let content = TextBlock::new()?;
-        let inline_content = content.Inlines()?;
+/// Set up the app and show the initial UI.
+fn start() -> Result<()> {
+    // Make sure the plugin's background task is allowed to run. Failing that isn't fatal
+    // for the UI so just carry on.
+    let _ = wireguard_uwp_plugin::background::request_background_access();
 
+    // The plugin lets us know whenever the tunnel's state changes so we can re-render then.
+    // Without it we just won't notice until the next time we re-render for another reason.
+    let dispatcher = Windows::UI::Xaml::Window::Current()?.Dispatcher()?;
+    let service = VpnAppService::connect(move |_| {
+        dispatcher.RunAsync(
+            CoreDispatcherPriority::Normal,
+            DispatchedHandler::new(|| render(None)),
+        )?;
+        Ok(())
+    });
+    APP_SERVICE.with(|app_service| *app_service.borrow_mut() = service.ok());
+
+    // Grab the ambient Window created for our UWP app and set the content
+    render(None)?;
+    Windows::UI::Xaml::Window::Current()?.Activate()
+}
+
+/// (Re)build the UI from the current set of VPN profiles, showing `message` above them if given.
+///
+/// Must be called from the UI thread.
+fn render(message: Option<&str>) -> Result<()> {
+    use Windows::{
+        UI::Xaml::Controls::{Button, Grid, ListView, Pivot, SelectionChangedEventHandler},
+        UI::Xaml::Documents::{Hyperlink, LineBreak, Run},
+        UI::Xaml::Thickness,
+        UI::Xaml::Window,
+    };
+
+    // Whatever the old timers were updating is about to go away
+    TIMERS.with(|timers| {
+        for timer in timers.borrow_mut().drain(..) {
+            let _ = timer.Stop();
+        }
+    });
+
+    // Create the initial UI
+    let content = TextBlock::new()?;
+    let inline_content = content.Inlines()?;
+
+    inline_content.Append({
+        let run = Run::new()?;
+        run.SetFontSize(32.)?;
+        let color = SolidColorBrush::new()?;
+        color.SetColor(Windows::UI::Color {
+            A: 0xFF,
+            R: 0xFC,
+            G: 51,
+            B: 0x85,
+        })?;
+        run.SetForeground(color)?;
+        run.SetText("WireGuard + UWP + Rust")?;
+        run
+    })?;
+    inline_content.Append(LineBreak::new()?)?;
+    inline_content.Append(LineBreak::new()?)?;
+
+    let (profiles, message) = match profiles::list_profiles() {
+        Ok(profiles) => (profiles, message.map(str::to_owned)),
+        Err(err) => (vec![], Some(format!("Failed to get VPN profiles: {}", err))),
+    };
+
+    if let Some(message) = message {
         inline_content.Append({
             let run = Run::new()?;
-            run.SetFontSize(32.)?;
-            let color = SolidColorBrush::new()?;
-            color.SetColor(Windows::UI::Color {
-                A: 0xFF,
-                R: 0xFC,
-                G: 51,
-                B: 0x85,
-            })?;
-            run.SetForeground(color)?;
-            run.SetText("WireGuard + UWP + Rust")?;
+            run.SetText(message.as_str())?;
             run
         })?;
         inline_content.Append(LineBreak::new()?)?;
         inline_content.Append(LineBreak::new()?)?;
+    }
+
+    let profiles_panel = StackPanel::new()?;
+    if profiles.is_empty() {
+        let no_profiles = TextBlock::new()?;
+        let inline_content = no_profiles.Inlines()?;
         inline_content.Append({
             let run = Run::new()?;
             run.SetText("No profiles found ")?;
@@ -72,27 +177,484 @@ impl App {
             run.SetText("!")?;
             run
         })?;
+        profiles_panel.Children()?.Append(no_profiles)?;
+    }
 
-        let root = Page::new()?;
-        root.SetContent({
-            let grid = Grid::new()?;
-            grid.SetPadding(Thickness {
-                Left: 40.,
-                Top: 40.,
-                Right: 40.,
-                Bottom: 40.,
-            })?;
-            grid.Children()?.Append(content)?;
-            grid
+    profiles_panel.Children()?.Append({
+        let button = Button::new()?;
+        button.SetContent(IInspectable::try_from("Import from Clipboard")?)?;
+        button.Click(RoutedEventHandler::new(|_, _| {
+            import::import_from_clipboard()
+        }))?;
+        button
+    })?;
+    if !profiles.is_empty() {
+        let list = ListView::new()?;
+        let items = list.Items()?;
+        for profile in profiles {
+            items.Append(profile_item(profile)?)?;
+        }
+        profiles_panel.Children()?.Append(list)?;
+    }
+
+    let pivot = Pivot::new()?;
+    pivot
+        .Items()?
+        .Append(pivot_item("Profiles", profiles_panel)?)?;
+    pivot
+        .Items()?
+        .Append(pivot_item("History", history_panel()?)?)?;
+    pivot.Items()?.Append(pivot_item(
+        "Diagnostics",
+        diagnostics::diagnostics_panel()?,
+    )?)?;
+    pivot.SetSelectedIndex(SELECTED_TAB.with(Cell::get))?;
+    pivot.SelectionChanged(SelectionChangedEventHandler::new(|pivot, _| {
+        if let Some(pivot) = pivot {
+            let selected = pivot.cast::<Pivot>()?.SelectedIndex()?;
+            SELECTED_TAB.with(|tab| tab.set(selected));
+        }
+        Ok(())
+    }))?;
+
+    let panel = StackPanel::new()?;
+    panel.Children()?.Append(content)?;
+    panel.Children()?.Append(pivot)?;
+
+    let root = Page::new()?;
+    root.SetContent({
+        let grid = Grid::new()?;
+        grid.SetPadding(Thickness {
+            Left: 40.,
+            Top: 40.,
+            Right: 40.,
+            Bottom: 40.,
         })?;
+        grid.Children()?.Append(panel)?;
+        grid
+    })?;
+
+    // Accept configs dropped anywhere on the window
+    import::accept_drops(&root)?;
+
+    // Follow the user's light/dark preference, including any changes while we're running
+    apply_theme(&root)?;
+    root.ActualThemeChanged(TypedEventHandler::new(
+        |root: &Option<FrameworkElement>, _| match root {
+            Some(root) => apply_theme(&root.cast()?),
+            None => Ok(()),
+        },
+    ))?;
+
+    Window::Current()?.SetContent(root)
+}
+
+/// Wrap `content` up as a pivot tab titled `header`.
+fn pivot_item(header: &str, content: StackPanel) -> Result<PivotItem> {
+    let item = PivotItem::new()?;
+    item.SetHeader(IInspectable::try_from(header)?)?;
+    item.SetContent(content)?;
+    Ok(item)
+}
+
+/// Build the list of past connections, newest first, as recorded by the plugin.
+fn history_panel() -> Result<StackPanel> {
+    use Windows::UI::Xaml::Controls::ListView;
+
+    let panel = StackPanel::new()?;
+    let entries = match ConnectionHistory::load() {
+        Ok(history) => history.entries().rev().cloned().collect::<Vec<_>>(),
+        Err(err) => {
+            let error = TextBlock::new()?;
+            error.SetText(format!("Failed to load connection history: {}", err).as_str())?;
+            panel.Children()?.Append(error)?;
+            return Ok(panel);
+        }
+    };
+
+    if entries.is_empty() {
+        let empty = TextBlock::new()?;
+        empty.SetText("No connections yet.")?;
+        panel.Children()?.Append(empty)?;
+        return Ok(panel);
+    }
+
+    let list = ListView::new()?;
+    let items = list.Items()?;
+    for entry in entries {
+        let line = TextBlock::new()?;
+        line.SetText(format!("{}  {}", entry.timestamp, describe_history(&entry)).as_str())?;
+        items.Append(line)?;
+    }
+    panel.Children()?.Append(list)?;
+    Ok(panel)
+}
+
+/// Sum up a connection history entry in a line.
+fn describe_history(entry: &HistoryEntry) -> String {
+    let reason = entry
+        .reason
+        .as_ref()
+        .map(|reason| format!(": {}", reason))
+        .unwrap_or_default();
+    match entry.event {
+        HistoryEvent::Connected => format!("Connected to {}", entry.server),
+        HistoryEvent::Disconnected => format!(
+            "Disconnected from {} after {} ({} bytes sent, {} received){}",
+            entry.server,
+            format_duration(Duration::from_secs(entry.duration_secs)),
+            entry.bytes_sent,
+            entry.bytes_received,
+            reason
+        ),
+        HistoryEvent::Failed => format!("Failed to connect to {}{}", entry.server, reason),
+    }
+}
+
+/// Color the page's background and body text according to the current system theme.
+///
+/// The title keeps our brand color regardless, it works against either background.
+fn apply_theme(page: &Page) -> Result<()> {
+    let settings = UISettings::new()?;
+    let brush = |color_type| -> Result<SolidColorBrush> {
+        let brush = SolidColorBrush::new()?;
+        brush.SetColor(settings.GetColorValue(color_type)?)?;
+        Ok(brush)
+    };
+    page.SetBackground(brush(UIColorType::Background)?)?;
+    page.SetForeground(brush(UIColorType::Foreground)?)
+}
+
+/// Build the list item for a single profile: its name, buttons to toggle and export it and its
+/// details.
+fn profile_item(profile: Profile) -> Result<StackPanel> {
+    use Windows::{
+        UI::Xaml::Controls::{Button, Orientation},
+        UI::Xaml::Thickness,
+        UI::Xaml::Window,
+    };
 
-        // Grab the ambient Window created for our UWP app and set the content
-        let window = Window::Current()?;
-        window.SetContent(root)?;
-        window.Activate()
+    let item = StackPanel::new()?;
+    item.SetOrientation(Orientation::Horizontal)?;
+
+    let name = TextBlock::new()?;
+    name.SetText(profile.name.as_str())?;
+    name.SetMinWidth(200.)?;
+    item.Children()?.Append(name)?;
+
+    let details = profile_details(&profile)?;
+
+    let export = Button::new()?;
+    export.SetContent(IInspectable::try_from("Export")?)?;
+    export.SetMargin(Thickness {
+        Left: 10.,
+        Top: 0.,
+        Right: 0.,
+        Bottom: 0.,
+    })?;
+    export.Click(RoutedEventHandler::new({
+        let profile = profile.clone();
+        move |_, _| export::export_to_clipboard(&profile)
+    }))?;
+
+    let button = Button::new()?;
+    button.SetContent(IInspectable::try_from(if profile.state.is_active() {
+        "Disconnect"
+    } else {
+        "Connect"
+    })?)?;
+    button.SetMargin(Thickness {
+        Left: 20.,
+        Top: 0.,
+        Right: 0.,
+        Bottom: 0.,
+    })?;
+
+    // The operation completes off the UI thread so we need the dispatcher to get back onto it
+    let dispatcher = Window::Current()?.Dispatcher()?;
+    button.Click(RoutedEventHandler::new(move |_, _| {
+        let op = if profile.state.is_active() {
+            profile.disconnect()?
+        } else {
+            profile.connect()?
+        };
+
+        let dispatcher = dispatcher.clone();
+        op.SetCompleted(AsyncOperationCompletedHandler::new(move |op, _| {
+            let status = profiles::op_status(op)?;
+            dispatcher.RunAsync(
+                CoreDispatcherPriority::Normal,
+                DispatchedHandler::new(move || render(profiles::error_message(status))),
+            )?;
+            Ok(())
+        }))?;
+
+        // Pick up the in-progress state in the meantime
+        render(None)
+    }))?;
+    item.Children()?.Append(button)?;
+    item.Children()?.Append(export)?;
+
+    let pane = StackPanel::new()?;
+    pane.Children()?.Append(item)?;
+    pane.Children()?.Append(details)?;
+    Ok(pane)
+}
+
+/// Build the detail pane for a profile, kept up to date by a `DispatcherTimer`.
+///
+/// The tunnel stats come from our plugin over the app service, if it's running, otherwise
+/// they're shown as N/A.
+fn profile_details(profile: &Profile) -> Result<StackPanel> {
+    use Windows::UI::Xaml::Controls::{Button, Orientation, ProgressRing};
+
+    let details = StackPanel::new()?;
+    let line = || -> Result<TextBlock> {
+        let line = TextBlock::new()?;
+        details.Children()?.Append(line.clone())?;
+        Ok(line)
+    };
+    let state_line = line()?;
+    let duration_line = line()?;
+    let sent = line()?;
+    let received = line()?;
+    let last_handshake = line()?;
+
+    if let Some(row) = public_key_row(profile)? {
+        details.Children()?.Append(row)?;
+    }
+
+    let reconnect = Button::new()?;
+    reconnect.SetContent(IInspectable::try_from("Reconnect")?)?;
+    let ring = ProgressRing::new()?;
+    details.Children()?.Append({
+        let row = StackPanel::new()?;
+        row.SetOrientation(Orientation::Horizontal)?;
+        row.Children()?.Append(reconnect.clone())?;
+        row.Children()?.Append(ring.clone())?;
+        row
+    })?;
+
+    // The state as of the last tick and whether we've kicked off a reconnect ourselves
+    let state = Rc::new(Cell::new(profile.state));
+    let reconnecting = Rc::new(Cell::new(false));
+
+    // Only offer to reconnect once the profile has settled
+    let show_state = {
+        let (state, reconnecting) = (state.clone(), reconnecting.clone());
+        let (reconnect, ring) = (reconnect.clone(), ring.clone());
+        move || -> Result<()> {
+            state_line.SetText(format!("State: {}", state.get()).as_str())?;
+            let busy = reconnecting.get() || state.get().is_transitioning();
+            reconnect.SetIsEnabled(!busy)?;
+            ring.SetIsActive(busy)
+        }
+    };
+    show_state()?;
+
+    reconnect.Click(RoutedEventHandler::new({
+        let (profile, state, ring) = (profile.clone(), state.clone(), ring.clone());
+        move |sender, _| {
+            reconnecting.set(true);
+            // Go through the sender rather than capture the button, lest it keep itself alive
+            if let Some(button) = sender {
+                button.cast::<Button>()?.SetIsEnabled(false)?;
+            }
+            ring.SetIsActive(true)?;
+            reconnect_profile(profile.clone(), state.get().is_active())
+        }
+    }))?;
+
+    // Whatever the plugin last told us, updated as its answers come back
+    let latest_stats = Arc::new(Mutex::new(None));
+
+    let profile = profile.clone();
+    let update_stats = move || {
+        if let Ok(new_state) = profile.current_state() {
+            state.set(new_state);
+        }
+        show_state()?;
+
+        // Whatever stats we have can't be for this profile if it isn't up
+        let stats = latest_stats
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|_: &TunnelStats| state.get().is_active());
+        let duration = match state.get() {
+            ProfileState::Connected => stats.as_ref().and_then(|stats| stats.connected_for),
+            _ => None,
+        };
+        let duration = duration.map_or_else(|| "--".into(), format_duration);
+        duration_line.SetText(format!("Connected for {}", duration).as_str())?;
+
+        let bytes_sent = stats.as_ref().map(|stats| stats.bytes_sent.to_string());
+        sent.SetText(format!("Bytes sent: {}", or_na(bytes_sent)).as_str())?;
+        let bytes_received = stats.as_ref().map(|stats| stats.bytes_received.to_string());
+        received.SetText(format!("Bytes received: {}", or_na(bytes_received)).as_str())?;
+        let handshake = stats
+            .and_then(|stats| stats.last_handshake)
+            .map(|age| format!("{} ago", format_duration(age)));
+        last_handshake.SetText(format!("Last handshake: {}", or_na(handshake)).as_str())?;
+
+        request_stats(&latest_stats)
+    };
+    update_stats()?;
+    start_timer(1, update_stats)?;
+
+    Ok(details)
+}
+
+/// Ask the plugin for its latest stats, to be stored in `latest` once it answers.
+///
+/// `latest` is cleared if it can't, e.g. because it isn't running.
+fn request_stats(latest: &Arc<Mutex<Option<TunnelStats>>>) -> Result<()> {
+    APP_SERVICE.with(|app_service| match &*app_service.borrow() {
+        Some(app_service) => {
+            let latest = latest.clone();
+            app_service.request(Command::GetStats, move |res| {
+                *latest.lock().unwrap() = match res {
+                    Ok(Response::Stats(stats)) => Some(stats),
+                    _ => None,
+                };
+                Ok(())
+            })
+        }
+        None => Ok(()),
+    })
+}
+
+/// Build a row showing our public key from the profile's config, with a button to copy it.
+///
+/// Returns `None` if the profile has no config we can parse.
+fn public_key_row(profile: &Profile) -> Result<Option<StackPanel>> {
+    use Windows::{
+        ApplicationModel::DataTransfer::{Clipboard, DataPackage},
+        UI::Xaml::Controls::{Button, Orientation},
+        UI::Xaml::Thickness,
+    };
+
+    let config = profile.config().and_then(|config| config.ok());
+    let public_key = match config {
+        Some(config) => config.interface.public_key_base64(),
+        None => return Ok(None),
+    };
+
+    let row = StackPanel::new()?;
+    row.SetOrientation(Orientation::Horizontal)?;
+
+    let text = TextBlock::new()?;
+    text.SetText(format!("Local public key: {}", public_key).as_str())?;
+    text.SetIsTextSelectionEnabled(true)?;
+    row.Children()?.Append(text)?;
+
+    let copy = Button::new()?;
+    copy.SetContent(IInspectable::try_from("Copy")?)?;
+    copy.SetMargin(Thickness {
+        Left: 10.,
+        Top: 0.,
+        Right: 0.,
+        Bottom: 0.,
+    })?;
+    copy.Click(RoutedEventHandler::new(move |_, _| {
+        let package = DataPackage::new()?;
+        package.SetText(public_key.as_str())?;
+        Clipboard::SetContent(package)
+    }))?;
+    row.Children()?.Append(copy)?;
+
+    Ok(Some(row))
+}
+
+/// Disconnect the profile (if it's up) and connect it again, showing a dialog if either fails.
+fn reconnect_profile(profile: Profile, disconnect_first: bool) -> Result<()> {
+    use Windows::UI::Xaml::Window;
+
+    let disconnect = if disconnect_first {
+        Some(profile.disconnect()?)
+    } else {
+        None
+    };
+
+    // The operations complete off the UI thread so we need the dispatcher to get back onto it
+    let dispatcher = Window::Current()?.Dispatcher()?;
+    let finish = move |status| -> Result<()> {
+        dispatcher.RunAsync(
+            CoreDispatcherPriority::Normal,
+            DispatchedHandler::new(move || {
+                render(None)?;
+                match profiles::error_message(status) {
+                    Some(message) => show_error("Reconnect failed", message),
+                    None => Ok(()),
+                }
+            }),
+        )?;
+        Ok(())
+    };
+
+    let connect = {
+        let finish = finish.clone();
+        move || -> Result<()> {
+            let finish = finish.clone();
+            profile
+                .connect()?
+                .SetCompleted(AsyncOperationCompletedHandler::new(move |op, _| {
+                    finish(profiles::op_status(op)?)
+                }))
+        }
+    };
+
+    match disconnect {
+        None => connect(),
+        Some(op) => op.SetCompleted(AsyncOperationCompletedHandler::new(move |op, _| {
+            match profiles::op_status(op)? {
+                VpnManagementErrorStatus::Ok => connect(),
+                status => finish(status),
+            }
+        })),
+    }
+}
+
+/// Let the user know something went wrong with a simple dialog.
+fn show_error(title: &str, message: &str) -> Result<()> {
+    use Windows::UI::Xaml::Controls::ContentDialog;
+
+    let dialog = ContentDialog::new()?;
+    dialog.SetTitle(IInspectable::try_from(title)?)?;
+    dialog.SetContent(IInspectable::try_from(message)?)?;
+    dialog.SetCloseButtonText("OK")?;
+    dialog.ShowAsync()?;
+    Ok(())
+}
+
+/// Call `tick` every `secs` seconds on the UI thread, until the next call to `render`.
+fn start_timer(secs: i64, mut tick: impl FnMut() -> Result<()> + 'static) -> Result<()> {
+    let timer = DispatcherTimer::new()?;
+    // `TimeSpan` is in units of 100ns
+    timer.SetInterval(TimeSpan {
+        Duration: secs * 10_000_000,
+    })?;
+    timer.Tick(EventHandler::new(move |_, _| tick()))?;
+    timer.Start()?;
+    TIMERS.with(|timers| timers.borrow_mut().push(timer));
+    Ok(())
+}
+
+/// Format a duration as HH:MM:SS, with the number of days in front if there are any.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {:02}:{:02}:{:02}", days, hours, mins, secs)
+    } else {
+        format!("{:02}:{:02}:{:02}", hours, mins, secs)
     }
 }
 
+fn or_na(value: Option<String>) -> String {
+    value.unwrap_or_else(|| "N/A".into())
+}
+
 fn main() -> Result<()> {
     // We must initialize a COM MTA before initializing the rest of the App
     unsafe {
@@ -0,0 +1,229 @@
+//! Helpers for managing the VPN profiles installed on the system via `VpnManagementAgent`.
+
+use std::fmt;
+
+use windows::{
+    core::*,
+    ApplicationModel::Package,
+    Foundation::IAsyncOperation,
+    Networking::Vpn::{
+        IVpnProfile, VpnManagementAgent, VpnManagementConnectionStatus, VpnManagementErrorStatus,
+        VpnNativeProfile, VpnPlugInProfile,
+    },
+};
+use wireguard_uwp_plugin::config::WireGuardConfig;
+use wireguard_uwp_plugin::keystore::{self, PasswordVaultStore};
+
+/// The connection state of a VPN profile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfileState {
+    Disconnected,
+    Disconnecting,
+    Connected,
+    Connecting,
+}
+
+impl ProfileState {
+    /// Whether the user should be offered to disconnect rather than connect.
+    pub fn is_active(self) -> bool {
+        matches!(self, ProfileState::Connected | ProfileState::Connecting)
+    }
+
+    /// Whether the profile is on its way up or down.
+    pub fn is_transitioning(self) -> bool {
+        matches!(self, ProfileState::Connecting | ProfileState::Disconnecting)
+    }
+}
+
+impl From<VpnManagementConnectionStatus> for ProfileState {
+    fn from(status: VpnManagementConnectionStatus) -> Self {
+        match status {
+            VpnManagementConnectionStatus::Connected => ProfileState::Connected,
+            VpnManagementConnectionStatus::Connecting => ProfileState::Connecting,
+            VpnManagementConnectionStatus::Disconnecting => ProfileState::Disconnecting,
+            _ => ProfileState::Disconnected,
+        }
+    }
+}
+
+impl fmt::Display for ProfileState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProfileState::Disconnected => "Disconnected",
+            ProfileState::Disconnecting => "Disconnecting",
+            ProfileState::Connected => "Connected",
+            ProfileState::Connecting => "Connecting",
+        })
+    }
+}
+
+/// An installed VPN profile along with its state when we listed it.
+#[derive(Clone)]
+pub struct Profile {
+    pub name: String,
+    pub state: ProfileState,
+    profile: IVpnProfile,
+}
+
+impl Profile {
+    /// Ask the system to connect this profile.
+    pub fn connect(&self) -> Result<IAsyncOperation<VpnManagementErrorStatus>> {
+        VpnManagementAgent::new()?.ConnectProfileAsync(&self.profile)
+    }
+
+    /// Ask the system to disconnect this profile.
+    pub fn disconnect(&self) -> Result<IAsyncOperation<VpnManagementErrorStatus>> {
+        VpnManagementAgent::new()?.DisconnectProfileAsync(&self.profile)
+    }
+
+    /// Check what state the profile is in now.
+    pub fn current_state(&self) -> Result<ProfileState> {
+        connection_status(&self.profile).map(Into::into)
+    }
+
+    /// The profile's custom configuration, if it's one of ours (or any other plugin's).
+    pub fn custom_configuration(&self) -> Option<String> {
+        let plugin = self.profile.cast::<VpnPlugInProfile>().ok()?;
+        plugin
+            .CustomConfiguration()
+            .ok()
+            .map(|config| config.to_string())
+    }
+
+    /// Parse the profile's custom configuration, fetching its private key from the Credential
+    /// Manager if that's where it's kept.
+    pub fn config(&self) -> Option<std::result::Result<WireGuardConfig, String>> {
+        self.custom_configuration()
+            .map(|config| parse_config(&config))
+    }
+
+    /// The hosts of the profile's servers, in order.
+    pub fn servers(&self) -> Vec<String> {
+        let uris = self
+            .profile
+            .cast::<VpnPlugInProfile>()
+            .and_then(|plugin| plugin.ServerUris());
+        match uris {
+            Ok(uris) => uris
+                .into_iter()
+                .filter_map(|uri| uri.Host().ok())
+                .map(|host| host.to_string())
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+}
+
+/// Get all the VPN profiles installed on the system.
+pub fn list_profiles() -> Result<Vec<Profile>> {
+    let profiles = VpnManagementAgent::new()?.GetProfilesAsync()?.get()?;
+    profiles
+        .into_iter()
+        .map(|profile| {
+            Ok(Profile {
+                name: profile.ProfileName()?.to_string(),
+                state: connection_status(&profile)?.into(),
+                profile,
+            })
+        })
+        .collect()
+}
+
+/// Parse a profile's custom configuration, resolving any reference into the key store.
+fn parse_config(config: &str) -> std::result::Result<WireGuardConfig, String> {
+    let store = PasswordVaultStore::new().map_err(|e| e.message().to_string())?;
+    let config = keystore::resolve_references(config, &store).map_err(|e| e.to_string())?;
+    WireGuardConfig::from_powershell_splatting(&config)
+        .map_err(|e| format!("Failed to parse config: {}", e))
+}
+
+fn connection_status(profile: &IVpnProfile) -> Result<VpnManagementConnectionStatus> {
+    // Only the concrete profile types know their connection status
+    if let Ok(plugin) = profile.cast::<VpnPlugInProfile>() {
+        plugin.ConnectionStatus()
+    } else if let Ok(native) = profile.cast::<VpnNativeProfile>() {
+        native.ConnectionStatus()
+    } else {
+        Ok(VpnManagementConnectionStatus::Disconnected)
+    }
+}
+
+/// Install a new profile for our VPN plugin, connecting to `server` with `config` as its
+/// custom configuration.
+pub fn add_profile(
+    name: &str,
+    server: &str,
+    config: &str,
+) -> Result<IAsyncOperation<VpnManagementErrorStatus>> {
+    let family_name = Package::Current()?.Id()?.FamilyName()?.to_string();
+    let profile = format!(
+        "<VPNProfile>\
+            <ProfileName>{}</ProfileName>\
+            <PluginProfile>\
+                <ServerUrlList>{}</ServerUrlList>\
+                <CustomConfiguration>{}</CustomConfiguration>\
+                <PluginPackageFamilyName>{}</PluginPackageFamilyName>\
+            </PluginProfile>\
+        </VPNProfile>",
+        escape_xml(name),
+        escape_xml(server),
+        escape_xml(config),
+        escape_xml(&family_name),
+    );
+    VpnManagementAgent::new()?.AddProfileFromXmlAsync(profile)
+}
+
+/// Escape `s` for use as the text of an XML element.
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Get the result of a completed `VpnManagementAgent` operation.
+pub fn op_status(
+    op: &Option<IAsyncOperation<VpnManagementErrorStatus>>,
+) -> Result<VpnManagementErrorStatus> {
+    match op {
+        Some(op) => op.GetResults(),
+        None => Ok(VpnManagementErrorStatus::Other),
+    }
+}
+
+/// Map the result of a `VpnManagementAgent` operation to an error message fit for the user.
+///
+/// Returns `None` if the operation succeeded.
+pub fn error_message(status: VpnManagementErrorStatus) -> Option<&'static str> {
+    Some(match status {
+        VpnManagementErrorStatus::Ok => return None,
+        VpnManagementErrorStatus::InvalidXmlSyntax => "The profile's configuration is not valid.",
+        VpnManagementErrorStatus::ProfileNameTooLong => "The profile's name is too long.",
+        VpnManagementErrorStatus::ProfileInvalidAppId => {
+            "The profile refers to a VPN app that isn't installed."
+        }
+        VpnManagementErrorStatus::AccessDenied => "Access to the profile was denied.",
+        VpnManagementErrorStatus::CannotFindProfile => "The profile could not be found.",
+        VpnManagementErrorStatus::AlreadyDisconnecting => "The profile is already disconnecting.",
+        VpnManagementErrorStatus::AlreadyConnected => "The profile is already connected.",
+        VpnManagementErrorStatus::GeneralAuthenticationFailure
+        | VpnManagementErrorStatus::EapFailure
+        | VpnManagementErrorStatus::SmartCardFailure
+        | VpnManagementErrorStatus::CertificateFailure
+        | VpnManagementErrorStatus::UserNamePassword => "Authentication failed.",
+        VpnManagementErrorStatus::ServerConfiguration => "The server is misconfigured.",
+        VpnManagementErrorStatus::NoConnection => "There is no network connection.",
+        VpnManagementErrorStatus::ServerConnection => "Could not reach the server.",
+        VpnManagementErrorStatus::DnsNotResolvable => "Could not resolve the server's name.",
+        VpnManagementErrorStatus::InvalidIP => "The server's address is not valid.",
+        _ => "Something went wrong.",
+    })
+}
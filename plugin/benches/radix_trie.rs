@@ -0,0 +1,39 @@
+//! Lookups in a `NetworkPrefixMatcher` the size of a full routing table.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ipnetwork::{IpNetwork, Ipv4Network};
+use wireguard_uwp_plugin::utils::radix_trie::NetworkPrefixMatcher;
+
+const PREFIXES: u32 = 10_000;
+
+/// A cheap deterministic stand-in for random addresses (xorshift32).
+fn next(state: &mut u32) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state
+}
+
+fn longest_match(c: &mut Criterion) {
+    let mut state = 0x2545_f491;
+    let mut matcher = NetworkPrefixMatcher::new();
+    for i in 0..PREFIXES {
+        let prefix = 8 + (next(&mut state) % 25) as u8;
+        let network = Ipv4Network::new(Ipv4Addr::from(next(&mut state)), prefix).unwrap();
+        matcher.insert(IpNetwork::V4(network), i % 2);
+    }
+
+    let addrs = (0..1024)
+        .map(|_| IpAddr::V4(Ipv4Addr::from(next(&mut state))))
+        .collect::<Vec<_>>();
+    let mut addrs = addrs.iter().cycle();
+
+    c.bench_function("longest_match 10k prefixes", |b| {
+        b.iter(|| matcher.longest_match(black_box(*addrs.next().unwrap())))
+    });
+}
+
+criterion_group!(benches, longest_match);
+criterion_main!(benches);
@@ -0,0 +1,31 @@
+//! Build script to bake the versions of some of our dependencies into the plugin
+//! for diagnostic purposes.
+
+use std::fs;
+use std::path::Path;
+
+/// Find the version of the given package in the contents of a `Cargo.lock`.
+fn locked_version<'a>(lock: &'a str, package: &str) -> Option<&'a str> {
+    let name = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    lines.find(|line| *line == name)?;
+    lines
+        .next()?
+        .strip_prefix("version = \"")?
+        .strip_suffix('"')
+}
+
+fn main() {
+    // We're part of a workspace so the lockfile is one level up
+    let lock_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lock_path.display());
+
+    let lock = fs::read_to_string(&lock_path).unwrap_or_default();
+    for (package, var) in [
+        ("boringtun", "BORINGTUN_VERSION"),
+        ("windows", "WINDOWS_CRATE_VERSION"),
+    ] {
+        let version = locked_version(&lock, package).unwrap_or("unknown");
+        println!("cargo:rustc-env={}={}", var, version);
+    }
+}
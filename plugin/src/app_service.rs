@@ -0,0 +1,429 @@
+//! An app service letting the foreground app talk to the plugin in the background.
+//!
+//! Each request is a `ValueSet` with a `Command` (see `Command`) and gets back a `ValueSet`
+//! with a `Status` of either `ok` or `error` (along with an `Error` message), plus whatever
+//! that command returns. On top of that the plugin pushes a `state_changed` `Event` with the
+//! new `State` to every open connection whenever the tunnel's state changes, so the app
+//! doesn't have to keep asking.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use windows::{
+    self as Windows,
+    core::*,
+    ApplicationModel::AppService::{
+        AppServiceConnection, AppServiceConnectionStatus, AppServiceRequestReceivedEventArgs,
+        AppServiceResponse, AppServiceResponseStatus, AppServiceTriggerDetails,
+    },
+    ApplicationModel::Background::{BackgroundTaskCanceledEventHandler, IBackgroundTaskInstance},
+    ApplicationModel::Package,
+    Foundation::Collections::ValueSet,
+    Foundation::{
+        AsyncOperationCompletedHandler, IPropertyValue, PropertyValue, TypedEventHandler,
+    },
+    Win32::Foundation::{E_FAIL, E_UNEXPECTED},
+};
+
+use crate::background::with_plugin;
+use crate::plugin::{ConnectionState, PeerStats, TunnelStats, VpnPlugin};
+use crate::utils::debug_log;
+
+/// The name the app service is declared under in `AppxManifest.xml`.
+pub const APP_SERVICE_NAME: &str = "WireGuard-UWP.Tunnel";
+
+// The keys we use in the messages going either way
+const COMMAND_KEY: &str = "Command";
+const EVENT_KEY: &str = "Event";
+const STATUS_KEY: &str = "Status";
+const ERROR_KEY: &str = "Error";
+const STATE_KEY: &str = "State";
+const BYTES_SENT_KEY: &str = "BytesSent";
+const BYTES_RECEIVED_KEY: &str = "BytesReceived";
+const CONNECTED_FOR_KEY: &str = "ConnectedForMs";
+const LAST_HANDSHAKE_KEY: &str = "LastHandshakeMs";
+const PEER_COUNT_KEY: &str = "PeerCount";
+
+/// The `Event` we push whenever the tunnel's state changes.
+const STATE_CHANGED_EVENT: &str = "state_changed";
+
+/// Connections from the foreground app, which we push any state changes to.
+static CONNECTIONS: Mutex<Vec<AppServiceConnection>> = Mutex::new(Vec::new());
+
+/// What the foreground app can ask of the plugin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// The tunnel's `ConnectionState`
+    GetState,
+    /// The tunnel's state along with its counters, see `TunnelStats`
+    GetStats,
+    /// Each of the peers' state, see `PeerStats`
+    GetPeers,
+    /// Start over with fresh sessions to all the peers, see `VpnPlugin::force_reconnect`
+    ForceReconnect,
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Command::GetState => "get_state",
+            Command::GetStats => "get_stats",
+            Command::GetPeers => "get_peers",
+            Command::ForceReconnect => "force_reconnect",
+        })
+    }
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "get_state" => Command::GetState,
+            "get_stats" => Command::GetStats,
+            "get_peers" => Command::GetPeers,
+            "force_reconnect" => Command::ForceReconnect,
+            _ => return Err(format!("unknown command: {}", s)),
+        })
+    }
+}
+
+/// The plugin's answer to a `Command`.
+#[derive(Clone, Debug)]
+pub enum Response {
+    State(ConnectionState),
+    Stats(TunnelStats),
+    Peers(Vec<PeerStats>),
+    /// The command went through and there's nothing else to say
+    Done,
+}
+
+/// The WinRT Activatable Class which acts as the entrypoint for the background tasks
+/// the system starts whenever the foreground app connects to our app service.
+#[implement(Windows::ApplicationModel::Background::IBackgroundTask)]
+pub struct VpnAppServiceTask;
+
+impl VpnAppServiceTask {
+    fn Run(&self, task: &Option<IBackgroundTaskInstance>) -> Result<()> {
+        let task = task.as_ref().ok_or(Error::from(E_UNEXPECTED))?;
+        let details: AppServiceTriggerDetails = task.TriggerDetails()?.cast()?;
+        let connection = details.AppServiceConnection()?;
+
+        // The connection only stays open for as long as the task does
+        let deferral = Arc::new(Mutex::new(Some(task.GetDeferral()?)));
+
+        connection.RequestReceived(TypedEventHandler::new(
+            |_, args: &Option<AppServiceRequestReceivedEventArgs>| {
+                let args = match args {
+                    Some(args) => args,
+                    None => return Ok(()),
+                };
+                // Hold on to the request until the response has actually gone out
+                let deferral = args.GetDeferral()?;
+                let request = args.Request()?;
+                let response = handle_request(&request.Message()?)?;
+                request.SendResponseAsync(response)?.SetCompleted(
+                    AsyncOperationCompletedHandler::new(move |_, _| deferral.Complete()),
+                )
+            },
+        ))?;
+
+        // Whichever side goes away first, we're done with this connection
+        let close = {
+            let connection = connection.clone();
+            move || -> Result<()> {
+                CONNECTIONS
+                    .lock()
+                    .unwrap()
+                    .retain(|open| *open != connection);
+                if let Some(deferral) = deferral.lock().unwrap().take() {
+                    deferral.Complete()?;
+                }
+                Ok(())
+            }
+        };
+        connection.ServiceClosed(TypedEventHandler::new({
+            let close = close.clone();
+            move |_, _| close()
+        }))?;
+        task.Canceled(BackgroundTaskCanceledEventHandler::new(move |_, _| close()))?;
+
+        CONNECTIONS.lock().unwrap().push(connection);
+        Ok(())
+    }
+}
+
+/// Answer a request from the foreground app.
+fn handle_request(message: &ValueSet) -> Result<ValueSet> {
+    let response = ValueSet::new()?;
+    let res = lookup(message, COMMAND_KEY, |value| value.GetString())
+        .ok_or_else(|| "no command given".to_string())
+        .and_then(|command| command.to_string().parse::<Command>())
+        .and_then(|command| {
+            with_plugin(|plugin| respond(plugin, command, &response))
+                .ok_or_else(|| "the VPN plugin isn't running".to_string())?
+                .map_err(|err| err.message().to_string())
+        });
+
+    match res {
+        Ok(()) => insert(&response, STATUS_KEY, PropertyValue::CreateString("ok"))?,
+        Err(err) => {
+            insert(&response, STATUS_KEY, PropertyValue::CreateString("error"))?;
+            insert(&response, ERROR_KEY, PropertyValue::CreateString(err))?;
+        }
+    }
+    Ok(response)
+}
+
+/// Carry out `command` against the plugin, filling in `response` with the results.
+fn respond(plugin: &VpnPlugin, command: Command, response: &ValueSet) -> Result<()> {
+    match command {
+        Command::GetState => insert(
+            response,
+            STATE_KEY,
+            PropertyValue::CreateString(plugin.state().as_str()),
+        ),
+        Command::GetStats => {
+            let stats = plugin.tunnel_stats();
+            insert(
+                response,
+                STATE_KEY,
+                PropertyValue::CreateString(stats.state.as_str()),
+            )?;
+            insert(
+                response,
+                BYTES_SENT_KEY,
+                PropertyValue::CreateUInt64(stats.bytes_sent),
+            )?;
+            insert(
+                response,
+                BYTES_RECEIVED_KEY,
+                PropertyValue::CreateUInt64(stats.bytes_received),
+            )?;
+            insert_duration(response, CONNECTED_FOR_KEY, stats.connected_for)?;
+            insert_duration(response, LAST_HANDSHAKE_KEY, stats.last_handshake)
+        }
+        Command::GetPeers => {
+            let peers = plugin.peer_stats()?;
+            insert(
+                response,
+                PEER_COUNT_KEY,
+                PropertyValue::CreateUInt32(peers.len() as u32),
+            )?;
+            for (idx, peer) in peers.into_iter().enumerate() {
+                let key = |name: &str| format!("Peer{}.{}", idx, name);
+                insert(
+                    response,
+                    &key("Endpoint"),
+                    PropertyValue::CreateString(peer.endpoint),
+                )?;
+                if let Some(description) = peer.description {
+                    insert(
+                        response,
+                        &key("Description"),
+                        PropertyValue::CreateString(description),
+                    )?;
+                }
+                insert_duration(response, &key(LAST_HANDSHAKE_KEY), peer.last_handshake)?;
+            }
+            Ok(())
+        }
+        Command::ForceReconnect => plugin.force_reconnect(),
+    }
+}
+
+/// Let every connected foreground app know the tunnel moved to `state`.
+pub(crate) fn broadcast_state(state: ConnectionState) {
+    let connections = CONNECTIONS.lock().unwrap().clone();
+    for connection in connections {
+        let res = ValueSet::new().and_then(|message| {
+            insert(
+                &message,
+                EVENT_KEY,
+                PropertyValue::CreateString(STATE_CHANGED_EVENT),
+            )?;
+            insert(
+                &message,
+                STATE_KEY,
+                PropertyValue::CreateString(state.as_str()),
+            )?;
+            // Nothing to wait for, the app doesn't answer these
+            connection.SendMessageAsync(message).map(|_| ())
+        });
+        if let Err(err) = res {
+            debug_log!("Failed to push state change to app: {}", err);
+        }
+    }
+}
+
+/// The foreground app's end of the app service.
+pub struct VpnAppService {
+    connection: AppServiceConnection,
+}
+
+impl VpnAppService {
+    /// Start connecting to the plugin's app service.
+    ///
+    /// `on_state_changed` is called (off the UI thread) with the new state whenever the
+    /// tunnel's state changes. This doesn't wait for the connection to open, any requests
+    /// made in the meantime fail.
+    pub fn connect(
+        on_state_changed: impl Fn(ConnectionState) -> Result<()> + Send + 'static,
+    ) -> Result<Self> {
+        let connection = AppServiceConnection::new()?;
+        connection.SetAppServiceName(APP_SERVICE_NAME)?;
+        connection.SetPackageFamilyName(Package::Current()?.Id()?.FamilyName()?)?;
+
+        connection.RequestReceived(TypedEventHandler::new(
+            move |_, args: &Option<AppServiceRequestReceivedEventArgs>| {
+                let message = match args {
+                    Some(args) => args.Request()?.Message()?,
+                    None => return Ok(()),
+                };
+                let event = lookup(&message, EVENT_KEY, |value| value.GetString());
+                if event.is_some_and(|event| event == STATE_CHANGED_EVENT) {
+                    if let Some(state) = lookup_state(&message) {
+                        on_state_changed(state)?;
+                    }
+                }
+                Ok(())
+            },
+        ))?;
+
+        connection
+            .OpenAsync()?
+            .SetCompleted(AsyncOperationCompletedHandler::new(|op, _| {
+                let status = match op {
+                    Some(op) => op.GetResults()?,
+                    None => AppServiceConnectionStatus::Unknown,
+                };
+                if status != AppServiceConnectionStatus::Success {
+                    debug_log!("Failed to connect to the app service: {:?}", status);
+                }
+                Ok(())
+            }))?;
+
+        Ok(Self { connection })
+    }
+
+    /// Send `command` to the plugin, calling `on_response` (off the UI thread) with its answer.
+    pub fn request(
+        &self,
+        command: Command,
+        on_response: impl FnOnce(Result<Response>) -> Result<()> + Send + 'static,
+    ) -> Result<()> {
+        let message = ValueSet::new()?;
+        insert(
+            &message,
+            COMMAND_KEY,
+            PropertyValue::CreateString(command.to_string()),
+        )?;
+
+        let mut on_response = Some(on_response);
+        self.connection.SendMessageAsync(message)?.SetCompleted(
+            AsyncOperationCompletedHandler::new(move |op, _| {
+                let on_response = match on_response.take() {
+                    Some(on_response) => on_response,
+                    None => return Ok(()),
+                };
+                let res = op
+                    .as_ref()
+                    .ok_or(Error::from(E_UNEXPECTED))
+                    .and_then(|op| op.GetResults())
+                    .and_then(|response: AppServiceResponse| match response.Status()? {
+                        AppServiceResponseStatus::Success => {
+                            parse_response(command, &response.Message()?)
+                        }
+                        status => Err(Error::new(
+                            E_FAIL,
+                            format!("app service request failed: {:?}", status).into(),
+                        )),
+                    });
+                on_response(res)
+            }),
+        )
+    }
+}
+
+/// Make sense of the plugin's answer to `command`.
+fn parse_response(command: Command, message: &ValueSet) -> Result<Response> {
+    let status = lookup(message, STATUS_KEY, |value| value.GetString());
+    if status.is_none_or(|status| status != "ok") {
+        let err = lookup(message, ERROR_KEY, |value| value.GetString()).unwrap_or_default();
+        return Err(Error::new(E_FAIL, err));
+    }
+
+    let state = || lookup_state(message).ok_or(Error::from(E_UNEXPECTED));
+    Ok(match command {
+        Command::GetState => Response::State(state()?),
+        Command::GetStats => Response::Stats(TunnelStats {
+            state: state()?,
+            connected_for: lookup_duration(message, CONNECTED_FOR_KEY),
+            bytes_sent: lookup(message, BYTES_SENT_KEY, |value| value.GetUInt64()).unwrap_or(0),
+            bytes_received: lookup(message, BYTES_RECEIVED_KEY, |value| value.GetUInt64())
+                .unwrap_or(0),
+            last_handshake: lookup_duration(message, LAST_HANDSHAKE_KEY),
+        }),
+        Command::GetPeers => {
+            let count = lookup(message, PEER_COUNT_KEY, |value| value.GetUInt32()).unwrap_or(0);
+            let peers = (0..count)
+                .map(|idx| {
+                    let key = |name: &str| format!("Peer{}.{}", idx, name);
+                    PeerStats {
+                        endpoint: lookup(message, &key("Endpoint"), |value| value.GetString())
+                            .map(|endpoint| endpoint.to_string())
+                            .unwrap_or_default(),
+                        description: lookup(message, &key("Description"), |value| {
+                            value.GetString()
+                        })
+                        .map(|description| description.to_string()),
+                        last_handshake: lookup_duration(message, &key(LAST_HANDSHAKE_KEY)),
+                    }
+                })
+                .collect();
+            Response::Peers(peers)
+        }
+        Command::ForceReconnect => Response::Done,
+    })
+}
+
+fn insert(set: &ValueSet, key: &str, value: Result<IInspectable>) -> Result<()> {
+    set.Insert(key, value?).map(|_| ())
+}
+
+/// Durations go over the wire as milliseconds, left out entirely if there's none.
+fn insert_duration(set: &ValueSet, key: &str, duration: Option<Duration>) -> Result<()> {
+    match duration {
+        Some(duration) => insert(
+            set,
+            key,
+            PropertyValue::CreateUInt64(duration.as_millis() as u64),
+        ),
+        None => Ok(()),
+    }
+}
+
+fn lookup<T>(
+    set: &ValueSet,
+    key: &str,
+    get: impl FnOnce(&IPropertyValue) -> Result<T>,
+) -> Option<T> {
+    if !set.HasKey(key).ok()? {
+        return None;
+    }
+    let value: IPropertyValue = set.Lookup(key).ok()?.cast().ok()?;
+    get(&value).ok()
+}
+
+fn lookup_duration(set: &ValueSet, key: &str) -> Option<Duration> {
+    lookup(set, key, |value| value.GetUInt64()).map(Duration::from_millis)
+}
+
+fn lookup_state(set: &ValueSet) -> Option<ConnectionState> {
+    lookup(set, STATE_KEY, |value| value.GetString())?
+        .to_string()
+        .parse()
+        .ok()
+}
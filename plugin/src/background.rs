@@ -1,17 +1,35 @@
 //! The entrypoint for the background task where our actual VPN plugin runs.
 
 use std::mem::ManuallyDrop;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use windows::{
     self as Windows,
     core::*,
-    ApplicationModel::Background::IBackgroundTaskInstance,
+    ApplicationModel::Background::{
+        BackgroundAccessStatus, BackgroundExecutionManager, BackgroundTaskCanceledEventHandler,
+        BackgroundTaskCancellationReason, IBackgroundTaskInstance,
+    },
     ApplicationModel::Core::CoreApplication,
+    Foundation::{AsyncOperationCompletedHandler, AsyncStatus, IPropertyValue},
+    Networking::Connectivity::{NetworkInformation, NetworkStatusChangedEventHandler},
     Networking::Vpn::{IVpnPlugIn, VpnChannel},
     Win32::Foundation::{E_INVALIDARG, E_NOINTERFACE, E_UNEXPECTED, S_OK},
     Win32::System::WinRT::IActivationFactory,
 };
 
+pub use crate::plugin::{ConnectionState, PeerStats, TunnelStats};
+
+use crate::app_service::VpnAppServiceTask;
+use crate::plugin::{CancelHandle, VpnPlugin, CONNECTED_AT_PROPERTY};
+use crate::utils::{debug_log, from_datetime};
+
+/// Lets us reach the plugin's tunnel state should the system cancel our background task.
+///
+/// The plugin object itself is stashed in the app properties, but only as an `IVpnPlugIn`.
+static CANCEL_HANDLE: Mutex<Option<CancelHandle>> = Mutex::new(None);
+
 /// The WinRT Activatable Class which acts as the entrypoint for the background tasks
 /// which get invoked to handle the actual VPN tunnel.
 #[implement(Windows::ApplicationModel::Background::IBackgroundTask)]
@@ -20,27 +38,148 @@ pub struct VpnBackgroundTask;
 impl VpnBackgroundTask {
     fn Run(&self, task: &Option<IBackgroundTaskInstance>) -> Result<()> {
         let task = task.as_ref().ok_or(Error::from(E_UNEXPECTED))?;
-        let deferral = task.GetDeferral()?;
+        let deferral = Arc::new(Mutex::new(Some(task.GetDeferral()?)));
 
         // Grab existing plugin instance from in-memory app properties or create a new one
         let app_props = CoreApplication::Properties()?;
         let plugin = if app_props.HasKey("plugin")? {
             app_props.Lookup("plugin")?.cast()?
         } else {
-            let plugin: IVpnPlugIn = super::plugin::VpnPlugin::new().into();
+            let plugin = VpnPlugin::new();
+            *CANCEL_HANDLE.lock().unwrap() = Some(plugin.cancel_handle());
+            let plugin: IVpnPlugIn = plugin.into();
             app_props.Insert("plugin", plugin.clone())?;
+
+            // Give the plugin a chance to reconnect should the network it's using go away.
+            // It's needed for as long as the plugin is around, so it's never unregistered.
+            NetworkInformation::NetworkStatusChanged(NetworkStatusChangedEventHandler::new(
+                |_| {
+                    if let Some(Err(err)) = with_plugin(VpnPlugin::handle_network_change) {
+                        debug_log!("Failed to handle network change: {}", err);
+                    }
+                    Ok(())
+                },
+            ))?;
+
             plugin
         };
 
+        // The system may cancel us (e.g. if it's low on memory or battery) in which case we
+        // should tear down the tunnel ourselves before letting it know we're done
+        let cancel_deferral = deferral.clone();
+        task.Canceled(BackgroundTaskCanceledEventHandler::new(move |_, reason| {
+            debug_log!("Background task cancelled: {:?}", reason);
+            if let Some(handle) = &*CANCEL_HANDLE.lock().unwrap() {
+                if let Err(err) = handle.cancel(Some(cancellation_reason(reason))) {
+                    debug_log!("Failed to stop tunnel on cancellation: {}", err);
+                }
+            }
+            if let Some(deferral) = cancel_deferral.lock().unwrap().take() {
+                deferral.Complete()?;
+            }
+            Ok(())
+        }))?;
+
         // Call into VPN platform with the plugin object
         VpnChannel::ProcessEventAsync(plugin, task.TriggerDetails()?)?;
 
-        deferral.Complete()?;
+        // Unless we've already been cancelled
+        if let Some(deferral) = deferral.lock().unwrap().take() {
+            deferral.Complete()?;
+        }
 
         Ok(())
     }
 }
 
+/// Describe why the system cancelled our background task, for the user's benefit.
+fn cancellation_reason(reason: BackgroundTaskCancellationReason) -> &'static str {
+    match reason {
+        BackgroundTaskCancellationReason::Terminating => "the app was closed",
+        BackgroundTaskCancellationReason::LoggingOff => "the user is signing out",
+        BackgroundTaskCancellationReason::ServicingUpdate => "the app is being updated",
+        BackgroundTaskCancellationReason::Uninstall => "the app is being uninstalled",
+        BackgroundTaskCancellationReason::SystemPolicy
+        | BackgroundTaskCancellationReason::EnergySaver => {
+            "the system's background app policy stopped it"
+        }
+        BackgroundTaskCancellationReason::ResourceRevocation => {
+            "the system needed the resources back"
+        }
+        _ => "the system cancelled it",
+    }
+}
+
+/// Make sure we're allowed to run our background task.
+///
+/// Meant to be called by the foreground app. The `vpnClient` background task itself can only
+/// be declared via `AppxManifest.xml` as there's no corresponding trigger type we could pass to
+/// `BackgroundTaskBuilder`. What we can do programmatically is request background access for
+/// the app which is otherwise subject to the user's (or system's) background app policy.
+///
+/// This doesn't block waiting on the request as it must be called from the UI thread.
+pub fn request_background_access() -> Result<()> {
+    let request = BackgroundExecutionManager::RequestAccessAsync()?;
+    request.SetCompleted(AsyncOperationCompletedHandler::new(|op, status| {
+        let op = op.as_ref().ok_or(Error::from(E_UNEXPECTED))?;
+        if status != AsyncStatus::Completed {
+            debug_log!("Background access request failed: {:?}", op.ErrorCode()?);
+            return Ok(());
+        }
+        match op.GetResults()? {
+            BackgroundAccessStatus::Denied
+            | BackgroundAccessStatus::DeniedBySystemPolicy
+            | BackgroundAccessStatus::DeniedByUser => {
+                debug_log!("Background access denied, the VPN plugin may fail to start.");
+            }
+            _ => {}
+        }
+        Ok(())
+    }))
+}
+
+/// Run `f` against the plugin instance in this process, if any.
+///
+/// The plugin normally lives in the background task's own process so this is mostly useful
+/// when the foreground app and the background task end up sharing one.
+pub(crate) fn with_plugin<T>(f: impl FnOnce(&VpnPlugin) -> T) -> Option<T> {
+    let app_props = CoreApplication::Properties().ok()?;
+    if !app_props.HasKey("plugin").ok()? {
+        return None;
+    }
+    let plugin: IVpnPlugIn = app_props.Lookup("plugin").ok()?.cast().ok()?;
+    // SAFETY: The only thing we ever store under "plugin" is our `VpnPlugin`
+    let plugin = unsafe { VpnPlugin::to_impl(&plugin) };
+    Some(f(plugin))
+}
+
+/// Get a snapshot of the VPN tunnel's state from the plugin instance in this process, if any.
+pub fn tunnel_stats() -> Option<TunnelStats> {
+    with_plugin(VpnPlugin::tunnel_stats)
+}
+
+/// Swap out a peer's pre-shared key on the live tunnel, see `VpnPlugin::rotate_psk`.
+///
+/// Like `tunnel_stats`, this only works if the plugin shares a process with the caller.
+pub fn rotate_psk(peer_idx: u32, new_psk: [u8; 32]) -> Result<()> {
+    with_plugin(|plugin| plugin.rotate_psk(peer_idx, new_psk))
+        .unwrap_or_else(|| Err(Error::from(E_UNEXPECTED)))
+}
+
+/// How long the VPN tunnel has been up for, as published by the plugin in this process.
+///
+/// Like `tunnel_stats`, this only works if the plugin shares a process with the caller.
+pub fn connected_for() -> Option<Duration> {
+    let app_props = CoreApplication::Properties().ok()?;
+    if !app_props.HasKey(CONNECTED_AT_PROPERTY).ok()? {
+        return None;
+    }
+    let connected_at: IPropertyValue = app_props.Lookup(CONNECTED_AT_PROPERTY).ok()?.cast().ok()?;
+    let connected_at = from_datetime(connected_at.GetDateTime().ok()?);
+    // Don't let the clock going backwards give us a negative duration
+    Some(connected_at.elapsed().unwrap_or_default())
+}
+
 /// A factory object to generate `VpnBackgroundTask`.
 ///
 /// Returned by `DllGetActivationFactory` when the system attempts to get an
@@ -55,6 +194,17 @@ impl VpnBackgroundTaskFactory {
     }
 }
 
+/// A factory object to generate `VpnAppServiceTask`, much like `VpnBackgroundTaskFactory`.
+#[implement(Windows::Win32::System::WinRT::IActivationFactory)]
+struct VpnAppServiceTaskFactory;
+
+impl VpnAppServiceTaskFactory {
+    /// Creates and returns a new instance of `VpnAppServiceTask`.
+    fn ActivateInstance(&self) -> Result<IInspectable> {
+        Ok(VpnAppServiceTask.into())
+    }
+}
+
 /// Called by any consumers of this library attempting to get instances of any activatable
 /// Windows Runtime classes we support.
 ///
@@ -83,6 +233,8 @@ pub unsafe extern "system" fn DllGetActivationFactory(
     // Return the appropriate factory based on which class was requested
     if *activatableClassId == "WireGuard-UWP.VpnBackgroundTask" {
         *factory = Some(VpnBackgroundTaskFactory.into());
+    } else if *activatableClassId == "WireGuard-UWP.VpnAppService" {
+        *factory = Some(VpnAppServiceTaskFactory.into());
     } else {
         *factory = None;
         return E_NOINTERFACE;
@@ -1,101 +1,2660 @@
 //! Config parsing.
+//!
+//! The config types themselves have no hard dependency on serde. The `serde` feature derives
+//! the (de)serialization traits for them while the `std-only` feature pulls in the actual XML
+//! parsing. Both are enabled by default.
+//!
+//! Configs may also be written in TOML, mirroring the XML layout with an `[interface]` table
+//! and a `[[peer]]` entry per peer. Note that where XML elements are `PascalCase` (`AllowedIPs`),
+//! TOML keys are `snake_case` (`allowed_ips`).
+//!
+//! Lastly, the standard WireGuard INI-style `.conf` format is also understood.
 
-use std::net::IpAddr;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::time::Duration;
 
 use boringtun::crypto::x25519::{X25519PublicKey, X25519SecretKey};
 use ipnetwork::IpNetwork;
-use serde::Deserialize;
-use serde_with::{serde_as, DisplayFromStr};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "std-only")]
+use windows::{core::Error as WinError, Networking::HostName, Win32::Foundation::E_INVALIDARG};
 
 /// A fully-parsed config
-#[derive(Deserialize)]
-#[serde(rename_all = "PascalCase")]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "PascalCase"))]
 pub struct WireGuardConfig {
     /// Local interface configuration
     pub interface: InterfaceConfig,
 
-    /// Remote peer configuration
-    pub peer: PeerConfig,
+    /// Remote peers configuration
+    #[cfg_attr(feature = "serde", serde(rename = "Peer"))]
+    pub peers: Vec<PeerConfig>,
+
+    /// Reject the config if it contains any elements we don't recognize
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub strict_config: bool,
+
+    /// How to retry if connecting fails, otherwise we give up after the first failure
+    pub reconnect: Option<ReconnectPolicy>,
+
+    /// Whether to show a notification when the tunnel connects or disconnects (defaults to true)
+    pub notifications: Option<bool>,
+}
+
+/// The largest config (in bytes) we're willing to parse.
+pub const MAX_CONFIG_BYTES: usize = 1_048_576;
+
+/// The most peers we accept in a single config.
+pub const MAX_PEERS: usize = 100;
+
+/// The most `AllowedIPs` we accept for a single peer.
+pub const MAX_ALLOWED_IPS_PER_PEER: usize = 10_000;
+
+/// How to treat unrecognized elements when parsing a config.
+#[cfg(feature = "std-only")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Fail with an error naming the first unknown element
+    Strict,
+    /// Silently ignore unknown elements unless the config itself asks for `StrictConfig`
+    Lenient,
 }
 
+#[cfg(feature = "std-only")]
 impl WireGuardConfig {
     /// Parse the config from the given string or return an error.
+    ///
+    /// XML configs are picked out by their leading `<`. Otherwise we try the standard
+    /// WireGuard INI format followed by TOML.
     pub fn from_str(s: &str) -> Result<WireGuardConfig, quick_xml::DeError> {
-        quick_xml::de::from_str(s)
+        if s.trim_start().starts_with('<') {
+            return Self::from_str_with_mode(s, ParseMode::Lenient);
+        }
+
+        // INI is the more likely of the two so that's the error worth reporting
+        Self::from_ini_str(s).or_else(|err| Self::from_toml(s).map_err(|_| err))
+    }
+
+    /// Parse the config from the standard WireGuard INI format or return an error.
+    pub fn from_ini_str(s: &str) -> Result<WireGuardConfig, quick_xml::DeError> {
+        use toml::value::{Table, Value};
+
+        if s.len() > MAX_CONFIG_BYTES {
+            return Err(limit_exceeded("config bytes", MAX_CONFIG_BYTES, s.len()));
+        }
+
+        enum Section {
+            None,
+            Interface,
+            Peer,
+        }
+
+        // Build up the same structure as the XML would deserialize from, with the INI keys
+        // mapped to the matching XML element names
+        let mut interface = None;
+        let mut peers = vec![];
+        let mut section = Section::None;
+        for (i, line) in s.lines().enumerate() {
+            let err = |msg: String| quick_xml::DeError::Custom(format!("line {}: {}", i + 1, msg));
+
+            // Strip comments & whitespace
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = match name.trim() {
+                    "Interface" if interface.is_some() => {
+                        return Err(err("duplicate [Interface]".into()))
+                    }
+                    "Interface" => {
+                        interface = Some(Table::new());
+                        Section::Interface
+                    }
+                    "Peer" => {
+                        peers.push(Table::new());
+                        Section::Peer
+                    }
+                    name => return Err(err(format!("unknown section [{}]", name))),
+                };
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| err("expected `Key = Value`".into()))?;
+            let table = match section {
+                Section::Interface => interface.as_mut(),
+                Section::Peer => peers.last_mut(),
+                Section::None => None,
+            }
+            .ok_or_else(|| err("expected [Interface] or [Peer] first".into()))?;
+            ini_insert(table, key.trim(), value.trim()).map_err(err)?;
+        }
+
+        let interface =
+            interface.ok_or_else(|| quick_xml::DeError::Custom("missing [Interface]".into()))?;
+        let mut config = Table::new();
+        config.insert("Interface".into(), Value::Table(interface));
+        config.insert(
+            "Peer".into(),
+            Value::Array(peers.into_iter().map(Value::Table).collect()),
+        );
+
+        let config: WireGuardConfig = Value::Table(config)
+            .try_into()
+            .map_err(|e: toml::de::Error| quick_xml::DeError::Custom(e.to_string()))?;
+        config.check_limits()?;
+        Ok(config)
+    }
+
+    /// Parse the config from the given TOML string or return an error.
+    pub fn from_toml(s: &str) -> Result<WireGuardConfig, toml::de::Error> {
+        use serde::de::Error;
+
+        if s.len() > MAX_CONFIG_BYTES {
+            return Err(Error::custom("config too large"));
+        }
+
+        // Rather than duplicate all the serde attributes, just rename the keys to
+        // match what we'd expect from the XML
+        let mut value = toml_keys_to_xml(s.parse()?);
+
+        // Be forgiving of a lone `[peer]` table rather than an array of tables
+        if let Some(peer) = value.get_mut("Peer").filter(|peer| peer.is_table()) {
+            *peer = toml::Value::Array(vec![peer.clone()]);
+        }
+
+        let config: WireGuardConfig = value.try_into()?;
+        config.check_limits().map_err(Error::custom)?;
+        Ok(config)
+    }
+
+    /// Parse the config from the given string, treating unknown elements according to `mode`.
+    pub fn from_str_with_mode(
+        s: &str,
+        mode: ParseMode,
+    ) -> Result<WireGuardConfig, quick_xml::DeError> {
+        // Don't let an absurdly large config tie us up
+        if s.len() > MAX_CONFIG_BYTES {
+            return Err(limit_exceeded("config bytes", MAX_CONFIG_BYTES, s.len()));
+        }
+
+        let config: WireGuardConfig = quick_xml::de::from_str(s)?;
+        if mode == ParseMode::Strict || config.strict_config {
+            check_known_elements(s)?;
+        }
+
+        config.check_limits()?;
+        Ok(config)
+    }
+
+    /// Make sure the parsed config isn't unreasonably large.
+    fn check_limits(&self) -> Result<(), quick_xml::DeError> {
+        if self.peers.len() > MAX_PEERS {
+            return Err(limit_exceeded("peers", MAX_PEERS, self.peers.len()));
+        }
+
+        for peer in &self.peers {
+            let allowed_ips = peer.allowed_ips.len();
+            if allowed_ips > MAX_ALLOWED_IPS_PER_PEER {
+                return Err(limit_exceeded(
+                    "AllowedIPs",
+                    MAX_ALLOWED_IPS_PER_PEER,
+                    allowed_ips,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parse the config from a string as it was likely written in PowerShell.
+    ///
+    /// Configs are usually set via `Set-VpnConnection -CustomConfiguration` using a here-string
+    /// which can leave behind CRLF line endings, indentation and even the here-string delimiters
+    /// themselves if they were accidentally copied along. Clean all that up before parsing.
+    pub fn from_powershell_splatting(s: &str) -> Result<WireGuardConfig, quick_xml::DeError> {
+        let cleaned = s
+            .lines() // Also takes care of any CRLFs
+            .map(str::trim)
+            .filter(|line| !matches!(*line, "@'" | "'@" | "@\"" | "\"@"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Self::from_str(&cleaned)
+    }
+
+    /// Parse the config from the given reader or return an error.
+    ///
+    /// Use with `utils::IRandomAccessStreamReader` to parse straight from a `StorageFile`.
+    pub fn from_reader<R: std::io::Read>(r: R) -> Result<WireGuardConfig, quick_xml::DeError> {
+        use std::io::Read;
+
+        // Read it all in upfront so we can also check it for unknown elements if need be.
+        // Reading one byte past the size limit is enough for `from_str` to reject it.
+        let mut s = String::new();
+        r.take(MAX_CONFIG_BYTES as u64 + 1)
+            .read_to_string(&mut s)
+            .map_err(|e| quick_xml::DeError::Custom(e.to_string()))?;
+        Self::from_str(&s)
+    }
+
+    /// Serialize the config back into the XML format accepted by `from_str`.
+    ///
+    /// Keys are written out base64 encoded and each peer gets its own `<Peer>` element.
+    pub fn to_xml_string(&self) -> String {
+        let mut xml = XmlWriter::default();
+        xml.open("WireGuard");
+
+        let interface = &self.interface;
+        xml.open("Interface");
+        xml.key("PrivateKey", interface.private_key.as_bytes());
+        xml.elements("Address", &interface.address);
+        xml.elements("DNS", &interface.dns_servers);
+        xml.elements("DNSSearch", &interface.search_domains);
+        xml.elements("DeadPeerDetection", &interface.dead_peer_detection);
+        xml.elements("RekeyWatchdog", &interface.rekey_watchdog);
+        xml.elements("RateLimit", &interface.rate_limit);
+        xml.elements("ConnectionAttempts", &interface.connection_attempts);
+        xml.elements("ListenPort", &interface.listen_port);
+        xml.elements("MTU", &interface.mtu);
+        if interface.kill_switch {
+            xml.element("KillSwitch", true);
+        }
+        if interface.mss_clamp {
+            xml.element("MSSClamp", true);
+        }
+        xml.elements("Table", &interface.table);
+        if let Some(fwmark) = interface.fwmark {
+            xml.element("FwMark", format!("{:#x}", fwmark));
+        }
+        xml.close("Interface");
+
+        for peer in &self.peers {
+            xml.open("Peer");
+            xml.key("PublicKey", peer.public_key.as_bytes());
+            xml.elements("Endpoint", &peer.endpoint);
+            if peer.port != 0 {
+                xml.element("Port", peer.port);
+            }
+            xml.elements("AllowedIPs", &peer.allowed_ips);
+            xml.elements("ExcludedIPs", &peer.excluded_ips);
+            xml.elements("DNS", &peer.dns_servers);
+            xml.elements("DNSSearch", &peer.search_domains);
+            xml.elements("AppTunnel", &peer.app_tunnel);
+            xml.elements("PersistentKeepalive", &peer.persistent_keepalive);
+            if let Some(psk) = &peer.preshared_key {
+                xml.key("PresharedKey", psk);
+            }
+            xml.elements("Description", &peer.description);
+            xml.close("Peer");
+        }
+
+        if self.strict_config {
+            xml.element("StrictConfig", true);
+        }
+
+        if let Some(reconnect) = &self.reconnect {
+            xml.open("Reconnect");
+            xml.element("MaxAttempts", reconnect.max_attempts);
+            xml.element("BaseDelayMs", reconnect.base_delay_ms);
+            xml.element("MaxDelayMs", reconnect.max_delay_ms);
+            xml.close("Reconnect");
+        }
+
+        xml.elements("Notifications", &self.notifications);
+
+        xml.close("WireGuard");
+        xml.out
+    }
+
+    /// Serialize the config into the standard WireGuard INI format accepted by `from_ini_str`.
+    ///
+    /// Only the keys `wg-quick` understands are written out so the result works as is with the
+    /// usual tooling elsewhere. Our own extensions (e.g. `ExcludedIPs`, per-peer DNS or the
+    /// kill switch) are dropped. Peers without an `Endpoint` of their own are written without
+    /// one, callers that know the profile's servers should fill those in first.
+    ///
+    /// Unless `private_key` is set the interface's key is left out, with a comment in its place.
+    pub fn to_ini_string(&self, private_key: bool) -> String {
+        let mut ini = IniWriter::default();
+
+        let interface = &self.interface;
+        ini.section("Interface");
+        if private_key {
+            ini.key("PrivateKey", interface.private_key.as_bytes());
+        } else {
+            ini.comment("PrivateKey omitted, fill it in before use");
+        }
+        ini.entries("Address", &interface.address);
+        // wg-quick takes the search domains mixed in with the servers
+        let dns = interface
+            .dns_servers
+            .iter()
+            .map(ToString::to_string)
+            .chain(interface.search_domains.iter().cloned())
+            .collect::<Vec<_>>();
+        ini.entries("DNS", &dns);
+        ini.entries("MTU", &interface.mtu);
+        ini.entries("ListenPort", &interface.listen_port);
+        ini.entries("Table", &interface.table);
+        if let Some(fwmark) = interface.fwmark {
+            ini.entry("FwMark", format!("{:#x}", fwmark));
+        }
+
+        for peer in &self.peers {
+            ini.out.push('\n');
+            ini.section("Peer");
+            if let Some(description) = &peer.description {
+                ini.comment(description);
+            }
+            ini.key("PublicKey", peer.public_key.as_bytes());
+            if let Some(psk) = &peer.preshared_key {
+                ini.key("PresharedKey", psk);
+            }
+            ini.entries("AllowedIPs", &peer.allowed_ips);
+            ini.entries("Endpoint", &peer.endpoint);
+            ini.entries("PersistentKeepalive", &peer.persistent_keepalive);
+        }
+
+        ini.out
+    }
+}
+
+/// Minimal indenting XML writer used by `WireGuardConfig::to_xml_string`.
+#[cfg(feature = "std-only")]
+#[derive(Default)]
+struct XmlWriter {
+    out: String,
+    depth: usize,
+}
+
+#[cfg(feature = "std-only")]
+impl XmlWriter {
+    fn indent(&mut self) {
+        self.out.extend(std::iter::repeat_n("    ", self.depth));
+    }
+
+    fn open(&mut self, name: &str) {
+        self.indent();
+        self.out.push_str(&format!("<{}>\n", name));
+        self.depth += 1;
+    }
+
+    fn close(&mut self, name: &str) {
+        self.depth -= 1;
+        self.indent();
+        self.out.push_str(&format!("</{}>\n", name));
+    }
+
+    fn element(&mut self, name: &str, value: impl fmt::Display) {
+        let value = value.to_string();
+        let value = quick_xml::escape::escape(value.as_bytes());
+        self.indent();
+        self.out.push_str(&format!(
+            "<{0}>{1}</{0}>\n",
+            name,
+            String::from_utf8_lossy(&value)
+        ));
+    }
+
+    /// Write out one element per value, e.g. for each entry of a `Vec` or a present `Option`.
+    fn elements<'a, T: fmt::Display + 'a>(
+        &mut self,
+        name: &str,
+        values: impl IntoIterator<Item = &'a T>,
+    ) {
+        for value in values {
+            self.element(name, value);
+        }
+    }
+
+    fn key(&mut self, name: &str, key: &[u8]) {
+        self.element(name, base64::encode(key));
+    }
+}
+
+/// Minimal INI writer used by `WireGuardConfig::to_ini_string`.
+#[cfg(feature = "std-only")]
+#[derive(Default)]
+struct IniWriter {
+    out: String,
+}
+
+#[cfg(feature = "std-only")]
+impl IniWriter {
+    fn section(&mut self, name: &str) {
+        self.out.push_str(&format!("[{}]\n", name));
+    }
+
+    fn comment(&mut self, text: &str) {
+        // Keep multi-line text from spilling out of the comment
+        for line in text.lines() {
+            self.out.push_str(&format!("# {}\n", line));
+        }
+    }
+
+    fn entry(&mut self, key: &str, value: impl fmt::Display) {
+        self.out.push_str(&format!("{} = {}\n", key, value));
+    }
+
+    /// Write out the values as a single comma separated entry, if there are any.
+    fn entries<'a, T: fmt::Display + 'a>(
+        &mut self,
+        key: &str,
+        values: impl IntoIterator<Item = &'a T>,
+    ) {
+        let values = values
+            .into_iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        if !values.is_empty() {
+            self.entry(key, values.join(", "));
+        }
+    }
+
+    fn key(&mut self, name: &str, key: &[u8]) {
+        self.entry(name, base64::encode(key));
+    }
+}
+
+impl WireGuardConfig {
+    /// Check the config for mistakes that would still parse fine, returning every issue found.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = vec![];
+
+        if self
+            .interface
+            .private_key
+            .as_bytes()
+            .iter()
+            .all(|&b| b == 0)
+        {
+            errors.push(ConfigError::ZeroPrivateKey);
+        }
+
+        for &address in &self.interface.address {
+            if is_test_net(address.ip()) {
+                errors.push(ConfigError::TestNetAddress(address));
+            }
+        }
+
+        // Every link has to carry packets of at least 576 bytes for IPv4, 1280 for IPv6
+        if let Some(mtu) = self.interface.mtu {
+            let ipv6 = self
+                .interface
+                .address
+                .iter()
+                .chain(self.peers.iter().flat_map(|peer| &peer.allowed_ips))
+                .any(|network| network.is_ipv6());
+            let min_mtu = if ipv6 { 1280 } else { 576 };
+            if mtu < min_mtu {
+                errors.push(ConfigError::MtuTooSmall(mtu, min_mtu));
+            }
+        }
+
+        for (idx, peer) in self.peers.iter().enumerate() {
+            if peer.allowed_ips.is_empty() {
+                errors.push(ConfigError::EmptyAllowedIps(idx));
+            }
+            if peer.persistent_keepalive == Some(0) {
+                errors.push(ConfigError::ZeroKeepalive(idx));
+            }
+            if peer.preshared_key == Some([0; 32]) {
+                errors.push(ConfigError::ZeroPresharedKey(idx));
+            }
+            match &peer.endpoint {
+                Some(endpoint) if split_endpoint(endpoint).is_none() => {
+                    errors.push(ConfigError::InvalidEndpoint(idx));
+                }
+                None if peer.port == 0 => errors.push(ConfigError::MissingPort(idx)),
+                _ => {}
+            }
+            if !peer.dns_servers.is_empty() && peer.search_domains.is_empty() {
+                errors.push(ConfigError::PeerDnsWithoutSearch(idx));
+            }
+            if let Some(description) = &peer.description {
+                if description.chars().any(char::is_control) {
+                    errors.push(ConfigError::ControlCharacter(idx));
+                }
+            }
+
+            // The same network can't be routed to two different peers
+            for (other_idx, other) in self.peers[..idx].iter().enumerate() {
+                for network in &peer.allowed_ips {
+                    if other.allowed_ips.contains(network) {
+                        errors.push(ConfigError::ConflictingAllowedIps(*network, other_idx, idx));
+                    }
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The per-packet overhead of sending a packet through the tunnel.
+    ///
+    /// That's the WireGuard data message header (16 bytes) and authentication tag (16 bytes)
+    /// plus the outer UDP (8 bytes) and IP headers. We don't know which address family we'll
+    /// end up using to reach the peer so assume the larger IPv6 header (40 bytes).
+    pub fn estimate_overhead(&self) -> usize {
+        16 + 16 + 8 + 40
+    }
+
+    /// Put the config into a canonical form by sorting the peers by public key along with any
+    /// lists of networks, so equal configs serialize the same.
+    ///
+    /// DNS servers & search domains are left as-is since their order matters. Note a peer's
+    /// position also decides which of the profile's servers and which of the channel's two
+    /// transports it gets, so a live tunnel can't simply switch to the canonical form. `diff`
+    /// counts peers that moved as `other_changed` for that reason.
+    pub fn canonicalize(&mut self) {
+        self.peers
+            .sort_by(|a, b| a.public_key.as_bytes().cmp(b.public_key.as_bytes()));
+        self.interface.address.sort_by_key(network_sort_key);
+        for peer in &mut self.peers {
+            peer.allowed_ips.sort_by_key(network_sort_key);
+            peer.excluded_ips.sort_by_key(network_sort_key);
+        }
+    }
+
+    /// Work out what changed going from this config to `new`.
+    ///
+    /// Peers are matched up by their public key to find any that were added or removed,
+    /// otherwise by their position. Peers that merely moved count as `other_changed`, as their
+    /// position decides their server & transport.
+    pub fn diff(&self, new: &WireGuardConfig) -> ConfigDelta {
+        let missing_from = |peers: &[PeerConfig], others: &[PeerConfig]| {
+            peers
+                .iter()
+                .enumerate()
+                .filter(|(_, peer)| {
+                    !others
+                        .iter()
+                        .any(|other| other.public_key.as_bytes() == peer.public_key.as_bytes())
+                })
+                .map(|(idx, _)| idx)
+                .collect()
+        };
+
+        let (old_iface, new_iface) = (&self.interface, &new.interface);
+        let mut delta = ConfigDelta {
+            routes_changed: false,
+            dns_changed: old_iface.dns_servers != new_iface.dns_servers
+                || old_iface.search_domains != new_iface.search_domains
+                || self
+                    .peers
+                    .iter()
+                    .map(|peer| (&peer.dns_servers, &peer.search_domains))
+                    .ne(new
+                        .peers
+                        .iter()
+                        .map(|peer| (&peer.dns_servers, &peer.search_domains))),
+            peers_added: missing_from(&new.peers, &self.peers),
+            peers_removed: missing_from(&self.peers, &new.peers),
+            keepalive_changed: vec![],
+            other_changed: old_iface.private_key.as_bytes() != new_iface.private_key.as_bytes()
+                || sorted_networks(&old_iface.address) != sorted_networks(&new_iface.address)
+                || old_iface.dead_peer_detection != new_iface.dead_peer_detection
+                || old_iface.rekey_watchdog != new_iface.rekey_watchdog
+                || old_iface.rate_limit != new_iface.rate_limit
+                || old_iface.connection_attempts != new_iface.connection_attempts
+                || old_iface.listen_port != new_iface.listen_port
+                || old_iface.mtu != new_iface.mtu
+                || old_iface.kill_switch != new_iface.kill_switch
+                || old_iface.mss_clamp != new_iface.mss_clamp
+                || old_iface.table != new_iface.table
+                || old_iface.fwmark != new_iface.fwmark
+                || self.peers.len() != new.peers.len(),
+        };
+
+        for (idx, (old_peer, new_peer)) in self.peers.iter().zip(&new.peers).enumerate() {
+            delta.routes_changed |= sorted_networks(&old_peer.allowed_ips)
+                != sorted_networks(&new_peer.allowed_ips)
+                || sorted_networks(&old_peer.excluded_ips)
+                    != sorted_networks(&new_peer.excluded_ips);
+            if old_peer.persistent_keepalive != new_peer.persistent_keepalive {
+                delta.keepalive_changed.push(idx);
+            }
+            delta.other_changed |= old_peer.public_key.as_bytes() != new_peer.public_key.as_bytes()
+                || old_peer.endpoint != new_peer.endpoint
+                || old_peer.port != new_peer.port
+                || old_peer.preshared_key != new_peer.preshared_key
+                || old_peer.app_tunnel != new_peer.app_tunnel;
+        }
+
+        delta
+    }
+
+    /// Whether all of this config's routes fall within those of `other`, i.e. whether a tunnel
+    /// started with `other`'s routes would still carry all the traffic meant for our peers.
+    pub fn routes_within(&self, other: &WireGuardConfig) -> bool {
+        let excluded = |config: &WireGuardConfig| {
+            let excluded = config
+                .peers
+                .iter()
+                .flat_map(|peer| peer.excluded_ips.iter().copied())
+                .collect::<Vec<_>>();
+            sorted_networks(&excluded)
+        };
+
+        excluded(self) == excluded(other)
+            && self
+                .peers
+                .iter()
+                .flat_map(|peer| &peer.allowed_ips)
+                .all(|network| {
+                    other
+                        .peers
+                        .iter()
+                        .flat_map(|peer| &peer.allowed_ips)
+                        .any(|outer| {
+                            outer.prefix() <= network.prefix() && outer.contains(network.network())
+                        })
+                })
+    }
+}
+
+/// What changed between two configs, as found by `WireGuardConfig::diff`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigDelta {
+    /// Whether any peer's `AllowedIPs` or `ExcludedIPs` changed
+    pub routes_changed: bool,
+    /// Whether the DNS servers or search domains changed
+    pub dns_changed: bool,
+    /// The new config's peers that weren't in the old one, by position in the new config
+    pub peers_added: Vec<usize>,
+    /// The old config's peers that aren't in the new one, by position in the old config
+    pub peers_removed: Vec<usize>,
+    /// The peers whose `PersistentKeepalive` changed
+    pub keepalive_changed: Vec<usize>,
+    /// Whether anything else changed that affects the tunnel, e.g. any of the keys
+    pub other_changed: bool,
+}
+
+impl fmt::Display for ConfigDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let changes = [
+            (self.routes_changed, "routes"),
+            (self.dns_changed, "dns"),
+            (!self.peers_added.is_empty(), "peers added"),
+            (!self.peers_removed.is_empty(), "peers removed"),
+            (!self.keepalive_changed.is_empty(), "keepalive"),
+            (self.other_changed, "other"),
+        ]
+        .iter()
+        .filter(|(changed, _)| *changed)
+        .map(|(_, change)| *change)
+        .collect::<Vec<_>>();
+
+        if changes.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", changes.join(", "))
+        }
+    }
+}
+
+/// Configs are compared as if they were both canonicalized first, so the peers are compared as a
+/// set regardless of their order.
+impl PartialEq for WireGuardConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.interface == other.interface
+            && sorted_peers(&self.peers) == sorted_peers(&other.peers)
+            && self.strict_config == other.strict_config
+            && self.reconnect == other.reconnect
+            && self.notifications == other.notifications
+    }
+}
+
+impl Eq for WireGuardConfig {}
+
+/// How to retry a failed connection attempt, backing off exponentially between attempts.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(default, rename_all = "PascalCase"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// How many more times to try after the initial attempt fails
+    pub max_attempts: u32,
+    /// How long to wait before the first retry, doubling for each one after
+    pub base_delay_ms: u64,
+    /// The longest we'll wait between any two attempts
+    pub max_delay_ms: u64,
+}
+
+impl ReconnectPolicy {
+    /// How long to wait before the given (0-based) retry.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u64.checked_shl(attempt).unwrap_or(u64::MAX);
+        let delay_ms = self.base_delay_ms.saturating_mul(factor);
+        Duration::from_millis(delay_ms.min(self.max_delay_ms))
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 1_000,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+/// A config that parsed fine but doesn't make sense. Peers are identified by their position.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The interface's private key is all zeroes
+    ZeroPrivateKey,
+    /// An interface address is from one of the RFC 5737 documentation ranges
+    TestNetAddress(IpNetwork),
+    /// The `MTU` is below the minimum (the second field) for the address families in use
+    MtuTooSmall(u16, u16),
+    /// A peer has no `AllowedIPs` so nothing would ever be routed to it
+    EmptyAllowedIps(usize),
+    /// The same `AllowedIPs` network is listed for two peers
+    ConflictingAllowedIps(IpNetwork, usize, usize),
+    /// A peer's `PersistentKeepalive` is 0, which disables it
+    ZeroKeepalive(usize),
+    /// A peer's pre-shared key is all zeroes
+    ZeroPresharedKey(usize),
+    /// A peer's `Endpoint` isn't of the form `host:port`
+    InvalidEndpoint(usize),
+    /// A peer has neither an `Endpoint` nor a `Port`
+    MissingPort(usize),
+    /// A peer has `DNS` servers but no `DNSSearch` domains they'd be used for
+    PeerDnsWithoutSearch(usize),
+    /// A peer's `Description` contains a control character, e.g. NUL, that can't be written
+    /// back out as XML
+    ControlCharacter(usize),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ZeroPrivateKey => write!(f, "private key is all zeroes"),
+            ConfigError::TestNetAddress(address) => {
+                write!(f, "address {} is reserved for documentation", address)
+            }
+            ConfigError::MtuTooSmall(mtu, min_mtu) => {
+                write!(f, "MTU {} is below the minimum of {}", mtu, min_mtu)
+            }
+            ConfigError::EmptyAllowedIps(peer) => write!(f, "peer {} has no AllowedIPs", peer),
+            ConfigError::ConflictingAllowedIps(network, first, second) => write!(
+                f,
+                "AllowedIPs {} is listed for both peer {} and peer {}",
+                network, first, second
+            ),
+            ConfigError::ZeroKeepalive(peer) => {
+                write!(f, "peer {} has a PersistentKeepalive of 0", peer)
+            }
+            ConfigError::ZeroPresharedKey(peer) => {
+                write!(f, "peer {} has an all zero pre-shared key", peer)
+            }
+            ConfigError::InvalidEndpoint(peer) => {
+                write!(f, "peer {} has an Endpoint that isn't host:port", peer)
+            }
+            ConfigError::MissingPort(peer) => {
+                write!(f, "peer {} has neither an Endpoint nor a Port", peer)
+            }
+            ConfigError::PeerDnsWithoutSearch(peer) => {
+                write!(f, "peer {} has DNS servers but no DNSSearch domains", peer)
+            }
+            ConfigError::ControlCharacter(peer) => {
+                write!(
+                    f,
+                    "peer {} has a control character in its Description",
+                    peer
+                )
+            }
+        }
+    }
+}
+
+/// Whether the address falls in one of the RFC 5737 documentation (TEST-NET) ranges.
+fn is_test_net(addr: IpAddr) -> bool {
+    const TEST_NETS: [Ipv4Addr; 3] = [
+        Ipv4Addr::new(192, 0, 2, 0),
+        Ipv4Addr::new(198, 51, 100, 0),
+        Ipv4Addr::new(203, 0, 113, 0),
+    ];
+    match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, _] = addr.octets();
+            TEST_NETS.contains(&Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(_) => false,
     }
 }
 
 /// Local VPN interface specific configuration
-#[serde_as]
-#[derive(Deserialize)]
-#[serde(rename_all = "PascalCase")]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "PascalCase"))]
 pub struct InterfaceConfig {
     /// Our local private key
-    #[serde_as(as = "DisplayFromStr")]
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "from_str_value"))]
+    #[cfg_attr(feature = "serde", serde(serialize_with = "secret_key_to_base64"))]
     pub private_key: X25519SecretKey,
 
     /// Addresses to assign to local VPN interface
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "from_comma_separated"))]
     pub address: Vec<IpNetwork>,
 
     /// DNS servers
-    #[serde(default)]
-    #[serde(rename = "DNS")]
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(feature = "serde", serde(rename = "DNS"))]
     pub dns_servers: Vec<IpAddr>,
 
     /// DNS Search Domains
-    #[serde(default)]
-    #[serde(rename = "DNSSearch")]
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(feature = "serde", serde(rename = "DNSSearch"))]
     pub search_domains: Vec<String>,
+
+    /// Disconnect after this many keepalives in a row to a peer go unanswered (0 or unset to
+    /// disable)
+    pub dead_peer_detection: Option<u32>,
+
+    /// Warn once a session in use gets this many seconds old without us starting a handshake
+    /// to replace it (defaults to 170, 0 to disable)
+    pub rekey_watchdog: Option<u32>,
+
+    /// The max number of handshakes per second we'll handle before asking peers to back off
+    pub rate_limit: Option<u32>,
+
+    /// How many times to go through the list of servers when connecting (defaults to 1)
+    pub connection_attempts: Option<u32>,
+
+    /// The local UDP port to send from & listen on, otherwise one is picked for us
+    pub listen_port: Option<u16>,
+
+    /// MTU of the local VPN interface
+    #[cfg_attr(feature = "serde", serde(rename = "MTU"))]
+    pub mtu: Option<u16>,
+
+    /// Route everything through the tunnel regardless of `AllowedIPs`, dropping whatever
+    /// doesn't match, so nothing leaks out over the physical network
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub kill_switch: bool,
+
+    /// Clamp the MSS of TCP connections through the tunnel so their packets fit within the MTU
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(feature = "serde", serde(rename = "MSSClamp"))]
+    pub mss_clamp: bool,
+
+    /// `wg-quick`'s routing table setting. Kept so configs round-trip but otherwise unused
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub table: Option<TableConfig>,
+
+    /// The firewall mark for outgoing packets. Kept so configs round-trip but otherwise unused
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(feature = "serde", serde(rename = "FwMark"))]
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "from_fwmark"))]
+    pub fwmark: Option<u32>,
+}
+
+/// Which routing table `wg-quick` adds the routes to.
+///
+/// The UWP VPN platform manages the routes itself so this has no effect here.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableConfig {
+    /// Let `wg-quick` pick the table (the default)
+    Auto,
+    /// Don't add any routes
+    Off,
+    /// Add the routes to the given table
+    Custom(u32),
+}
+
+impl FromStr for TableConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "auto" => Ok(TableConfig::Auto),
+            "off" => Ok(TableConfig::Off),
+            table => table
+                .parse()
+                .map(TableConfig::Custom)
+                .map_err(|_| format!("invalid Table: {}", table)),
+        }
+    }
 }
 
+impl fmt::Display for TableConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableConfig::Auto => write!(f, "auto"),
+            TableConfig::Off => write!(f, "off"),
+            TableConfig::Custom(table) => write!(f, "{}", table),
+        }
+    }
+}
+
+// Written out the same way as in a `wg-quick` config, e.g. `off`
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for TableConfig {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        from_str_value(deserializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for TableConfig {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl InterfaceConfig {
+    /// Our public key, base64 encoded like in a config, for setting us up as a peer elsewhere.
+    pub fn public_key_base64(&self) -> String {
+        base64::encode(public_from_private(&self.private_key).as_bytes())
+    }
+}
+
+/// Derive the public key that goes with `private`.
+pub fn public_from_private(private: &X25519SecretKey) -> X25519PublicKey {
+    private.public_key()
+}
+
+/// Make a copy of a private key. boringtun doesn't let us clone one, but it will parse one back
+/// out of its base64 encoding.
+pub fn copy_private_key(private: &X25519SecretKey) -> X25519SecretKey {
+    base64::encode(private.as_bytes())
+        .parse()
+        .expect("base64 encoded key should parse")
+}
+
+impl PartialEq for InterfaceConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.private_key.as_bytes() == other.private_key.as_bytes()
+            && sorted_networks(&self.address) == sorted_networks(&other.address)
+            && self.dns_servers == other.dns_servers
+            && self.search_domains == other.search_domains
+            && self.dead_peer_detection == other.dead_peer_detection
+            && self.rekey_watchdog == other.rekey_watchdog
+            && self.rate_limit == other.rate_limit
+            && self.connection_attempts == other.connection_attempts
+            && self.listen_port == other.listen_port
+            && self.mtu == other.mtu
+            && self.kill_switch == other.kill_switch
+            && self.mss_clamp == other.mss_clamp
+            && self.table == other.table
+            && self.fwmark == other.fwmark
+    }
+}
+
+impl Eq for InterfaceConfig {}
+
 /// Remote peer specific configuration
-#[serde_as]
-#[derive(Deserialize)]
-#[serde(rename_all = "PascalCase")]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "PascalCase"))]
 pub struct PeerConfig {
     /// The remote endpoint's public key
-    #[serde_as(as = "DisplayFromStr")]
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "from_str_value"))]
+    #[cfg_attr(feature = "serde", serde(serialize_with = "public_key_to_base64"))]
     pub public_key: X25519PublicKey,
 
-    /// The port the remote endpoint is listening
+    /// The remote endpoint as `host:port`, otherwise the host comes from the VPN profile
+    pub endpoint: Option<String>,
+
+    /// The port the remote endpoint is listening, if there's no `Endpoint`
+    #[cfg_attr(feature = "serde", serde(default))]
     pub port: u16,
 
     /// The list of addresses that will get routed to the remote endpoint
-    #[serde(rename = "AllowedIPs")]
+    #[cfg_attr(feature = "serde", serde(rename = "AllowedIPs"))]
     pub allowed_ips: Vec<IpNetwork>,
 
     /// The list of addresses that won't get routed to the remote endpoint
-    #[serde(default)]
-    #[serde(rename = "ExcludedIPs")]
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(feature = "serde", serde(rename = "ExcludedIPs"))]
     pub excluded_ips: Vec<IpNetwork>,
 
+    /// DNS servers to resolve this peer's `search_domains` with, instead of the interface's
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(feature = "serde", serde(rename = "DNS"))]
+    pub dns_servers: Vec<IpAddr>,
+
+    /// DNS search domains served by this peer
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(feature = "serde", serde(rename = "DNSSearch"))]
+    pub search_domains: Vec<String>,
+
+    /// Package family names (or AUMIDs) of the apps allowed to use the tunnel through this peer
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub app_tunnel: Vec<String>,
+
     /// The interval at which to send KeepAlive packets.
     pub persistent_keepalive: Option<u16>,
 
-    /// An optional pre-shared key to enable an additional layer of security
-    #[serde(default)]
-    #[serde(deserialize_with = "from_base64")]
+    /// An optional pre-shared key (base64 or hex) to enable an additional layer of security
+    #[cfg_attr(feature = "serde", serde(default))]
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "from_base64_or_hex"))]
+    #[cfg_attr(feature = "serde", serde(serialize_with = "preshared_key_to_base64"))]
     pub preshared_key: Option<[u8; 32]>,
+
+    /// Freeform description of the peer, purely informational
+    pub description: Option<String>,
+}
+
+impl PartialEq for PeerConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.public_key.as_bytes() == other.public_key.as_bytes()
+            && self.endpoint == other.endpoint
+            && self.port == other.port
+            && sorted_networks(&self.allowed_ips) == sorted_networks(&other.allowed_ips)
+            && sorted_networks(&self.excluded_ips) == sorted_networks(&other.excluded_ips)
+            && self.dns_servers == other.dns_servers
+            && self.search_domains == other.search_domains
+            && self.app_tunnel == other.app_tunnel
+            && self.persistent_keepalive == other.persistent_keepalive
+            && self.preshared_key == other.preshared_key
+            && self.description == other.description
+    }
+}
+
+impl Eq for PeerConfig {}
+
+#[cfg(feature = "std-only")]
+impl PeerConfig {
+    /// The host & port to reach the peer at.
+    ///
+    /// That's the peer's own `Endpoint` if it has one, otherwise `fallback_host` (i.e. the
+    /// matching server from the VPN profile) along with the configured `Port`.
+    pub fn resolved_endpoint(
+        &self,
+        fallback_host: &HostName,
+    ) -> windows::core::Result<(HostName, u16)> {
+        match &self.endpoint {
+            Some(endpoint) => {
+                let (host, port) = split_endpoint(endpoint).ok_or_else(|| {
+                    WinError::new(
+                        E_INVALIDARG,
+                        format!("invalid Endpoint: {}", endpoint).into(),
+                    )
+                })?;
+                Ok((HostName::CreateHostName(host)?, port))
+            }
+            None => Ok((fallback_host.clone(), self.port)),
+        }
+    }
+}
+
+/// Split a `host:port` endpoint into its parts. IPv6 addresses must be in brackets.
+fn split_endpoint(endpoint: &str) -> Option<(&str, u16)> {
+    let (host, port) = endpoint.rsplit_once(':')?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']')?,
+        // Otherwise we couldn't tell where the address ends and the port begins
+        None if host.contains(':') => return None,
+        None => host,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host, port.parse().ok()?))
+}
+
+/// The elements we recognize directly under the root element.
+///
+/// NOTE: These must be kept in sync with the fields of the corresponding config structs.
+#[cfg(feature = "std-only")]
+const CONFIG_ELEMENTS: &[&str] = &[
+    "Interface",
+    "Peer",
+    "StrictConfig",
+    "Reconnect",
+    "Notifications",
+];
+
+/// The elements we recognize under `<Reconnect>`.
+#[cfg(feature = "std-only")]
+const RECONNECT_ELEMENTS: &[&str] = &["MaxAttempts", "BaseDelayMs", "MaxDelayMs"];
+
+/// The elements we recognize under `<Interface>`.
+#[cfg(feature = "std-only")]
+const INTERFACE_ELEMENTS: &[&str] = &[
+    "PrivateKey",
+    "Address",
+    "DNS",
+    "DNSSearch",
+    "DeadPeerDetection",
+    "RekeyWatchdog",
+    "RateLimit",
+    "ConnectionAttempts",
+    "ListenPort",
+    "MTU",
+    "KillSwitch",
+    "MSSClamp",
+    "Table",
+    "FwMark",
+];
+
+/// The elements we recognize under `<Peer>`.
+#[cfg(feature = "std-only")]
+const PEER_ELEMENTS: &[&str] = &[
+    "PublicKey",
+    "Endpoint",
+    "Port",
+    "AllowedIPs",
+    "ExcludedIPs",
+    "DNS",
+    "DNSSearch",
+    "AppTunnel",
+    "PersistentKeepalive",
+    "PresharedKey",
+    "Description",
+];
+
+/// Insert an INI `key = value` pair into `table` under the equivalent XML element name.
+#[cfg(feature = "std-only")]
+fn ini_insert(table: &mut toml::value::Table, key: &str, value: &str) -> Result<(), String> {
+    use toml::Value;
+
+    let list = || value.split(',').map(str::trim).filter(|v| !v.is_empty());
+    let int = |value: &str| {
+        value
+            .parse()
+            .map(Value::Integer)
+            .map_err(|e| format!("invalid {}: {}", key, e))
+    };
+    // Lists may be split across repeated keys
+    let mut append = |name: &str, items: Vec<&str>| {
+        if let Value::Array(values) = table
+            .entry(name.to_string())
+            .or_insert(Value::Array(vec![]))
+        {
+            values.extend(items.into_iter().map(|item| Value::String(item.into())));
+        }
+    };
+
+    match key {
+        "PrivateKey" | "PublicKey" | "PresharedKey" => {
+            table.insert(key.into(), Value::String(value.into()));
+        }
+        "Address" | "AllowedIPs" | "ExcludedIPs" | "AppTunnel" => append(key, list().collect()),
+        // wg-quick lets search domains be mixed in with the DNS servers
+        "DNS" => {
+            let (servers, domains): (Vec<_>, Vec<_>) =
+                list().partition(|v| v.parse::<IpAddr>().is_ok());
+            append("DNS", servers);
+            append("DNSSearch", domains);
+        }
+        "Table" | "FwMark" => {
+            table.insert(key.into(), Value::String(value.into()));
+        }
+        "MTU" | "ListenPort" | "PersistentKeepalive" => {
+            table.insert(key.into(), int(value)?);
+        }
+        "Endpoint" => {
+            split_endpoint(value).ok_or_else(|| format!("invalid Endpoint: {}", value))?;
+            table.insert(key.into(), Value::String(value.into()));
+        }
+        // wg-quick specific settings which don't apply to us
+        "PreUp" | "PostUp" | "PreDown" | "PostDown" | "SaveConfig" => {}
+        _ => return Err(format!("unknown key {}", key)),
+    }
+
+    Ok(())
+}
+
+/// Recursively rename the `snake_case` keys of a TOML config to their XML element names.
+#[cfg(feature = "std-only")]
+fn toml_keys_to_xml(value: toml::Value) -> toml::Value {
+    match value {
+        toml::Value::Table(table) => toml::Value::Table(
+            table
+                .into_iter()
+                .map(|(key, value)| (toml_key_to_xml(&key), toml_keys_to_xml(value)))
+                .collect(),
+        ),
+        toml::Value::Array(values) => {
+            toml::Value::Array(values.into_iter().map(toml_keys_to_xml).collect())
+        }
+        value => value,
+    }
+}
+
+/// Map a `snake_case` TOML key to the matching `PascalCase` XML element name.
+#[cfg(feature = "std-only")]
+fn toml_key_to_xml(key: &str) -> String {
+    match key {
+        // Elements whose names aren't just the PascalCase version of the field name
+        "dns" => "DNS".into(),
+        "dns_search" => "DNSSearch".into(),
+        "mtu" => "MTU".into(),
+        "mss_clamp" => "MSSClamp".into(),
+        "allowed_ips" => "AllowedIPs".into(),
+        "excluded_ips" => "ExcludedIPs".into(),
+        "fwmark" => "FwMark".into(),
+        _ => key
+            .split('_')
+            .map(|word| {
+                let mut chars = word.chars();
+                chars
+                    .next()
+                    .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                    .unwrap_or_default()
+            })
+            .collect(),
+    }
+}
+
+/// The error returned when a config exceeds one of our size limits.
+#[cfg(feature = "std-only")]
+fn limit_exceeded(what: &str, limit: usize, actual: usize) -> quick_xml::DeError {
+    quick_xml::DeError::Custom(format!(
+        "too many {} (limit {}, got {})",
+        what, limit, actual
+    ))
+}
+
+/// Walk the raw XML and return an error for the first element that doesn't
+/// correspond to any config field.
+#[cfg(feature = "std-only")]
+fn check_known_elements(s: &str) -> Result<(), quick_xml::DeError> {
+    use quick_xml::events::Event;
+
+    let check = |parents: &[String], name: &[u8]| {
+        let name = String::from_utf8_lossy(name);
+        let known: &[&str] = match parents {
+            // Like serde, we don't care what the root element is called
+            [] => return Ok(()),
+            [_] => CONFIG_ELEMENTS,
+            [_, section] if section == "Interface" => INTERFACE_ELEMENTS,
+            [_, section] if section == "Peer" => PEER_ELEMENTS,
+            [_, section] if section == "Reconnect" => RECONNECT_ELEMENTS,
+            // Anything else would be nested within a value
+            _ => &[],
+        };
+        if known.contains(&&*name) {
+            Ok(())
+        } else {
+            Err(quick_xml::DeError::Custom(format!(
+                "unknown field `{}`",
+                name
+            )))
+        }
+    };
+
+    let mut reader = quick_xml::Reader::from_str(s);
+    let mut buf = vec![];
+    let mut parents = vec![];
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(e) => {
+                check(&parents, e.name())?;
+                parents.push(String::from_utf8_lossy(e.name()).into_owned());
+            }
+            Event::Empty(e) => check(&parents, e.name())?,
+            Event::End(_) => {
+                parents.pop();
+            }
+            Event::Eof => return Ok(()),
+            _ => {}
+        }
+        buf.clear();
+    }
+}
+
+/// The order we sort networks in for `WireGuardConfig::canonicalize`.
+fn network_sort_key(net: &IpNetwork) -> (IpAddr, u8) {
+    (net.ip(), net.prefix())
+}
+
+/// Returns the given peers sorted by their public keys.
+fn sorted_peers(peers: &[PeerConfig]) -> Vec<&PeerConfig> {
+    let mut peers = peers.iter().collect::<Vec<_>>();
+    peers.sort_by(|a, b| a.public_key.as_bytes().cmp(b.public_key.as_bytes()));
+    peers
+}
+
+/// Returns a sorted copy of the given networks.
+fn sorted_networks(nets: &[IpNetwork]) -> Vec<IpNetwork> {
+    let mut nets = nets.to_vec();
+    nets.sort_by_key(network_sort_key);
+    nets
+}
+
+/// Parse a value from its string form, e.g. a base64 encoded key.
+#[cfg(feature = "serde")]
+fn from_str_value<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    use serde::de::Error;
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(Error::custom)
+}
+
+/// Write a private key out base64 encoded, as it's given in a config.
+#[cfg(feature = "serde")]
+fn secret_key_to_base64<S: serde::Serializer>(
+    key: &X25519SecretKey,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&base64::encode(key.as_bytes()))
 }
 
-/// Try to parse the base64 encoded pre-shared key from the config
+/// Write a public key out base64 encoded, as it's given in a config.
+#[cfg(feature = "serde")]
+fn public_key_to_base64<S: serde::Serializer>(
+    key: &X25519PublicKey,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&base64::encode(key.as_bytes()))
+}
+
+/// Write a pre-shared key out base64 encoded, if there is one.
+#[cfg(feature = "serde")]
+fn preshared_key_to_base64<S: serde::Serializer>(
+    key: &Option<[u8; 32]>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match key {
+        Some(key) => serializer.serialize_some(&base64::encode(key)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Parse a list of networks where each value may itself be a comma separated list,
+/// e.g. `<Address>10.0.0.2/32, fd00::2/128</Address>`.
+#[cfg(feature = "serde")]
+fn from_comma_separated<'de, D>(deserializer: D) -> Result<Vec<IpNetwork>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| Error::custom(format!("invalid address {}: {}", value, e)))
+        })
+        .collect()
+}
+
+/// Parse a firewall mark the way `wg` does: `off`, or a number which may be given in hex.
+#[cfg(feature = "serde")]
+fn from_fwmark<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    let s = match Option::<String>::deserialize(deserializer)? {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    let s = s.trim();
+    if s == "off" {
+        return Ok(None);
+    }
+    let fwmark = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    fwmark
+        .map(Some)
+        .map_err(|_| Error::custom(format!("invalid FwMark: {}", s)))
+}
+
+/// Try to parse the base64 (or hex) encoded pre-shared key from the config
 /// into the raw bytes it represents.
-fn from_base64<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
+#[cfg(feature = "serde")]
+fn from_base64_or_hex<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     use serde::de::Error;
-    match Option::<String>::deserialize(deserializer) {
-        Ok(s) => match s {
-            Some(s) => match base64::decode(&s) {
-                Ok(b) => match b.try_into() {
-                    Ok(b) => Ok(Some(b)),
-                    Err(_) => Err(Error::custom("invalid pre-shared key")),
-                },
-                Err(e) => Err(Error::custom(e.to_string())),
-            },
-            None => Ok(None),
-        },
-        Err(e) => Err(e),
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => {
+            // 64 hex characters are valid base64 too, just not of a 32 byte key
+            let key = base64::decode(&s)
+                .ok()
+                .and_then(|b| b.try_into().ok())
+                .or_else(|| decode_hex_key(&s));
+            match key {
+                Some(key) => Ok(Some(key)),
+                None => Err(Error::custom(
+                    "invalid pre-shared key, expected 32 bytes as base64 or 64 hex characters",
+                )),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// Decode a 32 byte key written out as 64 hex characters.
+#[cfg(feature = "serde")]
+fn decode_hex_key(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut key = [0; 32];
+    for (byte, pair) in key.iter_mut().zip(s.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// Configs kept as files under `tests/fixtures`, e.g. ones from bug reports.
+#[cfg(test)]
+pub(crate) mod test_fixtures {
+    use std::path::Path;
+
+    /// Read the fixture `name`, i.e. `tests/fixtures/<name>.xml`.
+    pub fn load_fixture(name: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+            .join(name)
+            .with_extension("xml");
+        std::fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("couldn't read {}: {}", path.display(), err))
+    }
+}
+
+#[cfg(all(test, feature = "std-only"))]
+mod tests {
+    use super::test_fixtures::load_fixture;
+    use super::*;
+
+    const PRIVATE_KEY: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+    const PUBLIC_KEY: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+    const OTHER_PUBLIC_KEY: &str = "ZDIuA2MhGMkoaBLex6DvVKxKwnEztl0GTY+E0TPE4BQ=";
+
+    /// The example config from the README, with the keys filled in.
+    fn readme_xml() -> String {
+        format!(
+            r#"<WireGuard>
+    <Interface>
+        <PrivateKey>{}</PrivateKey>
+        <Address>10.0.0.2/32</Address>
+        <Address>2001:db8::2/64</Address>
+        <DNS>1.1.1.1</DNS>
+        <DNSSearch>vpn.example.com</DNSSearch>
+        <DNSSearch>foo.corp.example.com</DNSSearch>
+    </Interface>
+    <Peer>
+        <PublicKey>{}</PublicKey>
+        <Port>51000</Port>
+        <AllowedIPs>10.0.0.0/24</AllowedIPs>
+        <AllowedIPs>10.10.0.0/24</AllowedIPs>
+        <AllowedIPs>10.20.0.0/24</AllowedIPs>
+        <AllowedIPs>2001:db8::/64</AllowedIPs>
+        <PersistentKeepalive>25</PersistentKeepalive>
+        <Description>Office network</Description>
+    </Peer>
+</WireGuard>
+"#,
+            PRIVATE_KEY, PUBLIC_KEY
+        )
+    }
+
+    fn parse(s: &str) -> WireGuardConfig {
+        WireGuardConfig::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn powershell_here_string() {
+        // As copied out of an indented script block, delimiters and all
+        let xml = readme_xml();
+        let pasted = std::iter::once("@'")
+            .chain(xml.lines())
+            .chain(std::iter::once("'@"))
+            .map(|line| format!("    {}\r\n", line))
+            .collect::<String>();
+        let config = WireGuardConfig::from_powershell_splatting(&pasted).unwrap();
+        assert!(config == parse(&readme_xml()));
+        assert_eq!(
+            config.peers[0].description.as_deref(),
+            Some("Office network")
+        );
+    }
+
+    #[test]
+    fn powershell_expandable_here_string() {
+        let pasted = format!("@\"\n{}\"@", readme_xml());
+        let config = WireGuardConfig::from_powershell_splatting(&pasted).unwrap();
+        assert!(config == parse(&readme_xml()));
+    }
+
+    #[test]
+    fn powershell_ini() {
+        let pasted = format!(
+            "@'\r\n  [Interface]\r\n  PrivateKey = {}\r\n  Address = 10.0.0.2/32\r\n\r\n  \
+             [Peer]\r\n  PublicKey = {}\r\n  AllowedIPs = 0.0.0.0/0\r\n  \
+             Endpoint = vpn.example.com:51820\r\n'@",
+            PRIVATE_KEY, PUBLIC_KEY
+        );
+        let config = WireGuardConfig::from_powershell_splatting(&pasted).unwrap();
+        assert_eq!(
+            config.interface.private_key.as_bytes(),
+            &*base64::decode(PRIVATE_KEY).unwrap()
+        );
+        assert_eq!(
+            config.peers[0].endpoint.as_deref(),
+            Some("vpn.example.com:51820")
+        );
+    }
+
+    /// The README's config with the peer's description swapped out for `description`.
+    fn with_description(description: &str) -> String {
+        readme_xml().replace("Office network", description)
+    }
+
+    #[test]
+    fn unicode_description_round_trips() {
+        for description in [
+            "Работа",
+            "办公室网络",
+            "テスト",
+            "شبكة المكتب",
+            "🏠 home ↔ office 🏢",
+        ] {
+            let config = parse(&with_description(description));
+            assert_eq!(config.peers[0].description.as_deref(), Some(description));
+            assert_eq!(config.validate(), Ok(()));
+
+            let xml = config.to_xml_string();
+            assert!(xml.contains(description));
+            assert!(parse(&xml) == config);
+        }
+    }
+
+    #[test]
+    fn unicode_description_in_ini_comment() {
+        let config = parse(&with_description("Работа 🏢"));
+        let ini = config.to_ini_string(false);
+        assert!(ini.contains("# Работа 🏢\n"));
+    }
+
+    #[test]
+    fn escaped_description_round_trips() {
+        let config = parse(&with_description("R&amp;D &lt;lab&gt; &#x1F52C;"));
+        assert_eq!(config.peers[0].description.as_deref(), Some("R&D <lab> 🔬"));
+        assert!(parse(&config.to_xml_string()) == config);
+    }
+
+    #[test]
+    fn nul_in_description_is_rejected() {
+        let config = parse(&with_description("before\0after"));
+        assert_eq!(
+            config.validate(),
+            Err(vec![ConfigError::ControlCharacter(0)])
+        );
+        assert_eq!(
+            parse(&with_description("before\u{1b}after")).validate(),
+            Err(vec![ConfigError::ControlCharacter(0)])
+        );
+    }
+
+    #[test]
+    fn nul_character_reference_is_rejected() {
+        assert!(WireGuardConfig::from_str(&with_description("before&#0;after")).is_err());
+    }
+
+    /// Check every optional field of a config with just the required ones has its default.
+    fn assert_defaults(config: &WireGuardConfig) {
+        let interface = &config.interface;
+        assert!(interface.dns_servers.is_empty());
+        assert!(interface.search_domains.is_empty());
+        assert_eq!(interface.dead_peer_detection, None);
+        assert_eq!(interface.rekey_watchdog, None);
+        assert_eq!(interface.rate_limit, None);
+        assert_eq!(interface.connection_attempts, None);
+        assert_eq!(interface.listen_port, None);
+        assert_eq!(interface.mtu, None);
+        assert!(!interface.kill_switch);
+        assert!(!interface.mss_clamp);
+        assert_eq!(interface.table, None);
+        assert_eq!(interface.fwmark, None);
+
+        let peer = &config.peers[0];
+        assert_eq!(peer.endpoint, None);
+        assert!(peer.excluded_ips.is_empty());
+        assert!(peer.dns_servers.is_empty());
+        assert!(peer.search_domains.is_empty());
+        assert!(peer.app_tunnel.is_empty());
+        assert_eq!(peer.persistent_keepalive, None);
+        assert_eq!(peer.preshared_key, None);
+        assert_eq!(peer.description, None);
+
+        assert!(!config.strict_config);
+        assert_eq!(config.reconnect, None);
+        assert_eq!(config.notifications, None);
+    }
+
+    #[test]
+    fn minimal_xml_defaults() {
+        let config = parse(&format!(
+            "<WireGuard>
+                <Interface>
+                    <PrivateKey>{}</PrivateKey>
+                    <Address>10.0.0.2/32</Address>
+                </Interface>
+                <Peer>
+                    <PublicKey>{}</PublicKey>
+                    <AllowedIPs>10.0.0.0/24</AllowedIPs>
+                    <Port>51820</Port>
+                </Peer>
+            </WireGuard>",
+            PRIVATE_KEY, PUBLIC_KEY
+        ));
+        assert_defaults(&config);
+        assert_eq!(config.peers[0].port, 51820);
+    }
+
+    #[test]
+    fn minimal_ini_defaults() {
+        let mut config = parse(&format!(
+            "[Interface]
+            PrivateKey = {}
+            Address = 10.0.0.2/32
+
+            [Peer]
+            PublicKey = {}
+            AllowedIPs = 10.0.0.0/24
+            Endpoint = vpn.example.com:51820",
+            PRIVATE_KEY, PUBLIC_KEY
+        ));
+        // wg-quick configs always give the port as part of the endpoint
+        assert_eq!(
+            config.peers[0].endpoint.as_deref(),
+            Some("vpn.example.com:51820")
+        );
+        config.peers[0].endpoint = None;
+        assert_defaults(&config);
+    }
+
+    #[test]
+    fn minimal_toml_defaults() {
+        let config = parse(&format!(
+            r#"[interface]
+            private_key = "{}"
+            address = ["10.0.0.2/32"]
+
+            [[peer]]
+            public_key = "{}"
+            allowed_ips = ["10.0.0.0/24"]
+            port = 51820"#,
+            PRIVATE_KEY, PUBLIC_KEY
+        ));
+        assert_defaults(&config);
+        assert_eq!(config.peers[0].port, 51820);
+    }
+
+    /// The README's example config written as TOML instead.
+    fn readme_toml() -> String {
+        format!(
+            r#"[interface]
+            private_key = "{}"
+            address = ["10.0.0.2/32", "2001:db8::2/64"]
+            dns = ["1.1.1.1"]
+            dns_search = ["vpn.example.com", "foo.corp.example.com"]
+
+            [[peer]]
+            public_key = "{}"
+            port = 51000
+            allowed_ips = ["10.0.0.0/24", "10.10.0.0/24", "10.20.0.0/24", "2001:db8::/64"]
+            persistent_keepalive = 25
+            description = "Office network""#,
+            PRIVATE_KEY, PUBLIC_KEY
+        )
+    }
+
+    #[test]
+    fn toml_matches_xml() {
+        let config = WireGuardConfig::from_toml(&readme_toml()).unwrap();
+        assert!(config == parse(&readme_xml()));
+        // Which `from_str` falls back to for anything that's neither XML nor INI
+        assert!(parse(&readme_toml()) == config);
+    }
+
+    #[test]
+    fn toml_round_trips_through_xml() {
+        let config = parse(&readme_toml());
+        assert!(parse(&config.to_xml_string()) == config);
+    }
+
+    #[test]
+    fn toml_lone_peer_table() {
+        let toml = readme_toml().replace("[[peer]]", "[peer]");
+        assert!(parse(&toml) == parse(&readme_toml()));
+    }
+
+    #[test]
+    fn toml_multiple_peers() {
+        let toml = format!(
+            r#"{}
+
+            [[peer]]
+            public_key = "{}"
+            endpoint = "vpn.example.com:51820"
+            allowed_ips = ["10.30.0.0/24"]"#,
+            readme_toml(),
+            OTHER_PUBLIC_KEY
+        );
+        let config = parse(&toml);
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(
+            config.peers[1].endpoint.as_deref(),
+            Some("vpn.example.com:51820")
+        );
+    }
+
+    #[test]
+    fn toml_renamed_keys() {
+        let config = parse(&format!(
+            r#"[interface]
+            private_key = "{}"
+            address = ["10.0.0.2/32"]
+            mtu = 1380
+            mss_clamp = true
+            fwmark = "0x51820"
+
+            [[peer]]
+            public_key = "{}"
+            port = 51820
+            allowed_ips = ["0.0.0.0/0"]
+            excluded_ips = ["10.99.0.0/16"]"#,
+            PRIVATE_KEY, PUBLIC_KEY
+        ));
+        assert_eq!(config.interface.mtu, Some(1380));
+        assert!(config.interface.mss_clamp);
+        assert_eq!(config.interface.fwmark, Some(0x51820));
+        assert_eq!(
+            config.peers[0].excluded_ips,
+            vec!["10.99.0.0/16".parse::<IpNetwork>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn toml_rejects_bad_values() {
+        let toml = readme_toml().replace("port = 51000", "port = \"51000\"");
+        assert!(WireGuardConfig::from_toml(&toml).is_err());
+    }
+
+    /// The README's example config as a wg-quick INI config.
+    fn readme_ini() -> String {
+        format!(
+            "[Interface]
+            PrivateKey = {}
+            Address = 10.0.0.2/32, 2001:db8::2/64
+            DNS = 1.1.1.1, vpn.example.com, foo.corp.example.com
+
+            [Peer]
+            # Office network
+            PublicKey = {}
+            AllowedIPs = 10.0.0.0/24, 10.10.0.0/24
+            AllowedIPs = 10.20.0.0/24, 2001:db8::/64
+            Endpoint = vpn.example.com:51000
+            PersistentKeepalive = 25",
+            PRIVATE_KEY, PUBLIC_KEY
+        )
+    }
+
+    #[test]
+    fn ini_matches_xml() {
+        let mut config = parse(&readme_ini());
+        assert_eq!(
+            config.peers[0].endpoint.as_deref(),
+            Some("vpn.example.com:51000")
+        );
+        // INI has no room for the XML's description, and the XML leaves the endpoint to the
+        // profile's servers with just the port given separately
+        config.peers[0].endpoint = None;
+        config.peers[0].port = 51000;
+        config.peers[0].description = Some("Office network".into());
+        assert!(config == parse(&readme_xml()));
+    }
+
+    #[test]
+    fn ini_comments_and_blank_lines() {
+        let ini = format!(
+            "# Exported from elsewhere\n\n[Interface]  # ours\nPrivateKey = {}\n\n\
+             Address = 10.0.0.2/32 # the only one\n[Peer]\nPublicKey = {}\n\
+             AllowedIPs = 0.0.0.0/0\n",
+            PRIVATE_KEY, PUBLIC_KEY
+        );
+        let config = parse(&ini);
+        assert_eq!(
+            config.interface.address,
+            ["10.0.0.2/32".parse::<IpNetwork>().unwrap()]
+        );
+        assert_eq!(
+            config.peers[0].allowed_ips,
+            ["0.0.0.0/0".parse::<IpNetwork>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn ini_preshared_key_and_keepalive() {
+        let psk = base64::encode([7u8; 32]);
+        let ini = readme_ini().replace(
+            "PersistentKeepalive = 25",
+            &format!("PersistentKeepalive = 15\nPresharedKey = {}", psk),
+        );
+        let config = parse(&ini);
+        assert_eq!(config.peers[0].preshared_key, Some([7u8; 32]));
+        assert_eq!(config.peers[0].persistent_keepalive, Some(15));
+    }
+
+    #[test]
+    fn ini_multiple_peers() {
+        let ini = format!(
+            "{}\n[Peer]\nPublicKey = {}\nAllowedIPs = 10.30.0.0/24\nEndpoint = 192.0.2.1:51820",
+            readme_ini(),
+            OTHER_PUBLIC_KEY
+        );
+        let config = parse(&ini);
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(
+            config.peers[1].public_key.as_bytes(),
+            &*base64::decode(OTHER_PUBLIC_KEY).unwrap()
+        );
+        assert_eq!(config.peers[1].endpoint.as_deref(), Some("192.0.2.1:51820"));
+    }
+
+    #[test]
+    fn ini_ignores_wg_quick_hooks() {
+        let ini = readme_ini().replace(
+            "DNS =",
+            "PostUp = iptables -A FORWARD -i %i -j ACCEPT\nSaveConfig = true\nDNS =",
+        );
+        assert!(parse(&ini) == parse(&readme_ini()));
+    }
+
+    #[test]
+    fn ini_rejects_malformed_configs() {
+        let ini = readme_ini();
+        let cases = [
+            (
+                ini.replace("[Peer]", "[Peers]"),
+                "line 6: unknown section [Peers]",
+            ),
+            (format!("{}\n[Interface]", ini), "duplicate [Interface]"),
+            (
+                ini.replace("[Interface]", ""),
+                "line 2: expected [Interface] or [Peer] first",
+            ),
+            (
+                ini.replace("PersistentKeepalive = 25", "Keepalive = 25"),
+                "unknown key Keepalive",
+            ),
+            (
+                ini.replace("PersistentKeepalive = 25", "PersistentKeepalive"),
+                "`Key = Value`",
+            ),
+            (
+                ini.replace("PersistentKeepalive = 25", "PersistentKeepalive = soon"),
+                "invalid PersistentKeepalive",
+            ),
+            (ini.replace(":51000", ""), "invalid Endpoint"),
+        ];
+        for (ini, expected) in cases {
+            let err = WireGuardConfig::from_ini_str(&ini)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(
+                err.contains(expected),
+                "{:?} doesn't mention {:?}",
+                err,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn ini_without_interface() {
+        let ini = format!("[Peer]\nPublicKey = {}\nAllowedIPs = 0.0.0.0/0", PUBLIC_KEY);
+        let err = WireGuardConfig::from_ini_str(&ini).err().unwrap();
+        assert!(err.to_string().contains("missing [Interface]"));
+    }
+
+    #[test]
+    fn ini_export() {
+        let config = parse(&readme_ini());
+        let expected = format!(
+            "[Interface]
+PrivateKey = {}
+Address = 10.0.0.2/32, 2001:db8::2/64
+DNS = 1.1.1.1, vpn.example.com, foo.corp.example.com
+
+[Peer]
+PublicKey = {}
+AllowedIPs = 10.0.0.0/24, 10.10.0.0/24, 10.20.0.0/24, 2001:db8::/64
+Endpoint = vpn.example.com:51000
+PersistentKeepalive = 25
+",
+            PRIVATE_KEY, PUBLIC_KEY
+        );
+        assert_eq!(config.to_ini_string(true), expected);
+    }
+
+    #[test]
+    fn ini_round_trips() {
+        let psk = base64::encode([7u8; 32]);
+        let ini = readme_ini()
+            .replace(
+                "DNS =",
+                "MTU = 1380\nListenPort = 51820\nTable = off\nFwMark = 0xca6c\nDNS =",
+            )
+            .replace(
+                "PersistentKeepalive = 25",
+                &format!("PersistentKeepalive = 25\nPresharedKey = {}", psk),
+            );
+        let config = parse(&ini);
+        assert_eq!(config.interface.fwmark, Some(0xca6c));
+        assert!(parse(&config.to_ini_string(true)) == config);
+    }
+
+    #[test]
+    fn ini_export_without_private_key() {
+        let ini = parse(&readme_ini()).to_ini_string(false);
+        assert!(!ini.contains(PRIVATE_KEY));
+        assert!(ini.contains("# PrivateKey omitted"));
+        // Not usable as is, the key has to be filled back in
+        assert!(WireGuardConfig::from_ini_str(&ini).is_err());
+        let ini = ini.replace(
+            "# PrivateKey omitted, fill it in before use",
+            &format!("PrivateKey = {}", PRIVATE_KEY),
+        );
+        assert!(parse(&ini) == parse(&readme_ini()));
+    }
+
+    /// The README's config with `psk` as the peer's pre-shared key.
+    fn with_psk(psk: &str) -> String {
+        readme_xml().replace(
+            "</Peer>",
+            &format!("<PresharedKey>{}</PresharedKey></Peer>", psk),
+        )
+    }
+
+    #[test]
+    fn hex_preshared_key() {
+        let key: [u8; 32] = std::array::from_fn(|i| (i * 8 + 1) as u8);
+        let hex = key.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+        let from_base64 = parse(&with_psk(&base64::encode(key)));
+        let from_hex = parse(&with_psk(&hex));
+        assert_eq!(from_base64.peers[0].preshared_key, Some(key));
+        assert_eq!(from_hex.peers[0].preshared_key, Some(key));
+        assert_eq!(
+            parse(&with_psk(&hex.to_uppercase())).peers[0].preshared_key,
+            Some(key)
+        );
+
+        let ini = readme_ini().replace(
+            "PersistentKeepalive = 25",
+            &format!("PersistentKeepalive = 25\nPresharedKey = {}", hex),
+        );
+        assert_eq!(parse(&ini).peers[0].preshared_key, Some(key));
+    }
+
+    #[test]
+    fn decode_hex_keys() {
+        let hex = "00ff".repeat(16);
+        let key = decode_hex_key(&hex).unwrap();
+        assert_eq!(key[..4], [0x00, 0xff, 0x00, 0xff]);
+        // Only a full 32 bytes' worth will do
+        assert_eq!(decode_hex_key(&hex[..62]), None);
+        assert_eq!(decode_hex_key(&format!("{}00", hex)), None);
+        assert_eq!(decode_hex_key(&hex.replace("ff", "fg")), None);
+        // Multi-byte characters mustn't trip up splitting it into pairs
+        assert_eq!(decode_hex_key(&"é".repeat(32)), None);
+    }
+
+    #[test]
+    fn invalid_preshared_key() {
+        for psk in ["not a key", &base64::encode([7u8; 16]), &"ab".repeat(31)] {
+            let err = WireGuardConfig::from_str(&with_psk(psk)).err().unwrap();
+            assert!(err.to_string().contains("base64 or 64 hex characters"));
+        }
+    }
+
+    #[test]
+    fn dns_servers() {
+        let xml = readme_xml().replace(
+            "<DNS>1.1.1.1</DNS>",
+            "<DNS>1.1.1.1</DNS><DNS>2606:4700:4700::1111</DNS><DNS>1.0.0.1</DNS>",
+        );
+        let config = parse(&xml);
+        let expected: Vec<IpAddr> = vec![
+            "1.1.1.1".parse().unwrap(),
+            "2606:4700:4700::1111".parse().unwrap(),
+            "1.0.0.1".parse().unwrap(),
+        ];
+        // Kept in order, the first is the one tried first
+        assert_eq!(config.interface.dns_servers, expected);
+    }
+
+    #[test]
+    fn dns_server_must_be_address() {
+        let xml = readme_xml().replace("<DNS>1.1.1.1</DNS>", "<DNS>dns.example.com</DNS>");
+        assert!(WireGuardConfig::from_str(&xml).is_err());
+    }
+
+    #[test]
+    fn ini_dns_mixes_servers_and_domains() {
+        let config = parse(&readme_ini());
+        assert_eq!(
+            config.interface.dns_servers,
+            vec!["1.1.1.1".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(
+            config.interface.search_domains,
+            ["vpn.example.com", "foo.corp.example.com"]
+        );
+    }
+
+    #[test]
+    fn dns_search_domains() {
+        let config = parse(&readme_xml());
+        assert_eq!(
+            config.interface.search_domains,
+            ["vpn.example.com", "foo.corp.example.com"]
+        );
+
+        // Their order matters so canonicalizing leaves it be
+        let mut canonical = parse(&readme_xml());
+        canonical.canonicalize();
+        assert_eq!(
+            canonical.interface.search_domains,
+            config.interface.search_domains
+        );
+    }
+
+    #[test]
+    fn peer_dns_search_domains() {
+        let xml = readme_xml().replace(
+            "<PersistentKeepalive>",
+            "<DNS>10.0.0.53</DNS><DNSSearch>corp.example.com</DNSSearch><PersistentKeepalive>",
+        );
+        let peer = &parse(&xml).peers[0];
+        assert_eq!(
+            peer.dns_servers,
+            vec!["10.0.0.53".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(peer.search_domains, ["corp.example.com"]);
+    }
+
+    #[test]
+    fn dns_search_domains_round_trip_through_ini() {
+        let config = parse(&readme_xml());
+        let ini = config.to_ini_string(false);
+        assert!(ini.contains("DNS = 1.1.1.1, vpn.example.com, foo.corp.example.com\n"));
+
+        let ini = ini.replace(
+            "# PrivateKey omitted, fill it in before use",
+            &format!("PrivateKey = {}", PRIVATE_KEY),
+        );
+        let reparsed = parse(&ini);
+        assert_eq!(reparsed.interface.dns_servers, config.interface.dns_servers);
+        assert_eq!(
+            reparsed.interface.search_domains,
+            config.interface.search_domains
+        );
+    }
+
+    #[test]
+    fn listen_port() {
+        let xml =
+            readme_xml().replace("</Interface>", "<ListenPort>51820</ListenPort></Interface>");
+        let config = parse(&xml);
+        assert_eq!(config.interface.listen_port, Some(51820));
+        assert!(config.to_ini_string(false).contains("ListenPort = 51820\n"));
+        assert!(parse(&config.to_xml_string()) == config);
+
+        let ini = readme_ini().replace("DNS =", "ListenPort = 51820\nDNS =");
+        assert_eq!(parse(&ini).interface.listen_port, Some(51820));
+    }
+
+    #[test]
+    fn listen_port_out_of_range() {
+        let xml =
+            readme_xml().replace("</Interface>", "<ListenPort>65536</ListenPort></Interface>");
+        assert!(WireGuardConfig::from_str(&xml).is_err());
+    }
+
+    #[test]
+    fn rate_limit() {
+        assert_eq!(parse(&readme_xml()).interface.rate_limit, None);
+
+        let xml = readme_xml().replace("</Interface>", "<RateLimit>20</RateLimit></Interface>");
+        let config = parse(&xml);
+        assert_eq!(config.interface.rate_limit, Some(20));
+        assert!(parse(&config.to_xml_string()) == config);
+
+        let xml = readme_xml().replace("</Interface>", "<RateLimit>-1</RateLimit></Interface>");
+        assert!(WireGuardConfig::from_str(&xml).is_err());
+    }
+
+    #[test]
+    fn excluded_ips() {
+        let ini = readme_ini().replace(
+            "[Peer]\n",
+            "[Peer]\nExcludedIPs = 192.168.0.0/16, 172.16.0.0/12\n",
+        );
+        let config = parse(&ini);
+        assert_eq!(
+            config.peers[0].excluded_ips,
+            [
+                "192.168.0.0/16".parse().unwrap(),
+                "172.16.0.0/12".parse().unwrap()
+            ]
+        );
+        assert!(parse(&config.to_xml_string()) == config);
+        // Being our own extension it's left out of the INI for other tools
+        assert!(!config.to_ini_string(true).contains("ExcludedIPs"));
+    }
+
+    #[test]
+    fn connection_attempts() {
+        let xml = readme_xml().replace(
+            "</Interface>",
+            "<ConnectionAttempts>3</ConnectionAttempts></Interface>",
+        );
+        let config = parse(&xml);
+        assert_eq!(config.interface.connection_attempts, Some(3));
+        assert!(parse(&config.to_xml_string()) == config);
+    }
+
+    /// The README's config with a second peer added after the first.
+    fn two_peers() -> String {
+        readme_xml().replace(
+            "</WireGuard>",
+            &format!(
+                "<Peer>
+        <PublicKey>{}</PublicKey>
+        <Port>51001</Port>
+        <AllowedIPs>10.30.0.0/24</AllowedIPs>
+    </Peer>
+</WireGuard>",
+                OTHER_PUBLIC_KEY
+            ),
+        )
+    }
+
+    #[test]
+    fn canonicalize_sorts_networks() {
+        let xml = readme_xml()
+            .replace(
+                "<AllowedIPs>10.0.0.0/24</AllowedIPs>",
+                "<AllowedIPs>2001:db8::/48</AllowedIPs><AllowedIPs>10.0.0.0/24</AllowedIPs>",
+            )
+            .replace(
+                "<Address>10.0.0.2/32</Address>",
+                "<Address>10.0.0.3/32</Address><Address>10.0.0.2/32</Address>",
+            )
+            .replace(
+                "</Peer>",
+                concat!(
+                    "<ExcludedIPs>10.0.0.128/25</ExcludedIPs>",
+                    "<ExcludedIPs>10.0.0.0/25</ExcludedIPs></Peer>"
+                ),
+            );
+        let mut config = parse(&xml);
+        config.canonicalize();
+
+        let networks =
+            |networks: &[IpNetwork]| networks.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            networks(&config.interface.address),
+            ["10.0.0.2/32", "10.0.0.3/32", "2001:db8::2/64"]
+        );
+        assert_eq!(
+            networks(&config.peers[0].allowed_ips),
+            [
+                "10.0.0.0/24",
+                "10.10.0.0/24",
+                "10.20.0.0/24",
+                "2001:db8::/48",
+                "2001:db8::/64"
+            ]
+        );
+        assert_eq!(
+            networks(&config.peers[0].excluded_ips),
+            ["10.0.0.0/25", "10.0.0.128/25"]
+        );
+
+        // Sorting is all it does so it's equal to what it started out as
+        assert!(config == parse(&xml));
+        let xml = config.to_xml_string();
+        config.canonicalize();
+        assert_eq!(config.to_xml_string(), xml);
+    }
+
+    #[test]
+    fn canonicalize_sorts_peers() {
+        let mut config = parse(&two_peers());
+        config.canonicalize();
+        assert_eq!(
+            config.peers[0].public_key.as_bytes(),
+            &*base64::decode(OTHER_PUBLIC_KEY).unwrap()
+        );
+        assert_eq!(
+            config.peers[1].public_key.as_bytes(),
+            &*base64::decode(PUBLIC_KEY).unwrap()
+        );
+
+        let mut reordered = parse(&two_peers());
+        reordered.peers.reverse();
+        reordered.canonicalize();
+        assert_eq!(reordered.to_xml_string(), config.to_xml_string());
+
+        // Still equal, but as far as a live tunnel is concerned the peers moved
+        let original = parse(&two_peers());
+        assert!(original == config);
+        assert!(original.diff(&config).other_changed);
+    }
+
+    #[test]
+    fn equality_ignores_order() {
+        let config = parse(&two_peers());
+        let mut reordered = parse(&two_peers());
+        reordered.peers.reverse();
+        reordered.peers[1].allowed_ips.reverse();
+        reordered.interface.address.reverse();
+        assert!(config == reordered);
+
+        // But not the order of DNS servers, the first is tried first
+        let mut dns = parse(&two_peers());
+        dns.interface.dns_servers.push("1.0.0.1".parse().unwrap());
+        let mut swapped = parse(&two_peers());
+        swapped
+            .interface
+            .dns_servers
+            .insert(0, "1.0.0.1".parse().unwrap());
+        assert!(dns != swapped);
+
+        let mut changed = parse(&two_peers());
+        changed.peers[1].port = 51002;
+        assert!(config != changed);
+    }
+
+    #[test]
+    fn diff_unchanged() {
+        let config = parse(&two_peers());
+        let mut reordered = parse(&two_peers());
+        reordered.peers[0].allowed_ips.reverse();
+        assert_eq!(config.diff(&reordered), ConfigDelta::default());
+        assert_eq!(config.diff(&reordered).to_string(), "none");
+    }
+
+    #[test]
+    fn diff_routes_dns_and_keepalive() {
+        let old = parse(&two_peers());
+        let mut new = parse(&two_peers());
+        new.peers[1]
+            .allowed_ips
+            .push("10.40.0.0/24".parse().unwrap());
+        new.interface.dns_servers.push("1.0.0.1".parse().unwrap());
+        new.peers[0].persistent_keepalive = Some(10);
+
+        let delta = old.diff(&new);
+        assert_eq!(
+            delta,
+            ConfigDelta {
+                routes_changed: true,
+                dns_changed: true,
+                keepalive_changed: vec![0],
+                ..ConfigDelta::default()
+            }
+        );
+        assert_eq!(delta.to_string(), "routes, dns, keepalive");
+    }
+
+    #[test]
+    fn diff_peers_added_and_removed() {
+        let one = parse(&readme_xml());
+        let two = parse(&two_peers());
+
+        let delta = one.diff(&two);
+        assert_eq!(delta.peers_added, [1]);
+        assert!(delta.peers_removed.is_empty());
+        // The peer count decides how servers get handed out
+        assert!(delta.other_changed);
+
+        let delta = two.diff(&one);
+        assert_eq!(delta.peers_removed, [1]);
+        assert!(delta.peers_added.is_empty());
+    }
+
+    #[test]
+    fn diff_keys_are_other_changes() {
+        let old = parse(&two_peers());
+        let xml = two_peers().replace(PRIVATE_KEY, &base64::encode([1u8; 32]));
+        assert!(old.diff(&parse(&xml)).other_changed);
+
+        // Peers swapping places aren't added or removed but they do change transports
+        let mut swapped = parse(&two_peers());
+        swapped.peers.reverse();
+        let delta = old.diff(&swapped);
+        assert!(delta.peers_added.is_empty() && delta.peers_removed.is_empty());
+        assert!(delta.other_changed);
+    }
+
+    #[test]
+    fn routes_within() {
+        let config = parse(&two_peers());
+        assert!(config.routes_within(&config));
+
+        // Narrower routes are still carried by the wider ones
+        let mut narrower = parse(&two_peers());
+        narrower.peers[1].allowed_ips = vec!["10.30.0.128/25".parse().unwrap()];
+        assert!(narrower.routes_within(&config));
+        assert!(!config.routes_within(&narrower));
+
+        // Anything outside them isn't
+        let mut wider = parse(&two_peers());
+        wider.peers[1].allowed_ips = vec!["10.30.0.0/16".parse().unwrap()];
+        assert!(!wider.routes_within(&config));
+
+        let mut default_route = parse(&two_peers());
+        default_route.peers[1].allowed_ips =
+            vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()];
+        assert!(wider.routes_within(&default_route));
+
+        // Exclusions have to match exactly
+        let mut excluded = parse(&two_peers());
+        excluded.peers[0].excluded_ips = vec!["10.0.0.128/25".parse().unwrap()];
+        assert!(!excluded.routes_within(&config));
+        assert!(!config.routes_within(&excluded));
+    }
+
+    /// What `validate` makes of the README's config with `from` replaced by `to`.
+    fn validate_with(from: &str, to: &str) -> Result<(), Vec<ConfigError>> {
+        let xml = readme_xml();
+        assert!(xml.contains(from), "{:?} isn't in the config", from);
+        parse(&xml.replace(from, to)).validate()
+    }
+
+    #[test]
+    fn validate_readme() {
+        assert_eq!(parse(&readme_xml()).validate(), Ok(()));
+        assert_eq!(parse(&two_peers()).validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_zero_private_key() {
+        let zeroes = base64::encode([0u8; 32]);
+        assert_eq!(
+            validate_with(PRIVATE_KEY, &zeroes),
+            Err(vec![ConfigError::ZeroPrivateKey])
+        );
+    }
+
+    #[test]
+    fn validate_test_net_address() {
+        for address in ["192.0.2.2/32", "198.51.100.2/32", "203.0.113.2/32"] {
+            assert_eq!(
+                validate_with("10.0.0.2/32", address),
+                Err(vec![ConfigError::TestNetAddress(address.parse().unwrap())])
+            );
+        }
+        // Only the addresses themselves are checked, not whatever's around them
+        assert_eq!(validate_with("10.0.0.2/32", "192.0.3.2/32"), Ok(()));
+    }
+
+    #[test]
+    fn validate_empty_allowed_ips() {
+        // An XML config needs at least one `AllowedIPs` element, a TOML one can have an empty list
+        let mut config = parse(&readme_xml());
+        config.peers[0].allowed_ips.clear();
+        assert_eq!(
+            config.validate(),
+            Err(vec![ConfigError::EmptyAllowedIps(0)])
+        );
+
+        let toml = readme_toml().replace("allowed_ips = [", "allowed_ips = [] # [");
+        assert_eq!(
+            WireGuardConfig::from_toml(&toml).unwrap().validate(),
+            Err(vec![ConfigError::EmptyAllowedIps(0)])
+        );
+    }
+
+    #[test]
+    fn validate_conflicting_allowed_ips() {
+        let xml = two_peers().replace(
+            "<AllowedIPs>10.30.0.0/24</AllowedIPs>",
+            "<AllowedIPs>10.10.0.0/24</AllowedIPs>",
+        );
+        assert_eq!(
+            parse(&xml).validate(),
+            Err(vec![ConfigError::ConflictingAllowedIps(
+                "10.10.0.0/24".parse().unwrap(),
+                0,
+                1
+            )])
+        );
+    }
+
+    #[test]
+    fn validate_zero_keepalive() {
+        assert_eq!(
+            validate_with(
+                "<PersistentKeepalive>25</PersistentKeepalive>",
+                "<PersistentKeepalive>0</PersistentKeepalive>"
+            ),
+            Err(vec![ConfigError::ZeroKeepalive(0)])
+        );
+    }
+
+    #[test]
+    fn validate_zero_preshared_key() {
+        let zeroes = format!("<PresharedKey>{}</PresharedKey></Peer>", "0".repeat(64));
+        assert_eq!(
+            validate_with("</Peer>", &zeroes),
+            Err(vec![ConfigError::ZeroPresharedKey(0)])
+        );
+    }
+
+    #[test]
+    fn validate_endpoint() {
+        assert_eq!(
+            validate_with("<Port>51000</Port>", "<Endpoint>vpn.example.com</Endpoint>"),
+            Err(vec![ConfigError::InvalidEndpoint(0)])
+        );
+        assert_eq!(
+            validate_with("<Port>51000</Port>", ""),
+            Err(vec![ConfigError::MissingPort(0)])
+        );
+        assert_eq!(
+            validate_with(
+                "<Port>51000</Port>",
+                "<Endpoint>[2001:db8::1]:51820</Endpoint>"
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn validate_mtu_too_small() {
+        let mut config = parse(&readme_xml());
+        config.interface.mtu = Some(20);
+        assert_eq!(
+            config.validate(),
+            Err(vec![ConfigError::MtuTooSmall(20, 1280)])
+        );
+        config.interface.mtu = Some(1280);
+        assert_eq!(config.validate(), Ok(()));
+
+        // IPv4 makes do with less
+        config.interface.address.retain(|network| network.is_ipv4());
+        config.peers[0]
+            .allowed_ips
+            .retain(|network| network.is_ipv4());
+        config.interface.mtu = Some(575);
+        assert_eq!(
+            config.validate(),
+            Err(vec![ConfigError::MtuTooSmall(575, 576)])
+        );
+        config.interface.mtu = Some(576);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_peer_dns_without_search() {
+        let mut config = parse(&two_peers());
+        config.peers[1].dns_servers = vec!["10.30.0.53".parse().unwrap()];
+        assert_eq!(
+            config.validate(),
+            Err(vec![ConfigError::PeerDnsWithoutSearch(1)])
+        );
+
+        config.peers[1].search_domains = vec!["cloud.example.com".into()];
+        assert_eq!(config.validate(), Ok(()));
+        // Search domains alone are fine, they go to the interface's DNS servers
+        config.peers[1].dns_servers.clear();
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_reports_every_error() {
+        let xml = readme_xml()
+            .replace(PRIVATE_KEY, &base64::encode([0u8; 32]))
+            .replace("<Port>51000</Port>", "")
+            .replace(
+                "<PersistentKeepalive>25</PersistentKeepalive>",
+                "<PersistentKeepalive>0</PersistentKeepalive>",
+            );
+        let errors = parse(&xml).validate().unwrap_err();
+        assert_eq!(
+            errors,
+            [
+                ConfigError::ZeroPrivateKey,
+                ConfigError::ZeroKeepalive(0),
+                ConfigError::MissingPort(0)
+            ]
+        );
+        assert_eq!(
+            errors[2].to_string(),
+            "peer 0 has neither an Endpoint nor a Port"
+        );
+    }
+
+    #[test]
+    fn reconnect_delay_doubles() {
+        let policy = ReconnectPolicy::default();
+        let delays = (0..7).map(|attempt| policy.delay(attempt).as_secs());
+        assert!(delays.eq([1, 2, 4, 8, 16, 30, 30]));
+    }
+
+    #[test]
+    fn reconnect_delay_never_overflows() {
+        let policy = ReconnectPolicy {
+            max_attempts: u32::MAX,
+            base_delay_ms: 1_000,
+            max_delay_ms: u64::MAX,
+        };
+        assert_eq!(policy.delay(63), Duration::from_millis(u64::MAX));
+        assert_eq!(policy.delay(64), Duration::from_millis(u64::MAX));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_millis(u64::MAX));
+
+        let capped = ReconnectPolicy::default();
+        assert_eq!(capped.delay(u32::MAX), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn reconnect_policy() {
+        let xml = readme_xml().replace(
+            "</WireGuard>",
+            "<Reconnect><MaxAttempts>5</MaxAttempts><BaseDelayMs>500</BaseDelayMs>\
+             </Reconnect></WireGuard>",
+        );
+        let config = parse(&xml);
+        // Anything left out keeps its default
+        assert_eq!(
+            config.reconnect,
+            Some(ReconnectPolicy {
+                max_attempts: 5,
+                base_delay_ms: 500,
+                ..ReconnectPolicy::default()
+            })
+        );
+        assert!(parse(&config.to_xml_string()) == config);
+    }
+
+    #[test]
+    fn multiple_peers() {
+        let config = parse(&load_fixture("valid_multi_peer"));
+        let descriptions = config
+            .peers
+            .iter()
+            .map(|peer| peer.description.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        // In the order they were given, which decides their servers
+        assert_eq!(descriptions, ["First site", "Second site"]);
+        assert_eq!(config.peers[1].allowed_ips.len(), 2);
+        assert!(config.peers[0].preshared_key.is_none());
+        assert!(config.peers[1].preshared_key.is_some());
+
+        let round_tripped = parse(&config.to_xml_string());
+        assert!(round_tripped == config);
+        assert_eq!(
+            round_tripped.peers[0].public_key.as_bytes(),
+            config.peers[0].public_key.as_bytes()
+        );
+    }
+
+    /// What a fixture should come out as.
+    enum Expected {
+        /// Parses and passes `validate`
+        Valid,
+        /// Fails to parse with an error mentioning the given text
+        ParseError(&'static str),
+        /// Parses but `validate` finds these issues
+        Invalid(Vec<ConfigError>),
+    }
+
+    #[test]
+    fn fixtures() {
+        let conflict =
+            |network: &str| ConfigError::ConflictingAllowedIps(network.parse().unwrap(), 0, 1);
+        let fixtures = [
+            ("valid_single_peer", Expected::Valid),
+            ("valid_multi_peer", Expected::Valid),
+            ("ipv6_only", Expected::Valid),
+            ("full_tunnel", Expected::Valid),
+            ("missing_private_key", Expected::ParseError("PrivateKey")),
+            ("invalid_base64_psk", Expected::ParseError("pre-shared key")),
+            (
+                "duplicate_peer",
+                Expected::Invalid(vec![conflict("10.0.0.0/24")]),
+            ),
+            ("very_long_comment", Expected::Valid),
+        ];
+
+        for (name, expected) in fixtures {
+            match (WireGuardConfig::from_str(&load_fixture(name)), expected) {
+                (Ok(config), Expected::Valid) => assert_eq!(config.validate(), Ok(()), "{}", name),
+                (Ok(config), Expected::Invalid(errors)) => {
+                    assert_eq!(config.validate(), Err(errors), "{}", name)
+                }
+                (Err(err), Expected::ParseError(text)) => {
+                    assert!(err.to_string().contains(text), "{}: {}", name, err)
+                }
+                (Ok(_), Expected::ParseError(_)) => panic!("{} should have failed to parse", name),
+                (Err(err), _) => panic!("{} failed to parse: {}", name, err),
+            }
+        }
     }
 }
@@ -0,0 +1,113 @@
+//! The errors our plugin can run into and how they map onto HRESULTs for the platform.
+
+use std::fmt;
+
+use boringtun::noise::errors::WireGuardError;
+use windows::{
+    core::{Error, HRESULT},
+    Win32::Foundation::{E_INVALIDARG, E_UNEXPECTED},
+};
+
+use crate::keystore::KeyStoreError;
+
+/// Everything that can go wrong in the plugin.
+///
+/// Converts into a `windows::core::Error` (with a matching HRESULT) when handed back to the
+/// platform, but until then lets us tell the different failures apart.
+#[derive(Debug)]
+pub enum PluginError {
+    /// The platform didn't pass along an argument we need
+    MissingArgument(&'static str),
+    /// The config couldn't be parsed
+    ConfigParse(quick_xml::DeError),
+    /// The config parsed fine but we can't use it as is
+    ConfigInvalid(String),
+    /// The config's private key is in the key store but we couldn't get it out
+    KeyStore(KeyStoreError),
+    /// boringtun refused to create a tunnel for a peer
+    TunnCreate(String),
+    /// We couldn't connect a peer's socket to any of its servers
+    SocketConnect(Error),
+    /// boringtun failed to encapsulate a packet
+    Encap(WireGuardError),
+    /// boringtun failed to decapsulate a frame
+    Decap(WireGuardError),
+    /// boringtun failed to update a tunnel's timers
+    Timers(WireGuardError),
+    /// Some other call into the platform failed
+    Platform(Error),
+}
+
+impl PluginError {
+    /// The name of the variant, for logging.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PluginError::MissingArgument(_) => "MissingArgument",
+            PluginError::ConfigParse(_) => "ConfigParse",
+            PluginError::ConfigInvalid(_) => "ConfigInvalid",
+            PluginError::KeyStore(_) => "KeyStore",
+            PluginError::TunnCreate(_) => "TunnCreate",
+            PluginError::SocketConnect(_) => "SocketConnect",
+            PluginError::Encap(_) => "Encap",
+            PluginError::Decap(_) => "Decap",
+            PluginError::Timers(_) => "Timers",
+            PluginError::Platform(_) => "Platform",
+        }
+    }
+
+    /// The HRESULT we report to the platform for this error.
+    pub fn code(&self) -> HRESULT {
+        match self {
+            PluginError::ConfigParse(_) | PluginError::ConfigInvalid(_) => E_INVALIDARG,
+            PluginError::SocketConnect(err) | PluginError::Platform(err) => err.code(),
+            PluginError::KeyStore(err) => err.error.code(),
+            // TODO: Better errors than `E_UNEXPECTED`?
+            PluginError::MissingArgument(_)
+            | PluginError::TunnCreate(_)
+            | PluginError::Encap(_)
+            | PluginError::Decap(_)
+            | PluginError::Timers(_) => E_UNEXPECTED,
+        }
+    }
+
+    /// Whether the error is down to the config rather than something going wrong at runtime.
+    pub fn is_config_error(&self) -> bool {
+        matches!(
+            self,
+            PluginError::ConfigParse(_) | PluginError::ConfigInvalid(_) | PluginError::KeyStore(_)
+        )
+    }
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginError::MissingArgument(arg) => write!(f, "missing argument: {}", arg),
+            PluginError::ConfigParse(err) => write!(f, "failed to parse config: {}", err),
+            PluginError::ConfigInvalid(msg) => write!(f, "invalid config: {}", msg),
+            PluginError::KeyStore(err) => write!(f, "{}", err),
+            PluginError::TunnCreate(msg) => write!(f, "failed to create tunnel: {}", msg),
+            PluginError::SocketConnect(err) => write!(f, "failed to connect socket: {}", err),
+            PluginError::Encap(err) => write!(f, "encap error: {:?}", err),
+            PluginError::Decap(err) => write!(f, "decap error: {:?}", err),
+            PluginError::Timers(err) => write!(f, "update_timers error: {:?}", err),
+            PluginError::Platform(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<Error> for PluginError {
+    fn from(err: Error) -> Self {
+        PluginError::Platform(err)
+    }
+}
+
+impl From<PluginError> for Error {
+    fn from(err: PluginError) -> Self {
+        match err {
+            // Already a platform error, pass it along untouched
+            PluginError::SocketConnect(err) | PluginError::Platform(err) => err,
+            err => Error::new(err.code(), err.to_string().into()),
+        }
+    }
+}
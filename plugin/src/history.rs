@@ -0,0 +1,211 @@
+//! A rolling log of the tunnel's connections, kept in the app's local data so it outlives the
+//! ETW traces.
+//!
+//! The plugin appends to it as the tunnel comes up, goes down or fails to connect, while the
+//! foreground app reads it back to show the user. It's a JSON array of `HistoryEntry`s, oldest
+//! first, capped at `MAX_ENTRIES`.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use windows::{
+    core::*,
+    Storage::ApplicationData,
+    Win32::Foundation::{E_FAIL, WIN32_ERROR},
+};
+
+/// The file under the app's local folder the history lives in.
+const HISTORY_FILE: &str = "history.json";
+
+/// How many entries we keep before dropping the oldest.
+const MAX_ENTRIES: usize = 100;
+
+/// Serializes our own read-modify-write cycles, the app only ever reads.
+static HISTORY_LOCK: Mutex<()> = Mutex::new(());
+
+/// What happened to the tunnel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryEvent {
+    Connected,
+    Disconnected,
+    Failed,
+}
+
+/// A single entry in the connection history.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// When it happened, as an ISO-8601 UTC timestamp
+    pub timestamp: String,
+    pub event: HistoryEvent,
+    /// The server we were connecting or connected to
+    pub server: String,
+    /// How long the tunnel was up for, only set on disconnecting
+    pub duration_secs: u64,
+    /// Only set on disconnecting
+    pub bytes_sent: u64,
+    /// Only set on disconnecting
+    pub bytes_received: u64,
+    /// Why we disconnected or failed to connect, if we know
+    pub reason: Option<String>,
+}
+
+impl HistoryEntry {
+    /// A new entry timestamped now, without any stats.
+    pub fn new(event: HistoryEvent, server: &str, reason: Option<&str>) -> Self {
+        Self {
+            timestamp: iso8601(SystemTime::now()),
+            event,
+            server: server.into(),
+            duration_secs: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            reason: reason.map(Into::into),
+        }
+    }
+
+    /// Fill in how long the tunnel was up and what went through it.
+    pub fn with_stats(mut self, duration: Duration, bytes_sent: u64, bytes_received: u64) -> Self {
+        self.duration_secs = duration.as_secs();
+        self.bytes_sent = bytes_sent;
+        self.bytes_received = bytes_received;
+        self
+    }
+}
+
+/// The connection history as stored on disk.
+pub struct ConnectionHistory {
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl ConnectionHistory {
+    /// Read the history, creating an empty one if there's none yet.
+    ///
+    /// A file that doesn't parse is reset rather than treated as an error, the history isn't
+    /// worth failing over.
+    pub fn load() -> Result<Self> {
+        let path = history_path()?;
+        let (entries, rewrite) = match fs::read(&path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(entries) => (entries, false),
+                Err(_) => (VecDeque::new(), true),
+            },
+            // First run
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (VecDeque::new(), true),
+            Err(err) => return Err(io_error(err)),
+        };
+
+        let history = Self { entries };
+        if rewrite {
+            history.save(&path)?;
+        }
+        Ok(history)
+    }
+
+    /// Add an entry to the end of the history, dropping the oldest if it's full.
+    pub fn append(entry: HistoryEntry) -> Result<()> {
+        let _guard = HISTORY_LOCK.lock().unwrap();
+        let mut history = Self::load()?;
+        history.entries.push_back(entry);
+        while history.entries.len() > MAX_ENTRIES {
+            history.entries.pop_front();
+        }
+        history.save(&history_path()?)
+    }
+
+    /// The entries, oldest first.
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+
+    /// Write the history out, via a temporary file so a reader never sees half of it.
+    fn save(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.entries)
+            .map_err(|err| Error::new(E_FAIL, err.to_string().into()))?;
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, data).map_err(io_error)?;
+        fs::rename(&tmp_path, path).map_err(io_error)
+    }
+}
+
+/// Where the history lives, under the app's local folder.
+fn history_path() -> Result<PathBuf> {
+    let folder = ApplicationData::Current()?.LocalFolder()?.Path()?;
+    Ok(PathBuf::from(folder.to_string()).join(HISTORY_FILE))
+}
+
+/// Map an I/O error onto the matching HRESULT, where there is one.
+fn io_error(err: io::Error) -> Error {
+    let code = err
+        .raw_os_error()
+        .map_or(E_FAIL, |code| HRESULT::from(WIN32_ERROR(code as u32)));
+    Error::new(code, err.to_string().into())
+}
+
+/// Format `time` as an ISO-8601 UTC timestamp, to the second.
+fn iso8601(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // Days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn iso8601_epoch() {
+        assert_eq!(iso8601(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        // Times before the epoch don't have a representation of their own
+        assert_eq!(
+            iso8601(UNIX_EPOCH - Duration::from_secs(1)),
+            "1970-01-01T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn iso8601_dates() {
+        assert_eq!(iso8601(at(951_782_400)), "2000-02-29T00:00:00Z");
+        assert_eq!(iso8601(at(1_234_567_890)), "2009-02-13T23:31:30Z");
+        assert_eq!(iso8601(at(1_709_251_199)), "2024-02-29T23:59:59Z");
+        assert_eq!(iso8601(at(1_735_689_599)), "2024-12-31T23:59:59Z");
+        assert_eq!(iso8601(at(4_107_542_400)), "2100-03-01T00:00:00Z");
+    }
+
+    #[test]
+    fn iso8601_drops_fractions() {
+        let time = at(1_234_567_890) + Duration::from_millis(999);
+        assert_eq!(iso8601(time), "2009-02-13T23:31:30Z");
+    }
+}
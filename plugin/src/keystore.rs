@@ -0,0 +1,293 @@
+//! Keeping the interface's private key in the Windows Credential Manager rather than inline in
+//! the VPN profile, where anyone able to read the profile's XML can see it.
+//!
+//! Instead of the key itself a config can give `vault://<resource>` as its `PrivateKey`, in any
+//! of the formats we accept. The foreground app stores keys under the profile's name once it
+//! has installed a profile, and the plugin swaps the reference back out for the key just before
+//! parsing the config.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use boringtun::crypto::x25519::X25519SecretKey;
+use windows::{
+    core::*,
+    Security::Credentials::{PasswordCredential, PasswordVault},
+    Win32::Foundation::E_INVALIDARG,
+};
+
+use crate::config::WireGuardConfig;
+
+/// The prefix marking a `PrivateKey` as a reference into the key store.
+pub const VAULT_PREFIX: &str = "vault://";
+
+/// The user name our keys are stored under, the vault wants one alongside each resource.
+const VAULT_USER: &str = "WireGuard";
+
+/// Somewhere to keep private keys, looked up by a resource name.
+pub trait SecureKeyStore {
+    /// Store the (base64 encoded) `key` under `resource`, replacing any key already there.
+    fn store(&self, resource: &str, key: &str) -> Result<()>;
+
+    /// Get back the key stored under `resource`.
+    fn retrieve(&self, resource: &str) -> Result<String>;
+}
+
+/// The key store backed by the Credential Manager, via the app's `PasswordVault`.
+///
+/// The vault is per package so the foreground app and the plugin share it.
+pub struct PasswordVaultStore {
+    vault: PasswordVault,
+}
+
+impl PasswordVaultStore {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            vault: PasswordVault::new()?,
+        })
+    }
+}
+
+impl SecureKeyStore for PasswordVaultStore {
+    fn store(&self, resource: &str, key: &str) -> Result<()> {
+        let credential = PasswordCredential::CreatePasswordCredential(resource, VAULT_USER, key)?;
+        self.vault.Add(credential)
+    }
+
+    fn retrieve(&self, resource: &str) -> Result<String> {
+        let credential = self.vault.Retrieve(resource, VAULT_USER)?;
+        // Only the credential's identity comes back until we ask for the password itself
+        credential.RetrievePassword()?;
+        Ok(credential.Password()?.to_string())
+    }
+}
+
+/// We couldn't get at the key a config referred to.
+#[derive(Debug)]
+pub struct KeyStoreError {
+    /// The resource the key should've been stored under
+    pub resource: String,
+    pub error: Error,
+}
+
+impl fmt::Display for KeyStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "couldn't retrieve the private key for {}{} from the Credential Manager: {}",
+            VAULT_PREFIX,
+            self.resource,
+            self.error.message()
+        )
+    }
+}
+
+/// Replace a `vault://` reference given as the (unparsed) config's `PrivateKey` with the key it
+/// refers to.
+///
+/// Only the value of a `PrivateKey` element or key counts, `vault://` anywhere else (e.g. in a
+/// peer's description) is left alone. The config is returned as is if it doesn't have one.
+pub fn resolve_references<'a>(
+    config: &'a str,
+    store: &dyn SecureKeyStore,
+) -> std::result::Result<Cow<'a, str>, KeyStoreError> {
+    let (start, end) = match find_private_key_reference(config) {
+        Some(span) => span,
+        None => return Ok(Cow::Borrowed(config)),
+    };
+
+    // Profile names can have spaces in them, only trailing ones don't count
+    let resource = config[start + VAULT_PREFIX.len()..end].trim_end();
+    let resource = if config.trim_start().starts_with('<') {
+        let unescaped =
+            quick_xml::escape::unescape(resource.as_bytes()).map_err(|_| KeyStoreError {
+                resource: resource.into(),
+                error: Error::new(E_INVALIDARG, "invalid vault reference".into()),
+            })?;
+        String::from_utf8_lossy(&unescaped).into_owned()
+    } else {
+        resource.to_owned()
+    };
+
+    let key = store
+        .retrieve(&resource)
+        .map_err(|error| KeyStoreError { resource, error })?;
+
+    Ok(Cow::Owned(format!(
+        "{}{}{}",
+        &config[..start],
+        key,
+        &config[end..]
+    )))
+}
+
+/// Find where the `vault://` reference given as the config's `PrivateKey` starts and ends.
+///
+/// That's whichever reference directly follows a `<PrivateKey>` element, or a `PrivateKey` (or
+/// TOML `private_key`) key along with its `=` and any quotes.
+fn find_private_key_reference(config: &str) -> Option<(usize, usize)> {
+    let mut from = 0;
+    while let Some(offset) = config[from..].find(VAULT_PREFIX) {
+        let start = from + offset;
+        // The reference runs until the end of the XML element, TOML string or INI line
+        let end = config[start..]
+            .find(['<', '"', '\'', '\r', '\n'])
+            .map_or(config.len(), |len| start + len);
+
+        let name = config[..start]
+            .trim_end_matches(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '=' | '>'))
+            .to_ascii_lowercase();
+        if name.ends_with("privatekey") || name.ends_with("private_key") {
+            return Some((start, end));
+        }
+        from = end;
+    }
+    None
+}
+
+/// The config as XML with a reference to `resource` in place of its private key, for once the
+/// key has been stored there with `store_private_key`.
+pub fn with_key_reference(config: &WireGuardConfig, resource: &str) -> String {
+    let key = base64::encode(config.interface.private_key.as_bytes());
+    let reference = format!("{}{}", VAULT_PREFIX, resource);
+    let reference = quick_xml::escape::escape(reference.as_bytes());
+    config
+        .to_xml_string()
+        .replace(&key, &String::from_utf8_lossy(&reference))
+}
+
+/// Store `private_key` in `store` under `resource`, replacing any key already there.
+///
+/// Only do so once the profile referring to it is in place, lest a failed install clobber the
+/// key of a profile that's already using the name.
+pub fn store_private_key(
+    private_key: &X25519SecretKey,
+    resource: &str,
+    store: &dyn SecureKeyStore,
+) -> Result<()> {
+    store.store(resource, &base64::encode(private_key.as_bytes()))
+}
+
+#[cfg(all(test, feature = "std-only"))]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::config::test_fixtures::load_fixture;
+
+    const KEY: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+
+    /// A key store kept in memory.
+    #[derive(Default)]
+    struct FakeStore {
+        keys: RefCell<HashMap<String, String>>,
+    }
+
+    impl SecureKeyStore for FakeStore {
+        fn store(&self, resource: &str, key: &str) -> Result<()> {
+            self.keys
+                .borrow_mut()
+                .insert(resource.to_owned(), key.to_owned());
+            Ok(())
+        }
+
+        fn retrieve(&self, resource: &str) -> Result<String> {
+            // Failing would mean creating a `windows::core::Error`, which only works on Windows
+            Ok(self
+                .keys
+                .borrow()
+                .get(resource)
+                .unwrap_or_else(|| panic!("nothing stored under {:?}", resource))
+                .clone())
+        }
+    }
+
+    fn store_with(resource: &str) -> FakeStore {
+        let store = FakeStore::default();
+        store.store(resource, KEY).unwrap();
+        store
+    }
+
+    fn resolve(config: &str, store: &FakeStore) -> String {
+        resolve_references(config, store).unwrap().into_owned()
+    }
+
+    #[test]
+    fn xml_reference() {
+        let xml = load_fixture("valid_single_peer");
+        let with_reference = xml.replace(KEY, "vault://Office VPN");
+        assert_ne!(with_reference, xml);
+        assert_eq!(resolve(&with_reference, &store_with("Office VPN")), xml);
+    }
+
+    #[test]
+    fn escaped_xml_reference() {
+        let xml = load_fixture("valid_single_peer");
+        let with_reference = xml.replace(KEY, "vault://R&amp;D");
+        assert_eq!(resolve(&with_reference, &store_with("R&D")), xml);
+    }
+
+    #[test]
+    fn ini_and_toml_references() {
+        let store = store_with("Office VPN");
+        let ini = "[Interface]\nPrivateKey = vault://Office VPN  \r\nAddress = 10.0.0.2/32\n";
+        // Trailing whitespace isn't part of the resource, it goes along with the reference
+        assert_eq!(
+            resolve(ini, &store),
+            format!(
+                "[Interface]\nPrivateKey = {}\r\nAddress = 10.0.0.2/32\n",
+                KEY
+            )
+        );
+
+        let toml = "[interface]\nprivate_key = \"vault://Office VPN\"\n";
+        assert_eq!(
+            resolve(toml, &store),
+            format!("[interface]\nprivate_key = \"{}\"\n", KEY)
+        );
+    }
+
+    #[test]
+    fn only_private_key_is_resolved() {
+        let store = store_with("Office VPN");
+        let xml = load_fixture("valid_single_peer")
+            .replace(KEY, "vault://Office VPN")
+            .replace(
+                "</Peer>",
+                "<Description>see vault://Office VPN</Description></Peer>",
+            );
+        let resolved = resolve(&xml, &store);
+        assert!(resolved.contains(&format!("<PrivateKey>{}</PrivateKey>", KEY)));
+        assert!(resolved.contains("<Description>see vault://Office VPN</Description>"));
+
+        // Even when the other mention comes first
+        let ini = "# keys live in vault://Other\n[Interface]\nPrivateKey = vault://Office VPN\n";
+        let resolved = resolve(ini, &store);
+        assert!(resolved.starts_with("# keys live in vault://Other\n"));
+        assert!(resolved.ends_with(&format!("PrivateKey = {}\n", KEY)));
+    }
+
+    #[test]
+    fn config_without_reference() {
+        let xml = load_fixture("valid_single_peer");
+        let store = FakeStore::default();
+        assert!(matches!(
+            resolve_references(&xml, &store),
+            Ok(Cow::Borrowed(_))
+        ));
+    }
+
+    #[test]
+    fn key_reference_round_trips() {
+        let config = WireGuardConfig::from_str(&load_fixture("valid_single_peer")).unwrap();
+        let store = FakeStore::default();
+        store_private_key(&config.interface.private_key, "Office VPN", &store).unwrap();
+
+        let with_reference = with_key_reference(&config, "Office VPN");
+        assert!(!with_reference.contains(KEY));
+        assert!(with_reference.contains("<PrivateKey>vault://Office VPN</PrivateKey>"));
+        let resolved = WireGuardConfig::from_str(&resolve(&with_reference, &store)).unwrap();
+        assert!(resolved == config);
+    }
+}
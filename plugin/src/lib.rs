@@ -1,10 +1,27 @@
 //! This crate contains the `IVpnPlugIn` implementation for our UWP VPN plugin app.
+//!
+//! It's primarily built as a DLL loaded by the platform but the foreground app also links
+//! against it for the few bits it needs to share with the plugin.
 
 #![windows_subsystem = "windows"]
 #![allow(non_snake_case)] // Windows naming conventions
 
-mod background;
-mod config;
+#[cfg(feature = "std-only")]
+pub mod app_service;
+#[cfg(feature = "std-only")]
+pub mod background;
+pub mod config;
+#[cfg(feature = "std-only")]
+mod error;
+#[cfg(feature = "std-only")]
+pub mod history;
+#[cfg(feature = "std-only")]
+pub mod keystore;
 mod logging;
+#[cfg(feature = "std-only")]
+mod notification;
+#[cfg(feature = "std-only")]
+mod perf_counters;
+#[cfg(feature = "std-only")]
 mod plugin;
-mod utils;
+pub mod utils;
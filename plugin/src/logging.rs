@@ -6,12 +6,119 @@ use win_etw_macros::trace_logging_provider;
 #[allow(non_snake_case)]
 #[trace_logging_provider(guid = "c4522a55-401f-4b81-93f9-aa0d1db734c4")]
 pub trait WireGuardUWPEvents {
+    /// Event emitted for each attempt at connecting a peer's socket to a server.
+    #[event(level = "info")]
+    fn connect_attempt(host: &str, port: u16, attempt: u32);
+
     /// `Connect` event emitted once we've successfully connected
     #[event(level = "info")]
-    fn connected(remote_host: &str, remote_port: u16);
-    /// Event emitted if we've failed during `Connect`
+    fn connected(
+        remote_host: &str,
+        remote_port: u16,
+        local_port: u16,
+        desc: &str,
+        dns_servers: u32,
+    );
+    /// Event emitted if we've failed during `Connect`, along with the kind of error.
+    #[event(level = "error")]
+    fn connect_fail(code: u32, kind: &str, msg: &str);
+
+    /// Event emitted when we schedule another go at a failed `Connect`, `delay_ms` from now.
+    #[event(level = "warn")]
+    fn reconnect_attempt(attempt: u32, delay_ms: u64);
+
+    /// Event emitted when a socket operation for a peer fails.
+    /// The operation is one of `connect`, `send` or `bind`.
     #[event(level = "error")]
-    fn connect_fail(code: u32, msg: &str);
+    fn socket_error(peer_idx: u32, operation: &str, hresult: u32, msg: &str);
+
+    /// Event emitted during `Connect` with the address family used to reach each peer.
+    #[event(level = "info")]
+    fn address_family(remote_host: &str, remote_addr: &str, ipv6: bool);
+
+    /// Event emitted during `Connect` with the versions of the libraries we were built with.
+    #[event(level = "info")]
+    fn library_versions(boringtun: &str, windows_crate: &str, plugin: &str);
+
+    /// Event emitted during `Connect` for each route we assign, along with the peer it's for.
+    #[event(level = "verbose")]
+    fn route_assigned(peer_idx: u32, family: u8, network: &str, prefix: u8, is_exclusion: bool);
+
+    /// Event emitted during `Connect` with how many routes we're about to hand to the platform.
+    #[event(level = "info")]
+    fn routes_applied(ipv4_count: u32, ipv6_count: u32, excluded_count: u32);
+
+    /// Event emitted during `Connect` if we had to adjust some config value.
+    #[event(level = "warn")]
+    fn config_warning(msg: &str);
+
+    /// Event emitted during `Connect` for each config setting we accept but can't act on.
+    #[event(level = "warn")]
+    fn unsupported_config_field(field: &str);
+
+    /// Event emitted when a changed config was applied without reconnecting, listing what changed.
+    #[event(level = "info")]
+    fn config_reloaded(changes: &str);
+
+    /// Event emitted when our private key has been swapped out on a live tunnel.
+    #[event(level = "warn")]
+    fn private_key_rotated();
+
+    /// Event emitted when a peer's pre-shared key has been swapped out on a live tunnel.
+    #[event(level = "info")]
+    fn psk_rotated(peer_idx: u32);
+
+    /// Event emitted when the foreground app asked for fresh sessions with all the peers.
+    #[event(level = "info")]
+    fn reconnect_forced();
+
+    /// Event emitted when we answer a peer's handshake with a cookie reply instead, meaning
+    /// boringtun's DoS mitigation kicked in as we're under load.
+    #[event(level = "verbose")]
+    fn cookie_reply_sent(peer_idx: u32);
+
+    /// Event emitted when a session still in use got `age_secs` old without us starting a
+    /// handshake to replace it, which boringtun should have done by now.
+    #[event(level = "warn")]
+    fn missed_rekey_window(peer_idx: u32, age_secs: u64);
+
+    /// Event emitted when we reconnect because the network we were using went away.
+    #[event(level = "info")]
+    fn network_change_reconnect(old_profile: &str, new_profile: &str);
+
+    /// Event emitted for each peer with a `PersistentKeepalive` when battery saver turns on or
+    /// off, with the keepalive interval now in effect (0 if suspended).
+    #[event(level = "info")]
+    fn power_mode_change(saver_active: bool, new_keepalive_secs: u16);
+
+    /// Event emitted whenever the tunnel moves between `ConnectionState`s.
+    #[event(level = "info")]
+    fn state_change(old: u32, new: u32);
+
+    /// Event emitted whenever we send a peer a handshake initiation, including retries.
+    #[event(level = "info")]
+    fn handshake_start(peer_idx: u32);
+
+    /// Event emitted once a peer responds to our handshake, timed from the first initiation.
+    #[event(level = "info")]
+    fn handshake_complete(peer_idx: u32, duration_ms: u64);
+
+    /// Event emitted when boringtun gives up on a handshake with a peer.
+    #[event(level = "warn")]
+    fn handshake_timeout(peer_idx: u32, elapsed_ms: u64);
+
+    /// Event emitted when we start a handshake to replace a peer's aging session.
+    /// The reason is either `rekey_after_time` or `reject_after_time`.
+    #[event(level = "info")]
+    fn session_rekey(peer_idx: u32, reason: &str);
+
+    /// Event emitted when a peer's session expires without having been replaced.
+    #[event(level = "warn")]
+    fn session_expired(peer_idx: u32, age_ms: u64);
+
+    /// Event emitted when we give up on a peer after too many unanswered keepalives.
+    #[event(level = "warn")]
+    fn dead_peer_detected(peer_idx: u32, failures: u32);
 
     /// Event emitted for `Disconnect`
     #[event(level = "warn")]
@@ -24,21 +131,63 @@ pub trait WireGuardUWPEvents {
     #[event(level = "verbose")]
     fn encapsulate_begin(packets: u32);
     /// Packet encap end event.
-    /// Indicates how many frames we sent to the remote endpoint.
+    /// Indicates how many frames we sent to the remote endpoint and the total bytes sent so far.
+    #[event(level = "verbose")]
+    fn encapsulate_end(frames: u32, total_bytes_sent: u64);
+
+    /// Periodic summary of packets processed per `Encapsulate` call.
+    /// The average is scaled by 100 to avoid floating point.
+    #[event(level = "info")]
+    fn encap_batch_stats(min: u32, max: u32, avg_x100: u32);
+
+    /// Timer thread event, emitted each time we update a peer's timers.
     #[event(level = "verbose")]
-    fn encapsulate_end(frames: u32);
+    fn timer_tick(peer_idx: u32);
 
     /// Frame decap begin event.
     /// Indicates the size of the frame received from the remote endpoint.
     #[event(level = "verbose")]
     fn decapsulate_begin(frame_sz: u32);
     /// Frame decap end event.
-    /// Indicates how many packets were decapsulated and how many frames sent to the remote.
+    /// Indicates how many packets were decapsulated and how many frames sent to the remote,
+    /// along with the total bytes received so far.
     #[event(level = "verbose")]
-    fn decapsulate_end(packets: u32, control_frames: u32);
+    fn decapsulate_end(packets: u32, control_frames: u32, total_bytes_received: u64);
 
     /// KeepAlive packet event.
     /// Indicates how many bytes destined for remote.
     #[event(level = "info")]
     fn keepalive(packet_sz: u32);
+
+    // Region markers for profiling with WPA/xperf.
+    // These use the Start (1) & Stop (2) opcodes so that WPA can pair them up into
+    // Regions of Interest. See `wpa/regions.xml`.
+
+    /// Marks the start of a call to `Encapsulate`.
+    #[event(level = "verbose", opcode = 1)]
+    fn encapsulate_start();
+    /// Marks the end of a call to `Encapsulate`.
+    #[event(level = "verbose", opcode = 2)]
+    fn encapsulate_stop();
+
+    /// Marks the start of a call to `Decapsulate`.
+    #[event(level = "verbose", opcode = 1)]
+    fn decapsulate_start();
+    /// Marks the end of a call to `Decapsulate`.
+    #[event(level = "verbose", opcode = 2)]
+    fn decapsulate_stop();
+
+    /// Marks the start of a call into boringtun to encapsulate a single packet.
+    #[event(level = "verbose", opcode = 1)]
+    fn tunn_encapsulate_start();
+    /// Marks the end of a call into boringtun to encapsulate a single packet.
+    #[event(level = "verbose", opcode = 2)]
+    fn tunn_encapsulate_stop();
+
+    /// Marks the start of a call into boringtun to decapsulate a single frame.
+    #[event(level = "verbose", opcode = 1)]
+    fn tunn_decapsulate_start();
+    /// Marks the end of a call into boringtun to decapsulate a single frame.
+    #[event(level = "verbose", opcode = 2)]
+    fn tunn_decapsulate_stop();
 }
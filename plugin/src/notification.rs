@@ -0,0 +1,103 @@
+//! Toast notifications letting the user know when the tunnel comes up or goes down.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use windows::{
+    core::*,
+    Data::Xml::Dom::XmlDocument,
+    UI::Notifications::{ToastNotification, ToastNotificationManager},
+};
+
+use crate::utils::debug_log;
+
+/// The least time between two notifications, so a reconnect loop doesn't bury the user in them.
+const MIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A toast with a title line followed by a line of body text.
+const TOAST_XML: &str = r#"<toast>
+    <visual>
+        <binding template="ToastGeneric">
+            <text></text>
+            <text></text>
+        </binding>
+    </visual>
+</toast>"#;
+
+/// Shows (rate-limited) toasts for the tunnel's state changes.
+pub struct Notifier {
+    /// Set from the config's `Notifications` element
+    enabled: AtomicBool,
+    /// When we last showed a toast, if ever
+    last_shown: Mutex<Option<Instant>>,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(true),
+            last_shown: Mutex::new(None),
+        }
+    }
+
+    /// Turn notifications on or off.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Let the user know the tunnel to `server` (and `peers` peers in total) is up.
+    pub fn connected(&self, server: &str, peers: usize) {
+        let body = match peers {
+            1 => format!("Connected to {}.", server),
+            n => format!("Connected to {} and {} other peer(s).", server, n - 1),
+        };
+        self.notify("WireGuard VPN connected", &body);
+    }
+
+    /// Let the user know the tunnel went down, and why if we know.
+    pub fn disconnected(&self, reason: Option<&str>) {
+        let body = match reason {
+            Some(reason) => format!("Disconnected: {}", reason),
+            None => "Disconnected.".into(),
+        };
+        self.notify("WireGuard VPN disconnected", &body);
+    }
+
+    /// Show the toast unless disabled or we only just showed one.
+    fn notify(&self, title: &str, body: &str) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        {
+            let mut last_shown = self.last_shown.lock().unwrap();
+            if matches!(*last_shown, Some(last) if last.elapsed() < MIN_INTERVAL) {
+                return;
+            }
+            *last_shown = Some(Instant::now());
+        }
+
+        // Failing to notify the user is no reason to fail whatever we were doing
+        if let Err(err) = show_toast(title, body) {
+            debug_log!("Failed to show notification: {}", err);
+        }
+    }
+}
+
+/// Build the toast payload and hand it off to the system.
+fn show_toast(title: &str, body: &str) -> Result<()> {
+    let doc = XmlDocument::new()?;
+    doc.LoadXml(TOAST_XML)?;
+
+    // Fill in the text as nodes rather than formatting it into the XML so it needn't be escaped
+    let texts = doc.GetElementsByTagName("text")?;
+    for (i, text) in [title, body].into_iter().enumerate() {
+        texts
+            .Item(i as u32)?
+            .AppendChild(doc.CreateTextNode(text)?)?;
+    }
+
+    let toast = ToastNotification::CreateToastNotification(doc)?;
+    ToastNotificationManager::CreateToastNotifier()?.Show(toast)
+}
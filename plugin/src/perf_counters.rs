@@ -0,0 +1,178 @@
+//! Windows performance counters exposing the tunnel's traffic stats to `typeperf`, PerfMon & co.
+//!
+//! The counter set is described by `perf/counters.man` which has to be registered (once, as an
+//! administrator) with `lodctr` before anything can read the counters. Until then we still
+//! publish them, there's just no one to see them.
+
+use std::mem;
+
+use windows::{
+    core::*,
+    Win32::Foundation::{HANDLE, WIN32_ERROR},
+    Win32::System::Performance::{
+        PerfCreateInstance, PerfDeleteInstance, PerfProviderHandle, PerfSetCounterSetInfo,
+        PerfSetULongCounterValue, PerfSetULongLongCounterValue, PerfStartProvider,
+        PerfStopProvider, PERF_COUNTERSET_INFO, PERF_COUNTERSET_INSTANCE,
+        PERF_COUNTERSET_SINGLE_INSTANCE, PERF_COUNTER_INFO, PERF_DETAIL_NOVICE,
+    },
+};
+
+/// Must match the `providerGuid` in `perf/counters.man`.
+const PROVIDER_GUID: GUID = GUID::from_u128(0x6f1c3b0e_5a2d_4c8e_9b7a_3e4d2f1a8c55);
+
+/// Must match the `counterSet` `guid` in `perf/counters.man`.
+const COUNTERSET_GUID: GUID = GUID::from_u128(0x0d8e5f7a_2b4c_4e1d_8a6f_9c3b1e7d5a20);
+
+// Counter types from `winperf.h`, which the windows crate doesn't carry
+const PERF_COUNTER_BULK_COUNT: u32 = 0x10410500;
+const PERF_COUNTER_RAWCOUNT: u32 = 0x00010000;
+const PERF_COUNTER_LARGE_RAWCOUNT: u32 = 0x00010100;
+
+// Counter IDs, must match the `id`s in `perf/counters.man`
+const BYTES_SENT: u32 = 1;
+const BYTES_RECEIVED: u32 = 2;
+const PACKETS_ENCAPSULATED: u32 = 3;
+const PACKETS_DECAPSULATED: u32 = 4;
+const ACTIVE_SESSIONS: u32 = 5;
+const HANDSHAKES: u32 = 6;
+
+const NUM_COUNTERS: usize = 6;
+
+/// The layout `PerfSetCounterSetInfo` expects: the counter set followed by each of its counters.
+#[repr(C)]
+struct CounterSetTemplate {
+    info: PERF_COUNTERSET_INFO,
+    counters: [PERF_COUNTER_INFO; NUM_COUNTERS],
+}
+
+/// Describe a counter living at `offset` in the instance's data block.
+fn counter(id: u32, ty: u32, size: u32, offset: u32) -> PERF_COUNTER_INFO {
+    PERF_COUNTER_INFO {
+        CounterId: id,
+        Type: ty,
+        Attrib: 0,
+        Size: size,
+        DetailLevel: PERF_DETAIL_NOVICE.0,
+        Scale: 0,
+        Offset: offset,
+    }
+}
+
+/// Map the Win32 error code the `Perf*` functions return to a `Result`.
+fn check(code: u32) -> Result<()> {
+    match code {
+        0 => Ok(()),
+        code => Err(HRESULT::from(WIN32_ERROR(code)).into()),
+    }
+}
+
+/// The values to publish, straight from the running totals we already keep.
+///
+/// The byte & packet counts are cumulative, whoever reads the counters turns them into rates.
+#[derive(Default)]
+pub struct PerfSample {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_encapsulated: u64,
+    pub packets_decapsulated: u64,
+    pub active_sessions: u32,
+    pub handshakes: u64,
+}
+
+/// Our registered counter provider along with the single instance of the counter set.
+pub struct PerfCounters {
+    provider: PerfProviderHandle,
+    instance: *mut PERF_COUNTERSET_INSTANCE,
+}
+
+// SAFETY: The instance block belongs to perflib and the `PerfSet*` functions are thread-safe.
+unsafe impl Send for PerfCounters {}
+unsafe impl Sync for PerfCounters {}
+
+impl PerfCounters {
+    /// Register our provider and create the counter set instance we'll publish to.
+    pub fn new() -> Result<Self> {
+        let mut provider = PerfProviderHandle::default();
+        // SAFETY: `provider` is valid for writes and we don't need a control callback.
+        check(unsafe { PerfStartProvider(&PROVIDER_GUID, None, &mut provider) })?;
+
+        let mut template = CounterSetTemplate {
+            info: PERF_COUNTERSET_INFO {
+                CounterSetGuid: COUNTERSET_GUID,
+                ProviderGuid: PROVIDER_GUID,
+                NumCounters: NUM_COUNTERS as u32,
+                InstanceType: PERF_COUNTERSET_SINGLE_INSTANCE,
+            },
+            counters: [
+                counter(BYTES_SENT, PERF_COUNTER_BULK_COUNT, 8, 0),
+                counter(BYTES_RECEIVED, PERF_COUNTER_BULK_COUNT, 8, 8),
+                counter(PACKETS_ENCAPSULATED, PERF_COUNTER_BULK_COUNT, 8, 16),
+                counter(PACKETS_DECAPSULATED, PERF_COUNTER_BULK_COUNT, 8, 24),
+                counter(HANDSHAKES, PERF_COUNTER_LARGE_RAWCOUNT, 8, 32),
+                counter(ACTIVE_SESSIONS, PERF_COUNTER_RAWCOUNT, 4, 40),
+            ],
+        };
+
+        // SAFETY: `template` is laid out just like perflib expects and outlives the call.
+        let instance = unsafe {
+            check(PerfSetCounterSetInfo(
+                HANDLE(provider.0),
+                &mut template.info,
+                mem::size_of::<CounterSetTemplate>() as u32,
+            ))
+            .and_then(|_| {
+                let instance = PerfCreateInstance(provider, &COUNTERSET_GUID, "WireGuard", 0);
+                if instance.is_null() {
+                    Err(Error::from_win32())
+                } else {
+                    Ok(instance)
+                }
+            })
+        };
+
+        match instance {
+            Ok(instance) => Ok(Self { provider, instance }),
+            Err(err) => {
+                // SAFETY: We started the provider above and nothing else has a hold of it.
+                unsafe { PerfStopProvider(provider) };
+                Err(err)
+            }
+        }
+    }
+
+    /// Publish a new set of values.
+    pub fn update(&self, sample: &PerfSample) {
+        let provider = HANDLE(self.provider.0);
+        let large = [
+            (BYTES_SENT, sample.bytes_sent),
+            (BYTES_RECEIVED, sample.bytes_received),
+            (PACKETS_ENCAPSULATED, sample.packets_encapsulated),
+            (PACKETS_DECAPSULATED, sample.packets_decapsulated),
+            (HANDSHAKES, sample.handshakes),
+        ];
+
+        // Nothing useful to do if one of these fails, the counter just goes stale
+        // SAFETY: `instance` stays valid until we delete it on drop.
+        unsafe {
+            for (id, value) in large {
+                PerfSetULongLongCounterValue(provider, self.instance, id, value);
+            }
+            PerfSetULongCounterValue(
+                provider,
+                self.instance,
+                ACTIVE_SESSIONS,
+                sample.active_sessions,
+            );
+        }
+    }
+}
+
+impl Drop for PerfCounters {
+    fn drop(&mut self) {
+        // SAFETY: Both were created in `new` and aren't used past this point.
+        unsafe {
+            PerfDeleteInstance(self.provider, self.instance);
+            PerfStopProvider(self.provider);
+        }
+    }
+}
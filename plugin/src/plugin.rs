@@ -1,89 +1,986 @@
 //! Our implementation of `IVpnPlugIn` which is the bulk of the UWP VPN plugin.
 
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
-
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use boringtun::crypto::x25519::{X25519PublicKey, X25519SecretKey};
+use boringtun::noise::errors::WireGuardError;
+use boringtun::noise::rate_limiter::RateLimiter;
 use boringtun::noise::{Tunn, TunnResult};
 use ipnetwork::IpNetwork;
+use win_etw_provider::EventOptions;
 use windows::{
     self as Windows,
     core::*,
+    ApplicationModel::Core::CoreApplication,
+    Foundation::Collections::{IIterable, IVector},
+    Foundation::{
+        AsyncOperationCompletedHandler, AsyncStatus, EventHandler, PropertyValue, TimeSpan,
+    },
+    Networking::Connectivity::{ConnectionProfile, NetworkConnectivityLevel, NetworkInformation},
     Networking::Sockets::*,
     Networking::Vpn::*,
     Networking::*,
-    Win32::Foundation::{E_BOUNDS, E_INVALIDARG, E_UNEXPECTED},
+    Storage::Streams::DataWriter,
+    System::Power::{EnergySaverStatus, PowerManager},
+    System::Threading::{ThreadPoolTimer, TimerElapsedHandler},
+    Win32::Foundation::{E_BOUNDS, E_UNEXPECTED},
+    Win32::System::Com::CoCreateGuid,
 };
 
-use crate::config::WireGuardConfig;
+use crate::app_service;
+use crate::config::{
+    copy_private_key, ConfigDelta, PeerConfig, ReconnectPolicy, TableConfig, WireGuardConfig,
+};
+use crate::error::PluginError;
+use crate::history::{ConnectionHistory, HistoryEntry, HistoryEvent};
+use crate::keystore::{self, PasswordVaultStore};
 use crate::logging::WireGuardUWPEvents;
-use crate::utils::{debug_log, IBufferExt, Vector};
+use crate::notification::Notifier;
+use crate::perf_counters::{PerfCounters, PerfSample};
+use crate::utils::handshake_debug::{self, parse_receiver_index};
+use crate::utils::radix_trie::NetworkPrefixMatcher;
+use crate::utils::{
+    debug_log, hresult_to_net_error, packet_dst_addr, packet_src_addr, tcp_mss_clamp, to_datetime,
+    BufferPool, IBufferExt, Vector,
+};
+
+/// How long `Disconnect` will wait for the final keepalive to go out before stopping the channel.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long we'll wait for a handshake initiation we send out ourselves to go out.
+const HANDSHAKE_SEND_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How many send & receive buffers we pre-allocate on `Connect`.
+const BUFFER_POOL_SIZE: usize = 64;
+
+/// The size of a WireGuard handshake initiation message.
+const HANDSHAKE_INIT_SZ: usize = 148;
+
+/// The MTU of the VPN tunnel interface if none was configured.
+/// Same as the usual WireGuard default which leaves room for our overhead on a 1500 byte link.
+const DEFAULT_MTU: u16 = 1420;
+
+/// The MTU of the underlying network we assume when checking a configured MTU.
+const PHYSICAL_MTU: usize = 1500;
+
+/// The max number of characters of a peer's description we include in ETW events.
+const MAX_EVENT_DESC_LEN: usize = 128;
+
+/// How often the timer thread drives each tunnel's timers.
+const TIMER_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often the timer thread publishes our stats to the performance counters.
+const PERF_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The platform only lets us pass a main and an optional secondary transport to `Start`.
+const MAX_TRANSPORTS: usize = 2;
+
+/// How old a session gets before it can no longer be used (`Reject-After-Time`).
+const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
+
+/// How old a session in use gets before we warn that boringtun never started a handshake to
+/// replace it, unless the config says otherwise. It should have by `Reject-After-Time` less a
+/// keepalive & rekey timeout, i.e. 165 seconds.
+const DEFAULT_REKEY_WINDOW: Duration = Duration::from_secs(170);
+
+/// The app property under which we publish when the current connection came up, as a `DateTime`.
+pub(crate) const CONNECTED_AT_PROPERTY: &str = "connected_at";
+
+/// How often we log a summary of the `Encapsulate` batch sizes.
+const ENCAP_STATS_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Battery saver suspends keepalives altogether for peers with a `PersistentKeepalive` (in
+/// seconds) below this.
+const SAVER_SUSPEND_KEEPALIVE_BELOW: u16 = 15;
+
+/// How much battery saver stretches out any other peers' keepalive interval by.
+const SAVER_KEEPALIVE_FACTOR: u16 = 4;
+
+/// The furthest battery saver stretches a keepalive interval, to keep the NAT mapping alive.
+const SAVER_MAX_KEEPALIVE: u16 = 60;
+
+/// The least time between reconnects caused by the network changing, so a flappy network
+/// doesn't have us constantly tearing down the tunnel.
+const NETWORK_RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The overall state of the VPN tunnel.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Idle,
+    Connecting,
+    Connected,
+    Disconnecting,
+}
+
+impl ConnectionState {
+    /// The name we use for the state when talking to the foreground app.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConnectionState::Idle => "idle",
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Disconnecting => "disconnecting",
+        }
+    }
+}
+
+impl FromStr for ConnectionState {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "idle" => ConnectionState::Idle,
+            "connecting" => ConnectionState::Connecting,
+            "connected" => ConnectionState::Connected,
+            "disconnecting" => ConnectionState::Disconnecting,
+            _ => return Err(format!("unknown connection state: {}", s)),
+        })
+    }
+}
+
+/// A snapshot of the tunnel's state and counters, e.g. for display by the foreground app.
+#[derive(Clone, Debug)]
+pub struct TunnelStats {
+    pub state: ConnectionState,
+    /// How long the tunnel has been up, if it is
+    pub connected_for: Option<Duration>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// How long ago we last completed a handshake with any of the peers, if ever
+    pub last_handshake: Option<Duration>,
+}
+
+/// A snapshot of a single peer's state, e.g. for display by the foreground app.
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    /// The peer's remote endpoint, as `host:port`
+    pub endpoint: String,
+    /// The peer's configured description, if any
+    pub description: Option<String>,
+    /// How long ago we last completed a handshake with the peer, if ever
+    pub last_handshake: Option<Duration>,
+}
+
+/// Per-peer tunnel state.
+struct PeerState {
+    /// The WireGuard tunnel to the peer
+    tunn: Box<Tunn>,
+    /// The peer's remote endpoint
+    endpoint: HostName,
+    /// The port the peer is listening on
+    port: u16,
+    /// The peer's configured description, if any
+    description: Option<String>,
+    /// The peer's public key
+    public_key: Arc<X25519PublicKey>,
+    /// The pre-shared key with the peer, if any
+    preshared_key: Option<[u8; 32]>,
+    /// The interval at which to send keepalives to the peer, if any
+    persistent_keepalive: Option<u16>,
+    /// When we sent the first initiation of the handshake currently in flight, if any
+    handshake_started: Mutex<Option<Instant>>,
+    /// What the timer thread has seen of the peer's sessions
+    timers: Mutex<TimerState>,
+    /// How many keepalives the timer thread has sent since we last heard from the peer
+    keepalive_failures: AtomicU32,
+}
+
+/// Keeps track of a peer's sessions to check boringtun replaces them before they get too old.
+struct TimerState {
+    /// When the current session was established, as best we can tell
+    session_start: Instant,
+    /// When we last sent the peer a handshake initiation
+    last_rekey: Instant,
+    /// `Inner::packets_received` as of `session_start`, to tell whether the session is in use
+    packets_at_start: u64,
+    /// Whether we already warned about the current session
+    warned: bool,
+}
+
+impl TimerState {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            session_start: now,
+            last_rekey: now,
+            packets_at_start: 0,
+            warned: false,
+        }
+    }
+}
+
+impl PeerState {
+    /// Take note of a message about to go out to the peer, logging any handshake initiation.
+    fn note_outgoing(&self, idx: usize, packet: &[u8], etw_logger: &WireGuardUWPEvents) {
+        if packet.first() != Some(&handshake_debug::HANDSHAKE_INIT) {
+            return;
+        }
+        self.timers.lock().unwrap().last_rekey = Instant::now();
+        // Retries are part of the same handshake as far as timing it goes
+        let mut handshake_started = self.handshake_started.lock().unwrap();
+        if handshake_started.is_none() {
+            // If we already have a session then this handshake is to replace it
+            if let Some(age) = self.tunn.time_since_last_handshake() {
+                let reason = if age >= REJECT_AFTER_TIME {
+                    "reject_after_time"
+                } else {
+                    "rekey_after_time"
+                };
+                etw_logger.session_rekey(None, idx as u32, reason);
+            }
+            *handshake_started = Some(Instant::now());
+        }
+        etw_logger.handshake_start(None, idx as u32);
+    }
+
+    /// Take note of a handshake response from the peer, completing our handshake.
+    fn note_handshake_complete(&self, idx: usize, etw_logger: &WireGuardUWPEvents) {
+        if let Some(started) = self.handshake_started.lock().unwrap().take() {
+            etw_logger.handshake_complete(None, idx as u32, started.elapsed().as_millis() as u64);
+        }
+    }
+
+    /// Warn if the current session got older than `window` without us starting a handshake to
+    /// replace it.
+    ///
+    /// boringtun should do so well before then for any session still in use, so this is a
+    /// watchdog for its timers misbehaving, e.g. with how `Instant` behaves on Windows.
+    fn check_rekey_window(
+        &self,
+        idx: usize,
+        window: Duration,
+        packets_received: u64,
+        etw_opts: &EventOptions,
+        etw_logger: &WireGuardUWPEvents,
+    ) {
+        let age = match self.tunn.time_since_last_handshake() {
+            Some(age) => age,
+            None => return,
+        };
+        let now = Instant::now();
+        let session_start = now.checked_sub(age).unwrap_or(now);
+
+        let mut timers = self.timers.lock().unwrap();
+        // Working it out from the age jitters a little, so only a clear jump is a new session
+        if session_start > timers.session_start + Duration::from_secs(1) {
+            timers.session_start = session_start;
+            timers.packets_at_start = packets_received;
+            timers.warned = false;
+        }
+
+        if age >= window
+            && !timers.warned
+            && timers.last_rekey < timers.session_start
+            && packets_received > timers.packets_at_start
+        {
+            etw_logger.missed_rekey_window(Some(etw_opts), idx as u32, age.as_secs());
+            timers.warned = true;
+        }
+    }
+
+    /// Take note of an error from `update_timers`, logging if it means the handshake timed out.
+    fn note_timers_error(&self, idx: usize, err: &WireGuardError, etw_logger: &WireGuardUWPEvents) {
+        if !matches!(err, WireGuardError::ConnectionExpired) {
+            return;
+        }
+        if let Some(age) = self.tunn.time_since_last_handshake() {
+            etw_logger.session_expired(None, idx as u32, age.as_millis() as u64);
+        }
+        if let Some(started) = self.handshake_started.lock().unwrap().take() {
+            etw_logger.handshake_timeout(None, idx as u32, started.elapsed().as_millis() as u64);
+        }
+    }
+}
+
+/// Running stats on the number of packets processed per call to `Encapsulate`.
+struct EncapBatchStats {
+    min_batch: AtomicU32,
+    max_batch: AtomicU32,
+    total_calls: AtomicU64,
+    total_packets: AtomicU64,
+    /// When we last logged the stats
+    last_report: Mutex<Instant>,
+}
+
+/// A point in time copy of `EncapBatchStats`.
+struct EncapBatchSnapshot {
+    min_batch: u32,
+    max_batch: u32,
+    total_calls: u64,
+    total_packets: u64,
+}
+
+impl EncapBatchStats {
+    fn new() -> Self {
+        Self {
+            min_batch: AtomicU32::new(u32::MAX),
+            max_batch: AtomicU32::new(0),
+            total_calls: AtomicU64::new(0),
+            total_packets: AtomicU64::new(0),
+            last_report: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Account for a call to `Encapsulate` that processed `batch` packets.
+    fn record(&self, batch: u32) {
+        self.min_batch.fetch_min(batch, Ordering::Relaxed);
+        self.max_batch.fetch_max(batch, Ordering::Relaxed);
+        self.total_calls.fetch_add(1, Ordering::Relaxed);
+        self.total_packets
+            .fetch_add(u64::from(batch), Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> EncapBatchSnapshot {
+        EncapBatchSnapshot {
+            min_batch: self.min_batch.load(Ordering::Relaxed),
+            max_batch: self.max_batch.load(Ordering::Relaxed),
+            total_calls: self.total_calls.load(Ordering::Relaxed),
+            total_packets: self.total_packets.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns a snapshot if it's been at least `ENCAP_STATS_INTERVAL` since we last did so.
+    fn snapshot_if_due(&self) -> Option<EncapBatchSnapshot> {
+        let mut last_report = self.last_report.lock().unwrap();
+        if last_report.elapsed() < ENCAP_STATS_INTERVAL {
+            return None;
+        }
+        *last_report = Instant::now();
+        Some(self.snapshot())
+    }
+}
+
+impl EncapBatchSnapshot {
+    /// The average number of packets per call, multiplied by 100.
+    fn avg_x100(&self) -> u32 {
+        let avg = (self.total_packets * 100)
+            .checked_div(self.total_calls)
+            .unwrap_or(0);
+        u32::try_from(avg).unwrap_or(u32::MAX)
+    }
+}
+
+/// The network connection the tunnel is running over, as far as the system is concerned.
+#[derive(Clone)]
+struct UnderlyingNetwork {
+    /// The connection profile's name, e.g. the Wi-Fi network's SSID
+    name: String,
+    /// The network adapter the connection goes through
+    adapter_id: GUID,
+}
+
+impl UnderlyingNetwork {
+    /// The connection currently providing internet access, if any.
+    fn current() -> Option<Self> {
+        let profile = NetworkInformation::GetInternetConnectionProfile().ok()?;
+        Self::from_profile(&profile).ok()
+    }
+
+    fn from_profile(profile: &ConnectionProfile) -> Result<Self> {
+        Ok(Self {
+            name: profile.ProfileName()?.to_string(),
+            adapter_id: profile.NetworkAdapter()?.NetworkAdapterId()?,
+        })
+    }
+
+    /// Whether the connection is still up, even if the system now prefers another one.
+    ///
+    /// Only once it's gone do our sockets go with it, and checking for that rather than any
+    /// change in the preferred connection keeps the tunnel itself from counting as a change.
+    fn is_connected(&self) -> bool {
+        let profiles = match NetworkInformation::GetConnectionProfiles() {
+            Ok(profiles) => profiles,
+            // Can't tell, so assume nothing changed
+            Err(_) => return true,
+        };
+        profiles.into_iter().any(|profile| {
+            let adapter_id = profile.NetworkAdapter().and_then(|a| a.NetworkAdapterId());
+            let level = profile.GetNetworkConnectivityLevel();
+            adapter_id == Ok(self.adapter_id)
+                && level.is_ok_and(|level| level != NetworkConnectivityLevel::None)
+        })
+    }
+}
 
 struct Inner {
-    tunn: Option<Box<Tunn>>,
+    /// Each peer along with the socket connected to it.
+    /// A peer's position here doubles as the transport affinity of its socket.
+    peers: Vec<(DatagramSocket, PeerState)>,
+    /// Maps AllowedIPs to the index of the peer they should be routed to
+    router: NetworkPrefixMatcher,
+    /// Maps the receiver index of each peer's latest established session to the index of the peer
+    sessions: RwLock<HashMap<u32, usize>>,
+    /// The channel we were last connected with
+    channel: Option<VpnChannel>,
+    /// Stats on how many packets we actually process per call to `Encapsulate`
+    encap_stats: EncapBatchStats,
+    /// Whether everything is routed through the tunnel, see `InterfaceConfig::kill_switch`
+    kill_switch: bool,
+    /// The largest TCP MSS we let through in either direction, if we're clamping it
+    max_mss: Option<u16>,
+    /// Disconnect once any peer's `keepalive_failures` reaches this, unless it's 0
+    dead_peer_threshold: u32,
+    /// Warn once a session in use gets this old without a new handshake, unless it's 0
+    rekey_window: Duration,
+    /// The configured handshake rate limit, if any
+    rate_limit: Option<u32>,
+    /// The handshake rate limiter shared by all the peers' tunnels
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Total size of the encapsulated data frames we've sent
+    bytes_sent: AtomicU64,
+    /// Total size of the packets we've decapsulated
+    bytes_received: AtomicU64,
+    /// Total number of packets we've decapsulated
+    packets_received: AtomicU64,
+    /// Total number of handshakes we've completed with any of the peers
+    handshakes: AtomicU64,
+    /// Set to let the timer thread for this connection know it should exit
+    timer_stop: Arc<AtomicBool>,
+    /// Pre-allocated buffers for frames sent to the remote endpoints
+    send_buffers: BufferPool,
+    /// Pre-allocated buffers for packets injected into the VPN interface
+    recv_buffers: BufferPool,
+    /// Ties together all the ETW events for the current connection
+    activity_id: Option<win_etw_provider::GUID>,
+    /// When the current connection finished coming up, if it has
+    connected_at: Option<Instant>,
+    /// The config the current connection is running with, to diff any new one against
+    config: Option<WireGuardConfig>,
+    /// Our private key, shared by all the peers' tunnels
+    private_key: Option<Arc<X25519SecretKey>>,
+    /// The network the current connection came up on, if we could tell
+    network: Option<UnderlyingNetwork>,
 }
 
 impl Inner {
     fn new() -> Self {
-        Self { tunn: None }
+        Self {
+            peers: vec![],
+            router: NetworkPrefixMatcher::new(),
+            sessions: RwLock::new(HashMap::new()),
+            channel: None,
+            encap_stats: EncapBatchStats::new(),
+            kill_switch: false,
+            max_mss: None,
+            dead_peer_threshold: 0,
+            rekey_window: DEFAULT_REKEY_WINDOW,
+            rate_limit: None,
+            rate_limiter: None,
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            packets_received: AtomicU64::new(0),
+            handshakes: AtomicU64::new(0),
+            timer_stop: Arc::new(AtomicBool::new(true)),
+            send_buffers: BufferPool::new(BUFFER_POOL_SIZE),
+            recv_buffers: BufferPool::new(BUFFER_POOL_SIZE),
+            activity_id: None,
+            connected_at: None,
+            config: None,
+            private_key: None,
+            network: None,
+        }
+    }
+
+    /// The options to log ETW events under the current connection's activity with.
+    fn etw_options(&self) -> EventOptions {
+        EventOptions {
+            activity_id: self.activity_id.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// The current values for the performance counters.
+    fn perf_sample(&self) -> PerfSample {
+        PerfSample {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            packets_encapsulated: self.encap_stats.total_packets.load(Ordering::Relaxed),
+            packets_decapsulated: self.packets_received.load(Ordering::Relaxed),
+            active_sessions: self.sessions.read().unwrap().len() as u32,
+            handshakes: self.handshakes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The index of the peer whose `AllowedIPs` best match the destination of an outgoing packet.
+    fn peer_for_packet(&self, packet: &[u8]) -> Option<u32> {
+        packet_dst_addr(packet).and_then(|dst| self.router.longest_match(dst))
+    }
+
+    /// Look up the peer for an established session by the receiver index of an incoming frame.
+    fn peer_by_receiver_index(&self, idx: u32) -> Option<usize> {
+        self.sessions.read().unwrap().get(&idx).copied()
+    }
+
+    /// Remember that the session with receiver index `idx` belongs to the peer at `peer_idx`.
+    ///
+    /// Only a peer's latest session is kept track of, otherwise every rekey would leave another
+    /// index behind for as long as the tunnel is up. Frames for an older session the peer is
+    /// still using just fall back to the socket they came in on.
+    fn note_session(&self, idx: u32, peer_idx: usize) {
+        if self.peer_by_receiver_index(idx) == Some(peer_idx) {
+            return;
+        }
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.retain(|_, session_peer| *session_peer != peer_idx);
+        sessions.insert(idx, peer_idx);
+    }
+}
+
+/// Builds up a fresh `Inner` for a new connection, making sure nothing is left unset.
+#[derive(Default)]
+struct InnerBuilder {
+    peers: Option<Vec<(DatagramSocket, PeerState)>>,
+    router: Option<NetworkPrefixMatcher>,
+    channel: Option<VpnChannel>,
+    kill_switch: bool,
+    max_mss: Option<u16>,
+    dead_peer_threshold: Option<u32>,
+    rekey_window: Option<u32>,
+    rate_limit: Option<(u32, Arc<RateLimiter>)>,
+    activity_id: Option<win_etw_provider::GUID>,
+    config: Option<WireGuardConfig>,
+    private_key: Option<Arc<X25519SecretKey>>,
+    network: Option<UnderlyingNetwork>,
+}
+
+impl InnerBuilder {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn peers(mut self, peers: Vec<(DatagramSocket, PeerState)>) -> Self {
+        self.peers = Some(peers);
+        self
+    }
+
+    fn router(mut self, router: NetworkPrefixMatcher) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    fn channel(mut self, channel: VpnChannel) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    /// Off if unset.
+    fn kill_switch(mut self, kill_switch: bool) -> Self {
+        self.kill_switch = kill_switch;
+        self
+    }
+
+    /// TCP MSS clamping is disabled if unset.
+    fn max_mss(mut self, max_mss: Option<u16>) -> Self {
+        self.max_mss = max_mss;
+        self
+    }
+
+    /// Dead peer detection is disabled if unset.
+    fn dead_peer_threshold(mut self, dead_peer_threshold: Option<u32>) -> Self {
+        self.dead_peer_threshold = dead_peer_threshold;
+        self
+    }
+
+    /// In seconds, `DEFAULT_REKEY_WINDOW` is used if unset.
+    fn rekey_window(mut self, rekey_window: Option<u32>) -> Self {
+        self.rekey_window = rekey_window;
+        self
+    }
+
+    /// The rate limit along with the limiter enforcing it, if any.
+    fn rate_limit(mut self, rate_limit: Option<(u32, Arc<RateLimiter>)>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// The activity to log the connection's ETW events under, if any.
+    fn activity_id(mut self, activity_id: Option<win_etw_provider::GUID>) -> Self {
+        self.activity_id = activity_id;
+        self
+    }
+
+    fn config(mut self, config: WireGuardConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    fn private_key(mut self, private_key: Arc<X25519SecretKey>) -> Self {
+        self.private_key = Some(private_key);
+        self
+    }
+
+    /// The network we're connecting over, if we could tell.
+    fn network(mut self, network: Option<UnderlyingNetwork>) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Returns an error if any of the required fields weren't set.
+    fn build(self) -> Result<Inner> {
+        let missing = |field: &str| Error::new(E_UNEXPECTED, format!("missing {}", field).into());
+        Ok(Inner {
+            peers: self.peers.ok_or_else(|| missing("peers"))?,
+            router: self.router.ok_or_else(|| missing("router"))?,
+            sessions: RwLock::new(HashMap::new()),
+            channel: Some(self.channel.ok_or_else(|| missing("channel"))?),
+            encap_stats: EncapBatchStats::new(),
+            kill_switch: self.kill_switch,
+            max_mss: self.max_mss,
+            dead_peer_threshold: self.dead_peer_threshold.unwrap_or(0),
+            rekey_window: self.rekey_window.map_or(DEFAULT_REKEY_WINDOW, |secs| {
+                Duration::from_secs(secs.into())
+            }),
+            rate_limit: self.rate_limit.as_ref().map(|(limit, _)| *limit),
+            rate_limiter: self.rate_limit.map(|(_, limiter)| limiter),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            packets_received: AtomicU64::new(0),
+            handshakes: AtomicU64::new(0),
+            timer_stop: Arc::new(AtomicBool::new(false)),
+            send_buffers: BufferPool::new(BUFFER_POOL_SIZE),
+            recv_buffers: BufferPool::new(BUFFER_POOL_SIZE),
+            activity_id: self.activity_id,
+            connected_at: None,
+            config: Some(self.config.ok_or_else(|| missing("config"))?),
+            private_key: Some(self.private_key.ok_or_else(|| missing("private_key"))?),
+            network: self.network,
+        })
     }
 }
 
 /// The VPN plugin object which provides the hooks that the UWP VPN platform will call into.
 #[implement(Windows::Networking::Vpn::IVpnPlugIn)]
 pub struct VpnPlugin {
-    inner: RwLock<Inner>,
-    etw_logger: WireGuardUWPEvents,
+    /// Shared with the timer thread
+    inner: Arc<RwLock<Inner>>,
+    /// Kept outside of `inner` so that it can be checked while we're still connecting
+    state: Arc<Mutex<ConnectionState>>,
+    /// The retry policy from the last config we parsed, if any
+    reconnect: Mutex<Option<ReconnectPolicy>>,
+    /// How many retries of a failed `Connect` we've scheduled in a row
+    reconnect_attempts: AtomicU32,
+    etw_logger: Arc<WireGuardUWPEvents>,
+    /// Shared with any `CancelHandle`s
+    notifier: Arc<Notifier>,
+    /// Shared with the timer thread, unless we failed to register them
+    perf_counters: Option<Arc<PerfCounters>>,
+    /// When we last reconnected because the network changed
+    last_network_reconnect: Mutex<Option<Instant>>,
 }
 
+// The platform is free to call into the plugin from any number of threads concurrently
+// so make sure everything we hold (including the ETW logger) is safe to share.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<VpnPlugin>();
+};
+
 impl VpnPlugin {
     pub fn new() -> Self {
         Self {
-            inner: RwLock::new(Inner::new()),
-            etw_logger: WireGuardUWPEvents::new(),
+            inner: Arc::new(RwLock::new(Inner::new())),
+            state: Arc::new(Mutex::new(ConnectionState::Idle)),
+            reconnect: Mutex::new(None),
+            reconnect_attempts: AtomicU32::new(0),
+            etw_logger: Arc::new(WireGuardUWPEvents::new()),
+            notifier: Arc::new(Notifier::new()),
+            perf_counters: match PerfCounters::new() {
+                Ok(counters) => Some(Arc::new(counters)),
+                Err(err) => {
+                    debug_log!("Failed to register performance counters: {}", err);
+                    None
+                }
+            },
+            last_network_reconnect: Mutex::new(None),
+        }
+    }
+
+    /// The version of boringtun we were built against.
+    pub fn boringtun_version() -> &'static str {
+        env!("BORINGTUN_VERSION")
+    }
+
+    /// The current state of the VPN tunnel.
+    pub fn state(&self) -> ConnectionState {
+        *self.state.lock().unwrap()
+    }
+
+    /// The total bytes sent and received over the current connection.
+    pub fn stats(&self) -> (u64, u64) {
+        let inner = self.inner.read().unwrap();
+        (
+            inner.bytes_sent.load(Ordering::Relaxed),
+            inner.bytes_received.load(Ordering::Relaxed),
+        )
+    }
+
+    /// A snapshot of the tunnel's current state and counters.
+    pub fn tunnel_stats(&self) -> TunnelStats {
+        let state = self.state();
+        let inner = self.inner.read().unwrap();
+        TunnelStats {
+            state,
+            connected_for: inner.connected_at.map(|at| at.elapsed()),
+            bytes_sent: inner.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: inner.bytes_received.load(Ordering::Relaxed),
+            last_handshake: inner
+                .peers
+                .iter()
+                .filter_map(|(_, peer)| peer.tunn.time_since_last_handshake())
+                .min(),
+        }
+    }
+
+    /// A snapshot of each of the peers' state, in config order.
+    pub fn peer_stats(&self) -> Result<Vec<PeerStats>> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .peers
+            .iter()
+            .map(|(_, peer)| {
+                Ok(PeerStats {
+                    endpoint: format!("{}:{}", peer.endpoint.ToString()?, peer.port),
+                    description: peer.description.clone(),
+                    last_handshake: peer.tunn.time_since_last_handshake(),
+                })
+            })
+            .collect()
+    }
+
+    /// A handle that can tear down the tunnel from outside of the platform's callbacks.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle {
+            inner: self.inner.clone(),
+            state: self.state.clone(),
+            etw_logger: self.etw_logger.clone(),
+            notifier: self.notifier.clone(),
         }
     }
 
+    /// Move to a new `ConnectionState`, noting the transition in our traces.
+    fn set_state(&self, new: ConnectionState) {
+        set_state(&self.state, &self.etw_logger, new);
+    }
+
     /// Called by the platform so that we may connect and setup the VPN tunnel.
     fn Connect(&self, channel: &Option<VpnChannel>) -> Result<()> {
+        // The platform also calls us to pick up a changed config on a live tunnel,
+        // which may not need a full reconnect
+        let mut wg_config = None;
+        if self.state() == ConnectionState::Connected {
+            if let Some(Ok(config)) = channel.as_ref().map(parse_config) {
+                if self.try_hot_reload(&config) {
+                    return Ok(());
+                }
+                // Otherwise no need to parse it (and look up its key) all over again
+                wg_config = Some(config);
+            }
+        }
+
+        self.connect(channel, wg_config)
+    }
+
+    /// Bring up the tunnel with `wg_config`, or the channel's own config if not given.
+    fn connect(
+        &self,
+        channel: &Option<VpnChannel>,
+        wg_config: Option<WireGuardConfig>,
+    ) -> Result<()> {
+        self.set_state(ConnectionState::Connecting);
+
+        // Tie all the events for this connection together, through to the disconnect
+        let etw_opts = EventOptions {
+            activity_id: new_activity_id().ok(),
+            ..Default::default()
+        };
+
         // Call out to separate method so that we can capture any errors
-        if let Err(err) = self.connect_inner(channel) {
-            self.etw_logger
-                .connect_fail(None, err.code().0, &err.to_string());
-            Err(err)
+        if let Err(err) = self.connect_inner(channel, wg_config, &etw_opts) {
+            self.set_state(ConnectionState::Idle);
+
+            self.etw_logger.connect_fail(
+                Some(&etw_opts),
+                err.code().0,
+                err.name(),
+                &err.to_string(),
+            );
+
+            let server = channel.as_ref().and_then(first_server).unwrap_or_default();
+            let reason = err.to_string();
+            record_history(HistoryEntry::new(
+                HistoryEvent::Failed,
+                &server,
+                Some(&reason),
+            ));
+
+            // Give the user something more useful than a bare error code where we can
+            if let Some(channel) = channel {
+                let message = if err.is_config_error() {
+                    Some(err.to_string())
+                } else {
+                    hresult_to_net_error(err.code()).map(|desc| format!("Network error: {}", desc))
+                };
+                // Not being able to show it mustn't keep us from retrying
+                if let Some(message) = message {
+                    if let Err(set_err) = channel.SetErrorMessage(message) {
+                        debug_log!("Failed to set error message: {}", set_err);
+                    }
+                }
+                self.schedule_retry(channel, &err, &etw_opts);
+            }
+
+            Err(err.into())
         } else {
+            self.reconnect_attempts.store(0, Ordering::Relaxed);
+
+            let mut inner = self.inner.write().unwrap();
+            inner.connected_at = Some(Instant::now());
+            let first_peer = inner.peers.first().map(|(_, peer)| {
+                let server = peer.endpoint.DisplayName().map(|server| server.to_string());
+                (server.unwrap_or_default(), inner.peers.len())
+            });
+            drop(inner);
+
+            publish_connected_at(Some(SystemTime::now()));
+            self.set_state(ConnectionState::Connected);
+
+            // The tunnel's up, so failing to let the user know mustn't make it look otherwise
+            if let Some((server, peers)) = first_peer {
+                self.notifier.connected(&server, peers);
+                record_history(HistoryEntry::new(HistoryEvent::Connected, &server, None));
+            }
             Ok(())
         }
     }
 
-    /// Internal `Connect` implementation.
-    fn connect_inner(&self, channel: &Option<VpnChannel>) -> Result<()> {
-        let channel = channel.as_ref().ok_or(Error::from(E_UNEXPECTED))?;
+    /// Have another go at a failed `Connect` later on, according to the config's
+    /// `ReconnectPolicy` (if any).
+    ///
+    /// We can't wait around in `Connect` itself, so the error goes straight back to the
+    /// platform and a timer asks it to connect the profile again once the delay is up. The
+    /// count starts over once a connection succeeds or we run out of attempts.
+    fn schedule_retry(&self, channel: &VpnChannel, err: &PluginError, etw_opts: &EventOptions) {
+        let attempt = self.reconnect_attempts.load(Ordering::Relaxed);
+        let policy = *self.reconnect.lock().unwrap();
+        let policy = match policy {
+            // No point retrying with a bad config
+            Some(policy) if attempt < policy.max_attempts && !err.is_config_error() => policy,
+            _ => {
+                self.reconnect_attempts.store(0, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        let delay = policy.delay(attempt);
+        let res = channel
+            .Configuration()
+            .and_then(|config| config.CustomField())
+            .and_then(|custom_field| reconnect_after(custom_field, delay));
+        match res {
+            Ok(()) => {
+                self.reconnect_attempts
+                    .store(attempt + 1, Ordering::Relaxed);
+                debug_log!("Connect failed ({}), retrying in {:?}.", err, delay);
+                self.etw_logger.reconnect_attempt(
+                    Some(etw_opts),
+                    attempt + 1,
+                    delay.as_millis() as u64,
+                );
+            }
+            Err(err) => debug_log!("Failed to schedule a reconnect: {}", err),
+        }
+    }
+
+    /// Internal `Connect` implementation, parsing the channel's config if not given one.
+    fn connect_inner(
+        &self,
+        channel: &Option<VpnChannel>,
+        wg_config: Option<WireGuardConfig>,
+        etw_opts: &EventOptions,
+    ) -> std::result::Result<(), PluginError> {
+        let channel = channel
+            .as_ref()
+            .ok_or(PluginError::MissingArgument("channel"))?;
         let mut inner = self.inner.write().unwrap();
 
-        let config = channel.Configuration()?;
+        // Make note of what we're running with to help with triaging any issues
+        self.etw_logger.library_versions(
+            Some(etw_opts),
+            Self::boringtun_version(),
+            env!("WINDOWS_CRATE_VERSION"),
+            env!("CARGO_PKG_VERSION"),
+        );
 
-        // Grab custom config field from VPN profile and try to parse the config
-        // In theory this would totally be fine to deal with as INI to match
-        // most other wireguard config, but it's a bit of pain since a number of
-        // places assume this will be XML...
-        let wg_config = match WireGuardConfig::from_str(&config.CustomField()?.to_string()) {
-            Ok(conf) => conf,
-            Err(err) => {
-                channel.SetErrorMessage(format!("failed to parse config: {}", err))?;
-                return Err(Error::from(E_INVALIDARG));
-            }
+        let config = channel.Configuration()?;
+        let wg_config = match wg_config {
+            Some(wg_config) => wg_config,
+            None => parse_config(channel)?,
         };
 
-        let static_private = Arc::new(wg_config.interface.private_key);
-        let peer_static_public = Arc::new(wg_config.peer.public_key);
-        let persistent_keepalive = wg_config.peer.persistent_keepalive;
-        let preshared_key = wg_config.peer.preshared_key;
+        // Note what we're going over before the tunnel itself shows up as a network
+        let network = UnderlyingNetwork::current();
+
+        *self.reconnect.lock().unwrap() = wg_config.reconnect;
+        self.notifier
+            .set_enabled(wg_config.notifications.unwrap_or(true));
+
+        // Settings carried over from `wg-quick` that the platform gives us no way to honour
+        if !matches!(wg_config.interface.table, None | Some(TableConfig::Auto)) {
+            self.etw_logger
+                .unsupported_config_field(Some(etw_opts), "Table");
+        }
+        if !matches!(wg_config.interface.fwmark, None | Some(0)) {
+            self.etw_logger
+                .unsupported_config_field(Some(etw_opts), "FwMark");
+        }
+
+        let overhead = wg_config.estimate_overhead();
+        let (mtu, lowered) = tunnel_mtu(wg_config.interface.mtu, overhead);
+        if lowered {
+            self.etw_logger
+                .config_warning(Some(etw_opts), "MTU too large, may cause fragmentation");
+        }
+        // Incoming frames are the tunneled packets plus our overhead
+        let max_frame_size = u32::from(mtu) + overhead as u32;
+
+        // Each peer needs its own transport but the platform only supports so many
+        let peer_count = wg_config.peers.len();
+        if peer_count == 0 || peer_count > MAX_TRANSPORTS {
+            return Err(PluginError::ConfigInvalid(format!(
+                "between 1 and {} peers are supported",
+                MAX_TRANSPORTS
+            )));
+        }
+
+        // The profile only gives us hostnames, so we match them up with peers by position
+        let servers = config.ServerHostNameList()?;
+        if (servers.Size()? as usize) < peer_count {
+            return Err(PluginError::ConfigInvalid(
+                "each peer needs its own server address".into(),
+            ));
+        }
+
+        // We hang on to `wg_config` as is to diff any later changes against
+        let static_private = Arc::new(copy_private_key(&wg_config.interface.private_key));
+        let listen_port = wg_config.interface.listen_port;
+        let connection_attempts = wg_config.interface.connection_attempts.unwrap_or(1).max(1);
+        let rate_limit = wg_config
+            .interface
+            .rate_limit
+            .map(|limit| (limit, new_rate_limiter(&static_private, limit)));
 
         // Grab interface addresses
-        let iface_addrs = wg_config.interface.address;
+        let iface_addrs = &wg_config.interface.address;
         // Now massage em into the right form
         let (ipv4, ipv6) = iface_addrs
-            .into_iter()
+            .iter()
+            .copied()
             .partition::<Vec<_>, _>(IpNetwork::is_ipv4);
         let ipv4_addrs = ipv4
             .into_iter()
@@ -110,29 +1007,68 @@ impl VpnPlugin {
             Some(Vector::new(ipv6_addrs).into())
         };
 
-        let build_routes = |routes: Vec<IpNetwork>| -> Result<_> {
-            let mut ipv4 = vec![];
-            let mut ipv6 = vec![];
+        // Takes each network along with the index of the peer it came from
+        let build_routes = |networks: PeerNetworks, is_exclusion: bool| -> Result<_> {
+            let mut routes = vec![];
 
-            for ip in routes {
+            for (peer_idx, ip) in networks {
+                self.etw_logger.route_assigned(
+                    Some(etw_opts),
+                    peer_idx as u32,
+                    if ip.is_ipv4() { 4 } else { 6 },
+                    &ip.network().to_string(),
+                    ip.prefix(),
+                    is_exclusion,
+                );
                 let route = VpnRoute::CreateVpnRoute(
                     HostName::CreateHostName(ip.network().to_string())?,
                     ip.prefix(),
                 )?;
-                if ip.is_ipv4() {
-                    ipv4.push(Some(route));
-                } else {
-                    ipv6.push(Some(route));
-                }
+                routes.push(Some(route));
             }
 
-            Ok((ipv4, ipv6))
+            Ok(routes)
         };
 
         let routes = VpnRouteAssignment::new()?;
 
-        // Grab AllowedIPs and build routes from it
-        let (allowed_ipv4, allowed_ipv6) = build_routes(wg_config.peer.allowed_ips)?;
+        // Build up the lookup table used to route outgoing packets to the right peer
+        let router = peer_router(&wg_config.peers);
+
+        // Grab AllowedIPs across all peers and build routes from it
+        let (mut allowed_ipv4, mut allowed_ipv6) =
+            peer_routes(&wg_config.peers, |peer| &peer.allowed_ips);
+
+        // With the kill switch on, everything goes to the VPN interface and anything not
+        // matching some peer's AllowedIPs gets dropped by us rather than leaking out. Local
+        // subnets are still let through so the device stays reachable on the LAN.
+        let kill_switch = wg_config.interface.kill_switch;
+        if kill_switch {
+            let mut missing_default = false;
+            for (allowed_ips, default) in [
+                (&mut allowed_ipv4, IpAddr::from(Ipv4Addr::UNSPECIFIED)),
+                (&mut allowed_ipv6, Ipv6Addr::UNSPECIFIED.into()),
+            ] {
+                let default = IpNetwork::new(default, 0).expect("0 is a valid prefix");
+                if !allowed_ips.iter().any(|(_, ip)| *ip == default) {
+                    missing_default = true;
+                    // Attributed to the first peer just for logging, the router never sees it
+                    allowed_ips.push((0, default));
+                }
+            }
+            if missing_default {
+                self.etw_logger.config_warning(
+                    Some(etw_opts),
+                    "KillSwitch is on but AllowedIPs doesn't include the default route, \
+                     traffic outside AllowedIPs will be dropped",
+                );
+            }
+            routes.SetExcludeLocalSubnets(true)?;
+        }
+
+        let allowed_ipv4 = build_routes(allowed_ipv4, false)?;
+        let allowed_ipv6 = build_routes(allowed_ipv6, false)?;
+        let (ipv4_route_count, ipv6_route_count) = (allowed_ipv4.len(), allowed_ipv6.len());
 
         if !allowed_ipv4.is_empty() {
             routes.SetIpv4InclusionRoutes(Vector::new(allowed_ipv4))?;
@@ -142,7 +1078,11 @@ impl VpnPlugin {
         }
 
         // Grab ExcludedIPs to determine exclusion routes
-        let (excluded_ipv4, excluded_ipv6) = build_routes(wg_config.peer.excluded_ips)?;
+        let (excluded_ipv4, excluded_ipv6) =
+            peer_routes(&wg_config.peers, |peer| &peer.excluded_ips);
+        let excluded_ipv4 = build_routes(excluded_ipv4, true)?;
+        let excluded_ipv6 = build_routes(excluded_ipv6, true)?;
+        let excluded_route_count = excluded_ipv4.len() + excluded_ipv6.len();
 
         if !excluded_ipv4.is_empty() {
             routes.SetIpv4ExclusionRoutes(Vector::new(excluded_ipv4))?;
@@ -152,121 +1092,613 @@ impl VpnPlugin {
         }
 
         // Setup DNS
-        let namespace_assignment = VpnNamespaceAssignment::new()?;
-        let dns_servers = wg_config
-            .interface
-            .dns_servers
-            .into_iter()
-            .map(|server| HostName::CreateHostName(server.to_string()))
-            .collect::<Result<Vec<_>>>()?
-            .into_iter()
-            .map(Some)
-            .collect::<Vec<_>>();
-        let search_domains = wg_config.interface.search_domains;
-
-        let namespace_count = search_domains.len() + !dns_servers.is_empty() as usize;
-        let mut namespaces = Vec::with_capacity(namespace_count);
-
-        // Add the search domains as suffix NRPT rules so that
-        // they get added to the virtual interface's
-        // Connection-Specific DNS Suffix Search List.
-        for mut search_domain in search_domains {
-            // Prefix with . to make it a suffix rule
-            search_domain.insert(0, '.');
-            let dns_servers = Vector::new(dns_servers.clone());
-            let namespace =
-                VpnNamespaceInfo::CreateVpnNamespaceInfo(search_domain, dns_servers, None)?;
-            namespaces.push(Some(namespace));
-        }
+        let namespace_assignment = build_namespace_assignment(
+            &wg_config.interface.dns_servers,
+            &wg_config.interface.search_domains,
+            &wg_config.peers,
+        )?;
+        let dns_server_count = wg_config.interface.dns_servers.len() as u32;
+
+        // Limiting the tunnel to specific apps needs `StartWithTrafficFilter`, which wants
+        // the DNS rules as a domain name assignment instead
+        let app_filters = match build_traffic_filters(&wg_config.peers)? {
+            Some(traffic_filters) => Some((
+                traffic_filters,
+                build_domain_name_assignment(
+                    &wg_config.interface.dns_servers,
+                    &wg_config.interface.search_domains,
+                    &wg_config.peers,
+                )?,
+            )),
+            None => None,
+        };
 
-        if !dns_servers.is_empty() {
-            // We set the namespace name to '.' so it applies to everything instead of
-            // a specific set of domains (see NRPT)
-            let dns_servers = Vector::new(dns_servers);
-            let namespace = VpnNamespaceInfo::CreateVpnNamespaceInfo(".", dns_servers, None)?;
-            namespaces.push(Some(namespace));
+        let mut peers = Vec::with_capacity(peer_count);
+        for (idx, peer) in wg_config.peers.iter().enumerate() {
+            // A peer's own `Endpoint` takes precedence over the profile's servers. Otherwise,
+            // with a single peer any of the servers will do, so we can fail over between them
+            let (candidates, port) = if peer.endpoint.is_some() {
+                let (host, port) = peer.resolved_endpoint(&servers.GetAt(idx as u32)?)?;
+                (vec![host], port)
+            } else if peer_count == 1 {
+                let candidates = (0..servers.Size()?)
+                    .map(|i| servers.GetAt(i))
+                    .collect::<Result<Vec<_>>>()?;
+                (candidates, peer.port)
+            } else {
+                peer.resolved_endpoint(&servers.GetAt(idx as u32)?)
+                    .map(|(host, port)| (vec![host], port))?
+            };
+
+            let public_key = Arc::new(X25519PublicKey::from(peer.public_key.as_bytes()));
+
+            // Create WG tunnel object
+            let tunn = Tunn::new(
+                static_private.clone(),
+                public_key.clone(),
+                peer.preshared_key,
+                peer.persistent_keepalive,
+                idx as u32, // Peer index, must be unique per tunnel
+                rate_limit.as_ref().map(|(_, limiter)| limiter.clone()),
+            )
+            .map_err(|e| PluginError::TunnCreate(e.into()))?;
+
+            // Only one socket can have the configured local port so it goes to the first peer
+            let local_port = listen_port.filter(|_| idx == 0);
+
+            // Go through the whole list of candidates up to `connection_attempts` times
+            let (sock, server) =
+                first_to_connect(&candidates, connection_attempts, |server, attempt| {
+                    let host = server.ToString()?.to_string();
+                    self.etw_logger
+                        .connect_attempt(Some(etw_opts), &host, port, attempt);
+                    self.connect_peer_socket(channel, server, port, local_port, idx)
+                })
+                .map_err(|err| {
+                    PluginError::SocketConnect(err.unwrap_or_else(|| E_UNEXPECTED.into()))
+                })?;
+
+            peers.push((
+                sock,
+                PeerState {
+                    tunn,
+                    endpoint: server.clone(),
+                    port,
+                    description: peer.description.clone(),
+                    public_key,
+                    preshared_key: peer.preshared_key,
+                    persistent_keepalive: peer.persistent_keepalive,
+                    handshake_started: Mutex::new(None),
+                    timers: Mutex::new(TimerState::new()),
+                    keepalive_failures: AtomicU32::new(0),
+                },
+            ));
         }
 
-        namespace_assignment.SetNamespaceList(Vector::new(namespaces))?;
-
-        // Create WG tunnel object
-        let tunn = Tunn::new(
-            static_private,
-            peer_static_public,
-            preshared_key,
-            persistent_keepalive,
-            0,    // Peer index. we only have one peer
-            None, // TODO: No rate limiter
-        )
-        // TODO: is E_UNEXPECTED the right error here?
-        .map_err(|e| Error::new(E_UNEXPECTED, e.into()))?;
+        let main_transport = IInspectable::from(&peers[0].0);
+        let secondary_transport = peers.get(1).map(|(sock, _)| IInspectable::from(sock));
 
         // Stuff it into our inner state
         // Just forget the previous tunn state and start over (if one exists at all)
-        if let Some(_) = std::mem::replace(&mut inner.tunn, Some(tunn)) {
+        let new_inner = InnerBuilder::new()
+            .peers(peers)
+            .router(router)
+            // Hang on to the channel so we can cleanly tear things down later
+            .channel(channel.clone())
+            .kill_switch(kill_switch)
+            // Leaves room for the IPv4 & TCP headers, `tcp_mss_clamp` accounts for IPv6
+            .max_mss(
+                wg_config
+                    .interface
+                    .mss_clamp
+                    .then(|| mtu.saturating_sub(40)),
+            )
+            .dead_peer_threshold(wg_config.interface.dead_peer_detection)
+            .rekey_window(wg_config.interface.rekey_watchdog)
+            .rate_limit(rate_limit)
+            .activity_id(etw_opts.activity_id.clone())
+            .config(wg_config)
+            .private_key(static_private)
+            .network(network)
+            .build()?;
+        let old_inner = std::mem::replace(&mut *inner, new_inner);
+        old_inner.timer_stop.store(true, Ordering::Relaxed);
+        if !old_inner.peers.is_empty() {
             debug_log!("Replacing leftover tunn state.");
         }
 
-        // Create socket and register with VPN platform
-        let sock = DatagramSocket::new()?;
-        channel.AddAndAssociateTransport(&sock, None)?;
-
-        // Just use the first server listed to connect to remote endpoint
-        let server = config.ServerHostNameList()?.GetAt(0)?;
-        let port = wg_config.peer.port;
+        self.etw_logger.routes_applied(
+            Some(etw_opts),
+            ipv4_route_count as u32,
+            ipv6_route_count as u32,
+            excluded_route_count as u32,
+        );
 
-        debug_log!("Server: {} Port: {}", server.ToString()?.to_string(), port);
+        // Kick off the VPN setup
+        match app_filters {
+            Some((traffic_filters, domain_name_assignment)) => channel.StartWithTrafficFilter(
+                ipv4_addrs,
+                ipv6_addrs,
+                None, // Interface ID portion of IPv6 address for VPN tunnel
+                routes,
+                domain_name_assignment,
+                u32::from(mtu),      // MTU size of VPN tunnel interface
+                max_frame_size,      // Max frame size of incoming buffers from remote endpoint
+                false,               // Reserved
+                main_transport,      // Pass in the socket to the first peer
+                secondary_transport, // And the second peer, if any
+                traffic_filters,     // Only let the listed apps use the tunnel
+            )?,
+            None => channel.Start(
+                ipv4_addrs,
+                ipv6_addrs,
+                None, // Interface ID portion of IPv6 address for VPN tunnel
+                routes,
+                namespace_assignment,
+                u32::from(mtu),      // MTU size of VPN tunnel interface
+                max_frame_size,      // Max frame size of incoming buffers from remote endpoint
+                false,               // Disable low cost network monitoring
+                main_transport,      // Pass in the socket to the first peer
+                secondary_transport, // And the second peer, if any
+            )?,
+        }
 
-        // We "block" here with the call to `.get()` but given this is a UDP socket
-        // connect isn't actually something that will hang (DNS aside perhaps?).
-        sock.ConnectAsync(&server, port.to_string())?.get()?;
+        // Get our buffers ready upfront rather than one at a time in the packet path.
+        // Not fatal, we'll just ask the platform for buffers as we go.
+        if let Err(err) = inner
+            .send_buffers
+            .fill(|| channel.GetVpnSendPacketBuffer())
+            .and_then(|_| {
+                inner
+                    .recv_buffers
+                    .fill(|| channel.GetVpnReceivePacketBuffer())
+            })
+        {
+            debug_log!("Failed to pre-allocate packet buffers: {:?}", err);
+        }
 
-        // Kick off the VPN setup
-        channel.Start(
-            ipv4_addrs,
-            ipv6_addrs,
-            None, // Interface ID portion of IPv6 address for VPN tunnel
-            routes,
-            namespace_assignment,
-            1500,  // MTU size of VPN tunnel interface
-            1600,  // Max frame size of incoming buffers from remote endpoint
-            false, // Disable low cost network monitoring
-            sock,  // Pass in the socket to the remote endpoint
-            None,  // No secondary socket used.
-        )?;
+        // It'll start ticking once we let go of the lock
+        spawn_timer_thread(
+            self.inner.clone(),
+            inner.timer_stop.clone(),
+            self.etw_logger.clone(),
+            self.perf_counters.clone(),
+            self.cancel_handle(),
+        );
 
         // Log successful connection
-        self.etw_logger
-            .connected(None, &server.ToString()?.to_string(), port);
+        for (sock, peer) in &inner.peers {
+            let info = sock.Information()?;
+            let local_port = info.LocalPort()?.to_string();
+
+            // The endpoint may well have been a hostname so note what it actually resolved to
+            let remote_addr = info.RemoteAddress()?;
+            self.etw_logger.address_family(
+                Some(etw_opts),
+                &peer.endpoint.ToString()?.to_string(),
+                &remote_addr.ToString()?.to_string(),
+                remote_addr.Type()? == HostNameType::Ipv6,
+            );
+
+            let desc = peer.description.as_deref().unwrap_or_default();
+            let desc = desc.chars().take(MAX_EVENT_DESC_LEN).collect::<String>();
+            self.etw_logger.connected(
+                Some(etw_opts),
+                &peer.endpoint.ToString()?.to_string(),
+                peer.port,
+                local_port.parse().unwrap_or(0),
+                &desc,
+                dns_server_count,
+            );
+        }
 
         Ok(())
     }
 
+    /// Create a socket for a peer and connect it to `server`, logging why if that fails.
+    fn connect_peer_socket(
+        &self,
+        channel: &VpnChannel,
+        server: &HostName,
+        port: u16,
+        listen_port: Option<u16>,
+        peer_idx: usize,
+    ) -> Result<DatagramSocket> {
+        connect_socket(channel, server, port, listen_port).map_err(|err| {
+            debug_log!(
+                "Failed to connect to {}: {}",
+                server.ToString().unwrap_or_default(),
+                err
+            );
+            // Binding the configured local port is usually what goes wrong there
+            let operation = if listen_port.is_some() {
+                "bind"
+            } else {
+                "connect"
+            };
+            log_socket_error(&self.etw_logger, peer_idx, operation, &err);
+            err
+        })
+    }
+
     /// Called by the platform to indicate we should disconnect and cleanup the VPN tunnel.
     fn Disconnect(&self, channel: &Option<VpnChannel>) -> Result<()> {
+        // Grab the connection's activity before we tear everything down
+        let etw_opts = self.inner.read().unwrap().etw_options();
+
         // Call out to separate method so that we can capture any errors
         if let Err(err) = self.disconnect_inner(channel) {
             self.etw_logger
-                .disconnect(None, err.code().0, &err.to_string());
+                .disconnect(Some(&etw_opts), err.code().0, &err.to_string());
             Err(err)
         } else {
-            self.etw_logger.disconnect(None, 0, "Operation successful.");
+            self.etw_logger
+                .disconnect(Some(&etw_opts), 0, "Operation successful.");
+            self.notifier.disconnected(None);
             Ok(())
         }
     }
 
     /// Internal `Disconnect` implementation.
     fn disconnect_inner(&self, channel: &Option<VpnChannel>) -> Result<()> {
-        let channel = channel.as_ref().ok_or(Error::from(E_UNEXPECTED))?;
+        let channel = channel
+            .as_ref()
+            .ok_or(PluginError::MissingArgument("channel"))?;
+        self.shutdown(channel, DISCONNECT_TIMEOUT)
+    }
 
+    /// Tear down the VPN tunnel, first letting each remote peer know we're going away.
+    ///
+    /// WireGuard has no explicit session termination message so the best we can do is send
+    /// one last keepalive on each established session. We wait up to `timeout / n` for each
+    /// of the `n` peers before giving up and stopping the channel regardless.
+    pub fn graceful_shutdown(&self, timeout: Duration) -> Result<()> {
+        let channel = self.inner.read().unwrap().channel.clone();
+        if let Some(channel) = channel {
+            self.shutdown(&channel, timeout)
+        } else {
+            // Never connected, nothing to do
+            Ok(())
+        }
+    }
+
+    /// Shared implementation of `Disconnect` & `graceful_shutdown`.
+    fn shutdown(&self, channel: &VpnChannel, timeout: Duration) -> Result<()> {
+        self.set_state(ConnectionState::Disconnecting);
+        let res = stop_tunnel(&self.inner, channel, timeout, &self.etw_logger, None);
+        self.set_state(ConnectionState::Idle);
+        res
+    }
+
+    /// Swap out our private key without tearing down the VPN tunnel.
+    ///
+    /// Each peer gets a fresh tunnel using the new key and we immediately send out a new
+    /// handshake initiation to each of them. boringtun zeroes the old key once the last
+    /// tunnel referencing it is dropped.
+    pub fn rotate_private_key(&self, new_key: X25519SecretKey) -> Result<()> {
+        let new_key = Arc::new(new_key);
         let mut inner = self.inner.write().unwrap();
-        inner.tunn = None;
 
-        channel.Stop()?;
+        // The rate limiter is tied to our public key so it needs replacing too
+        let rate_limiter = inner
+            .rate_limit
+            .map(|limit| new_rate_limiter(&new_key, limit));
+
+        // Only switch over once every peer's new tunnel is ready, failing leaves us as we were
+        let tunns = inner
+            .peers
+            .iter()
+            .enumerate()
+            .map(|(idx, (_, peer))| {
+                new_peer_tunn(
+                    idx,
+                    peer,
+                    peer.preshared_key,
+                    new_key.clone(),
+                    rate_limiter.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        inner.rate_limiter = rate_limiter;
+        inner.private_key = Some(new_key);
+        let handshakes = self.restart_peer_tunns(&mut inner.peers, tunns);
+
+        // Any established sessions went away along with the old tunnels
+        inner.sessions.write().unwrap().clear();
+        drop(inner);
+
+        self.send_handshakes(handshakes);
+        self.etw_logger.private_key_rotated(None);
+
+        Ok(())
+    }
+
+    /// Start over with fresh sessions to all the peers without tearing down the tunnel.
+    ///
+    /// Only the platform can reconnect the VPN channel itself, but this gets us new handshakes
+    /// with every peer, e.g. after the network changed underneath a stuck session.
+    pub fn force_reconnect(&self) -> Result<()> {
+        let mut guard = self.inner.write().unwrap();
+        let inner = &mut *guard;
+        let private_key = inner.private_key.clone().ok_or(Error::from(E_UNEXPECTED))?;
+
+        // Only switch over once every peer's new tunnel is ready, failing leaves us as we were
+        let tunns = inner
+            .peers
+            .iter()
+            .enumerate()
+            .map(|(idx, (_, peer))| {
+                new_peer_tunn(
+                    idx,
+                    peer,
+                    peer.preshared_key,
+                    private_key.clone(),
+                    inner.rate_limiter.clone(),
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let handshakes = self.restart_peer_tunns(&mut inner.peers, tunns);
+
+        // Any established sessions went away along with the old tunnels
+        inner.sessions.write().unwrap().clear();
+        let etw_opts = inner.etw_options();
+        drop(guard);
+
+        self.send_handshakes(handshakes);
+        self.etw_logger.reconnect_forced(Some(&etw_opts));
 
         Ok(())
     }
 
+    /// Re-establish the tunnel if the network it came up on went away, e.g. after switching
+    /// from Wi-Fi to Ethernet, as the peers' sockets went away along with it.
+    ///
+    /// Called whenever the system tells us the network status changed. Only `Connect` may
+    /// start the channel, so we stop it and ask the platform to connect the profile again,
+    /// but no more than once per `NETWORK_RECONNECT_INTERVAL`.
+    pub fn handle_network_change(&self) -> Result<()> {
+        if self.state() != ConnectionState::Connected {
+            return Ok(());
+        }
+
+        let (channel, old_network, etw_opts) = {
+            let inner = self.inner.read().unwrap();
+            (
+                inner.channel.clone(),
+                inner.network.clone(),
+                inner.etw_options(),
+            )
+        };
+        let (channel, old_network) = match (channel, old_network) {
+            (Some(channel), Some(network)) => (channel, network),
+            _ => return Ok(()),
+        };
+        if old_network.is_connected() {
+            return Ok(());
+        }
+
+        // Nothing to reconnect over yet, we'll hear about it again once there is
+        let new_network = match UnderlyingNetwork::current() {
+            Some(network) => network,
+            None => return Ok(()),
+        };
+
+        {
+            let mut last_reconnect = self.last_network_reconnect.lock().unwrap();
+            if matches!(*last_reconnect, Some(at) if at.elapsed() < NETWORK_RECONNECT_INTERVAL) {
+                debug_log!("Network changed again too soon, not reconnecting.");
+                return Ok(());
+            }
+            *last_reconnect = Some(Instant::now());
+        }
+
+        self.etw_logger.network_change_reconnect(
+            Some(&etw_opts),
+            &old_network.name,
+            &new_network.name,
+        );
+
+        // The platform calls `Connect` with the channel's own config, getting the peers fresh
+        // sockets over the new network
+        let custom_field = channel.Configuration()?.CustomField()?;
+        self.shutdown(&channel, DISCONNECT_TIMEOUT)?;
+        reconnect_after(custom_field, Duration::ZERO)
+    }
+
+    /// Swap out the pre-shared key for the peer at `peer_idx` without tearing down the tunnel.
+    ///
+    /// boringtun has no way to change the pre-shared key of an existing tunnel so the peer
+    /// gets a fresh one (with the same keys otherwise) and we immediately start a new handshake.
+    /// Traffic to & from that peer is interrupted until the handshake completes, usually within
+    /// a round trip. Any other peers are left alone.
+    pub fn rotate_psk(&self, peer_idx: u32, new_psk: [u8; 32]) -> Result<()> {
+        let idx = peer_idx as usize;
+        let mut guard = self.inner.write().unwrap();
+        let inner = &mut *guard;
+
+        let private_key = inner.private_key.clone();
+        let (sock, peer) = inner.peers.get_mut(idx).ok_or(Error::from(E_BOUNDS))?;
+        let private_key = private_key.ok_or(Error::from(E_UNEXPECTED))?;
+
+        // Keep the old key if we can't get a tunnel using the new one
+        let tunn = new_peer_tunn(
+            idx,
+            peer,
+            Some(new_psk),
+            private_key,
+            inner.rate_limiter.clone(),
+        )?;
+        peer.preshared_key = Some(new_psk);
+        let handshakes: Vec<_> = self
+            .restart_peer_tunn(idx, peer, tunn)
+            .map(|packet| (idx, sock.clone(), packet))
+            .into_iter()
+            .collect();
+
+        // Its old session went away along with the old tunnel
+        inner
+            .sessions
+            .write()
+            .unwrap()
+            .retain(|_, session_peer| *session_peer != idx);
+        let etw_opts = inner.etw_options();
+        drop(guard);
+
+        self.send_handshakes(handshakes);
+        self.etw_logger.psk_rotated(Some(&etw_opts), peer_idx);
+
+        Ok(())
+    }
+
+    /// Switch every peer over to its fresh tunnel from `new_peer_tunn`, returning the new
+    /// handshake initiations to send out with `send_handshakes`.
+    fn restart_peer_tunns(
+        &self,
+        peers: &mut [(DatagramSocket, PeerState)],
+        tunns: impl IntoIterator<Item = Box<Tunn>>,
+    ) -> Vec<(usize, DatagramSocket, Vec<u8>)> {
+        let mut handshakes = vec![];
+        for (idx, ((sock, peer), tunn)) in peers.iter_mut().zip(tunns).enumerate() {
+            let handshake = self.restart_peer_tunn(idx, peer, tunn);
+            handshakes.extend(handshake.map(|packet| (idx, sock.clone(), packet)));
+        }
+        handshakes
+    }
+
+    /// Switch a peer over to a fresh tunnel from `new_peer_tunn` and format a new handshake
+    /// initiation for it.
+    ///
+    /// The caller is most likely holding the `inner` write lock so it's up to them to send
+    /// the returned initiation out with `send_handshakes` once they've let go.
+    fn restart_peer_tunn(
+        &self,
+        idx: usize,
+        peer: &mut PeerState,
+        tunn: Box<Tunn>,
+    ) -> Option<Vec<u8>> {
+        peer.tunn = tunn;
+        *peer.handshake_started.lock().unwrap() = None;
+        peer.keepalive_failures.store(0, Ordering::Relaxed);
+
+        // Don't wait around for the next packet to kick off the handshake
+        let mut handshake_buf = [0u8; HANDSHAKE_INIT_SZ];
+        let initiation = peer
+            .tunn
+            .format_handshake_initiation(&mut handshake_buf, false);
+        match initiation {
+            TunnResult::WriteToNetwork(packet) => {
+                peer.note_outgoing(idx, packet, &self.etw_logger);
+                Some(packet.to_vec())
+            }
+            _ => None,
+        }
+    }
+
+    /// Send out the handshake initiations from `restart_peer_tunn` to each peer's socket.
+    ///
+    /// The sends can block for a while so this must only be called without holding `inner`.
+    fn send_handshakes(&self, handshakes: Vec<(usize, DatagramSocket, Vec<u8>)>) {
+        for (idx, sock, packet) in handshakes {
+            match send_to_peer(
+                &sock,
+                idx,
+                &packet,
+                HANDSHAKE_SEND_TIMEOUT,
+                &self.etw_logger,
+            ) {
+                Ok(true) => {}
+                Ok(false) => debug_log!("Timed out sending handshake initiation."),
+                Err(err) => debug_log!("Failed to send handshake initiation: {}", err),
+            }
+        }
+    }
+
+    /// Apply a new config to the live tunnel without disconnecting, if possible.
+    ///
+    /// The platform only takes routes & DNS servers when the channel is started, so we can
+    /// handle `AllowedIPs` changes that stay within the routes we started with (we just drop
+    /// packets for any networks no longer allowed) along with `PersistentKeepalive` changes,
+    /// which restart the affected peers' sessions. Returns `false`, having changed nothing, if
+    /// anything else changed and a full reconnect is needed instead.
+    pub fn try_hot_reload(&self, new_config: &WireGuardConfig) -> bool {
+        let mut guard = self.inner.write().unwrap();
+        let inner = &mut *guard;
+        let (config, private_key) = match (&mut inner.config, &inner.private_key) {
+            (Some(config), Some(private_key)) => (config, private_key.clone()),
+            // Not connected
+            _ => return false,
+        };
+
+        let delta = config.diff(new_config);
+        if delta == ConfigDelta::default() {
+            // Nothing about the tunnel changed so the platform must want it re-established
+            return false;
+        }
+        if delta.other_changed
+            || delta.dns_changed
+            || !delta.peers_added.is_empty()
+            || !delta.peers_removed.is_empty()
+            || (delta.routes_changed && !new_config.routes_within(config))
+        {
+            return false;
+        }
+
+        if delta.routes_changed {
+            inner.router = peer_router(&new_config.peers);
+        }
+
+        let mut handshakes = vec![];
+        for &idx in &delta.keepalive_changed {
+            let (sock, peer) = &mut inner.peers[idx];
+            peer.persistent_keepalive = new_config.peers[idx].persistent_keepalive;
+            let res = new_peer_tunn(
+                idx,
+                peer,
+                peer.preshared_key,
+                private_key.clone(),
+                inner.rate_limiter.clone(),
+            );
+            match res {
+                Ok(tunn) => {
+                    let handshake = self.restart_peer_tunn(idx, peer, tunn);
+                    handshakes.extend(handshake.map(|packet| (idx, sock.clone(), packet)))
+                }
+                Err(err) => debug_log!("Failed to restart peer {}: {}", idx, err),
+            }
+            // Its old session went away along with the old tunnel
+            inner
+                .sessions
+                .write()
+                .unwrap()
+                .retain(|_, peer_idx| *peer_idx != idx);
+        }
+
+        // Everything else is the same so this brings our copy of the config up to date
+        for (idx, (old_peer, new_peer)) in
+            config.peers.iter_mut().zip(&new_config.peers).enumerate()
+        {
+            old_peer.allowed_ips = new_peer.allowed_ips.clone();
+            old_peer.persistent_keepalive = new_peer.persistent_keepalive;
+            old_peer.description = new_peer.description.clone();
+            inner.peers[idx].1.description = new_peer.description.clone();
+        }
+        config.strict_config = new_config.strict_config;
+        config.reconnect = new_config.reconnect;
+        config.notifications = new_config.notifications;
+        *self.reconnect.lock().unwrap() = new_config.reconnect;
+        self.notifier
+            .set_enabled(new_config.notifications.unwrap_or(true));
+
+        let etw_opts = inner.etw_options();
+        drop(guard);
+
+        self.send_handshakes(handshakes);
+        self.etw_logger
+            .config_reloaded(Some(&etw_opts), &delta.to_string());
+
+        true
+    }
+
     /// Called by the platform to indicate there are outgoing packets ready to be encapsulated.
     ///
     /// `packets` contains outgoing L3 IP packets that we should encapsulate in whatever protocol
@@ -278,90 +1710,191 @@ impl VpnPlugin {
         packets: &Option<VpnPacketBufferList>,
         encapsulatedPackets: &Option<VpnPacketBufferList>,
     ) -> Result<()> {
-        let channel = channel.as_ref().ok_or(Error::from(E_UNEXPECTED))?;
-        let packets = packets.as_ref().ok_or(Error::from(E_UNEXPECTED))?;
+        // Call out to separate method so that we can bracket it with region markers
+        self.etw_logger.encapsulate_start(None);
+        let res = self.encapsulate_inner(channel, packets, encapsulatedPackets);
+        self.etw_logger.encapsulate_stop(None);
+        res
+    }
+
+    /// Internal `Encapsulate` implementation.
+    fn encapsulate_inner(
+        &self,
+        channel: &Option<VpnChannel>,
+        packets: &Option<VpnPacketBufferList>,
+        encapsulatedPackets: &Option<VpnPacketBufferList>,
+    ) -> Result<()> {
+        let channel = channel
+            .as_ref()
+            .ok_or(PluginError::MissingArgument("channel"))?;
+        let packets = packets
+            .as_ref()
+            .ok_or(PluginError::MissingArgument("packets"))?;
         let encapsulatedPackets = encapsulatedPackets
             .as_ref()
-            .ok_or(Error::from(E_UNEXPECTED))?;
+            .ok_or(PluginError::MissingArgument("encapsulatedPackets"))?;
 
         let inner = self.inner.read().unwrap();
-        let tunn = if let Some(tunn) = &inner.tunn {
-            &**tunn
-        } else {
+        if inner.peers.is_empty() {
             // We haven't initalized tunn yet, just return
             return Ok(());
-        };
+        }
 
-        let mut ret_buffers = vec![];
         let mut encap_err = None;
 
-        // Usually this would be called in the background by some periodic timer
-        // but a UWP VPN plugin will get suspended if there's no traffic and that
-        // includes any background threads or such we could create.
+        // The timer thread usually takes care of this but a UWP VPN plugin will
+        // get suspended if there's no traffic and that includes the timer thread.
         // So we may find ourselves with a stale session and need to do a new
         // handshake. Thus, we just call this opportunistically here before
         // trying to encapsulate.
-        if tunn.time_since_last_handshake() >= Some(Duration::from_millis(250)) {
-            const HANDSHAKE_INIT_SZ: usize = 148;
+        for (idx, (_, peer)) in inner.peers.iter().enumerate() {
+            let tunn = &peer.tunn;
+            if tunn.time_since_last_handshake() >= Some(Duration::from_millis(250)) {
+                let mut handshake_buf = [0u8; HANDSHAKE_INIT_SZ];
+                match tunn.update_timers(&mut handshake_buf) {
+                    // Session still valid, nothing more to do.
+                    TunnResult::Done => (),
+
+                    // Encountered an error, bail out
+                    TunnResult::Err(err) => {
+                        peer.note_timers_error(idx, &err, &self.etw_logger);
+                        return Err(PluginError::Timers(err).into());
+                    }
+
+                    // Looks like we need to get things updated
+                    TunnResult::WriteToNetwork(packet) => {
+                        peer.note_outgoing(idx, packet, &self.etw_logger);
+
+                        // Request a new buffer
+                        let mut handshake_buffer = inner
+                            .send_buffers
+                            .acquire_or_else(|| channel.GetVpnSendPacketBuffer())?;
+
+                        // Copy data over and update length on WinRT buffer
+                        handshake_buffer.get_buf_mut()?[..packet.len()].copy_from_slice(packet);
+                        let new_len =
+                            u32::try_from(packet.len()).map_err(|_| Error::from(E_BOUNDS))?;
+                        handshake_buffer.Buffer()?.SetLength(new_len)?;
+
+                        // Now queue it up to be sent out over the peer's socket
+                        handshake_buffer.SetTransportAffinity(idx as u32)?;
+                        encapsulatedPackets.Append(handshake_buffer.into_inner())?;
+                    }
+
+                    // Impossible cases for update_timers
+                    TunnResult::WriteToTunnelV4(_, _) | TunnResult::WriteToTunnelV6(_, _) => {
+                        panic!("unexpected result from update_timers");
+                    }
+                }
+            }
+        }
+
+        // Any packets for a peer we don't have a session with yet would just get queued up
+        // by boringtun so don't bother spending send buffers on them. Asking for a handshake
+        // initiation either gets us one to send out or tells us one is already in flight,
+        // without queueing anything up in the tunnel.
+        let mut awaiting_handshake = vec![false; inner.peers.len()];
+        for (idx, (_, peer)) in inner.peers.iter().enumerate() {
+            if peer.tunn.time_since_last_handshake().is_some() {
+                // Session already established
+                continue;
+            }
+
             let mut handshake_buf = [0u8; HANDSHAKE_INIT_SZ];
-            match tunn.update_timers(&mut handshake_buf) {
-                // Session still valid, nothing more to do.
-                TunnResult::Done => (),
+            match peer
+                .tunn
+                .format_handshake_initiation(&mut handshake_buf, false)
+            {
+                // Handshake initiation already sent, still waiting on a response
+                TunnResult::Done => awaiting_handshake[idx] = true,
 
                 // Encountered an error, bail out
-                TunnResult::Err(err) => {
-                    return Err(Error::new(
-                        E_UNEXPECTED,
-                        format!("update_timers error: {:?}", err).into(),
-                    ));
-                }
+                TunnResult::Err(err) => return Err(PluginError::Encap(err).into()),
 
-                // Looks like we need to get things updated
+                // Need to kick off a new handshake
                 TunnResult::WriteToNetwork(packet) => {
+                    peer.note_outgoing(idx, packet, &self.etw_logger);
+
                     // Request a new buffer
-                    let mut handshake_buffer = channel.GetVpnSendPacketBuffer()?;
+                    let mut handshake_buffer = inner
+                        .send_buffers
+                        .acquire_or_else(|| channel.GetVpnSendPacketBuffer())?;
 
                     // Copy data over and update length on WinRT buffer
                     handshake_buffer.get_buf_mut()?[..packet.len()].copy_from_slice(packet);
                     let new_len = u32::try_from(packet.len()).map_err(|_| Error::from(E_BOUNDS))?;
                     handshake_buffer.Buffer()?.SetLength(new_len)?;
 
-                    // Now queue it up to be sent
-                    encapsulatedPackets.Append(handshake_buffer)?;
+                    // Now queue it up to be sent out over the peer's socket
+                    handshake_buffer.SetTransportAffinity(idx as u32)?;
+                    encapsulatedPackets.Append(handshake_buffer.into_inner())?;
                 }
 
-                // Impossible cases for update_timers
+                // Impossible cases for format_handshake_initiation
                 TunnResult::WriteToTunnelV4(_, _) | TunnResult::WriteToTunnelV6(_, _) => {
-                    panic!("unexpected result from update_timers");
+                    panic!("unexpected result from format_handshake_initiation");
                 }
             }
         }
 
+        // Nothing we can send right now, just leave `packets` for the platform to clean up
+        if awaiting_handshake.iter().all(|&awaiting| awaiting) {
+            return Ok(());
+        }
+
         let packets_sz = packets.Size()?;
-        self.etw_logger.encapsulate_begin(None, packets_sz);
+        let etw_opts = inner.etw_options();
+        self.etw_logger
+            .encapsulate_begin(Some(&etw_opts), packets_sz);
 
         // Process outgoing packets from VPN tunnel.
         // TODO: Not using the simpler `for packet in packets` because
         //       `packets.First()?` fails with E_NOINTERFACE for some reason.
         for _ in 0..packets_sz {
-            let packet = packets.RemoveAtBegin()?;
+            let mut packet = packets.RemoveAtBegin()?;
+            if let Some(max_mss) = inner.max_mss {
+                let len = packet.get_buf()?.len();
+                tcp_mss_clamp(&mut packet.get_buf_mut()?[..len], max_mss);
+            }
             let src = packet.get_buf()?;
 
+            // Figure out which peer this packet is destined for. Just like any other
+            // WireGuard implementation, drop any packets not matching some peer's AllowedIPs
+            let peer_idx = match inner.peer_for_packet(src) {
+                Some(peer_idx) if !awaiting_handshake[peer_idx as usize] => peer_idx,
+                // No route to a peer or it's still waiting on a handshake
+                _ => {
+                    packets.Append(packet)?;
+                    continue;
+                }
+            };
+            let tunn = &inner.peers[peer_idx as usize].1.tunn;
+
             // Grab a destination buffer for the encapsulated packet
-            let mut encapPacket = channel.GetVpnSendPacketBuffer()?;
+            let mut encapPacket = inner
+                .send_buffers
+                .acquire_or_else(|| channel.GetVpnSendPacketBuffer())?;
             let dst = encapPacket.get_buf_mut()?;
 
             // Try to encapsulate packet
+            self.etw_logger.tunn_encapsulate_start(None);
             let res = tunn.encapsulate(src, dst);
+            self.etw_logger.tunn_encapsulate_stop(None);
 
             if let TunnResult::WriteToNetwork(packet) = res {
                 // Packet was encap'd successfully, make sure to update length on the WinRT side
                 let new_len = u32::try_from(packet.len()).map_err(|_| Error::from(E_BOUNDS))?;
                 drop(packet);
                 encapPacket.Buffer()?.SetLength(new_len)?;
+                inner
+                    .bytes_sent
+                    .fetch_add(u64::from(new_len), Ordering::Relaxed);
+
+                // Make sure it goes out over the socket connected to the right peer
+                encapPacket.SetTransportAffinity(peer_idx)?;
 
                 // Now, tack it onto `encapsulatedPackets` to send to remote endpoint
-                encapsulatedPackets.Append(encapPacket)?;
+                encapsulatedPackets.Append(encapPacket.into_inner())?;
             } else {
                 match res {
                     // Handled above
@@ -373,10 +1906,7 @@ impl VpnPlugin {
                     // Encountered an error while trying to encapsulate
                     TunnResult::Err(err) => {
                         if encap_err.is_none() {
-                            encap_err = Some(Error::new(
-                                E_UNEXPECTED,
-                                format!("encap error: {:?}", err).into(),
-                            ));
+                            encap_err = Some(PluginError::Encap(err).into());
                         }
                     }
 
@@ -386,8 +1916,7 @@ impl VpnPlugin {
                     }
                 }
 
-                // We must return the `encapPacket` we requested
-                ret_buffers.push(encapPacket);
+                // The unused `encapPacket` goes back to the pool to be reused
             }
 
             // Note: this loop does not consume the items in packets which is important
@@ -398,12 +1927,21 @@ impl VpnPlugin {
             packets.Append(packet)?;
         }
 
-        self.etw_logger
-            .encapsulate_end(None, encapsulatedPackets.Size()?);
-
-        // Just stick the unneeded buffers onto `packets` so the platform can clean them up
-        for packet in ret_buffers {
-            packets.Append(packet)?;
+        self.etw_logger.encapsulate_end(
+            Some(&etw_opts),
+            encapsulatedPackets.Size()?,
+            inner.bytes_sent.load(Ordering::Relaxed),
+        );
+
+        // We can't rely on a background timer so just report the stats as we go
+        inner.encap_stats.record(packets_sz);
+        if let Some(stats) = inner.encap_stats.snapshot_if_due() {
+            self.etw_logger.encap_batch_stats(
+                None,
+                stats.min_batch,
+                stats.max_batch,
+                stats.avg_x100(),
+            );
         }
 
         // If we encountered an error, return it
@@ -422,6 +1960,14 @@ impl VpnPlugin {
     /// `decapsulatedPackets`, making them ready to be injected into the virtual tunnel. If
     /// we need to send back control payloads or otherwise back to the remote endpoint, we
     /// may place such frames into `controlPackets`.
+    ///
+    /// Those control payloads are usually handshake responses, but once boringtun's rate
+    /// limiter decides we're under load it answers handshake initiations with a cookie reply
+    /// instead, which the initiator has to prove it received before we'll do the expensive
+    /// part of the handshake. They go back the same way, we just log them so it's visible when
+    /// the DoS mitigation is active. Either way they're headed out to the remote endpoint, so
+    /// they go in send buffers (`GetVpnSendPacketBuffer`) rather than the receive buffers used
+    /// for `decapsulatedPackets`.
     fn Decapsulate(
         &self,
         channel: &Option<VpnChannel>,
@@ -429,99 +1975,178 @@ impl VpnPlugin {
         decapsulatedPackets: &Option<VpnPacketBufferList>,
         controlPackets: &Option<VpnPacketBufferList>,
     ) -> Result<()> {
-        let channel = channel.as_ref().ok_or(Error::from(E_UNEXPECTED))?;
-        let buffer = buffer.as_ref().ok_or(Error::from(E_UNEXPECTED))?;
+        // Call out to separate method so that we can bracket it with region markers
+        self.etw_logger.decapsulate_start(None);
+        let res = self.decapsulate_inner(channel, buffer, decapsulatedPackets, controlPackets);
+        self.etw_logger.decapsulate_stop(None);
+        res
+    }
+
+    /// Internal `Decapsulate` implementation.
+    fn decapsulate_inner(
+        &self,
+        channel: &Option<VpnChannel>,
+        buffer: &Option<VpnPacketBuffer>,
+        decapsulatedPackets: &Option<VpnPacketBufferList>,
+        controlPackets: &Option<VpnPacketBufferList>,
+    ) -> Result<()> {
+        let channel = channel
+            .as_ref()
+            .ok_or(PluginError::MissingArgument("channel"))?;
+        let buffer = buffer
+            .as_ref()
+            .ok_or(PluginError::MissingArgument("buffer"))?;
         let decapsulatedPackets = decapsulatedPackets
             .as_ref()
-            .ok_or(Error::from(E_UNEXPECTED))?;
-        let controlPackets = controlPackets.as_ref().ok_or(Error::from(E_UNEXPECTED))?;
+            .ok_or(PluginError::MissingArgument("decapsulatedPackets"))?;
+        let controlPackets = controlPackets
+            .as_ref()
+            .ok_or(PluginError::MissingArgument("controlPackets"))?;
 
         let inner = self.inner.read().unwrap();
-        let tunn = if let Some(tunn) = &inner.tunn {
-            &**tunn
+
+        // We've no timer to reset the limiter's count every second so do it as frames come in.
+        // It keeps track of when it was last reset so calling this more often is harmless.
+        if let Some(rate_limiter) = &inner.rate_limiter {
+            rate_limiter.reset_count();
+        }
+
+        // Get a slice to the datagram we just received from the remote endpoint
+        // NOTE: Any IP fragments have already been reassembled by the network stack by the
+        //       time we see them so this will always be exactly one complete WireGuard message.
+        let datagram = buffer.get_buf()?;
+
+        // The frame's transport affinity tells us which peer's socket it came in on.
+        // Any replies need to go back out the same way.
+        let transport_idx = buffer.TransportAffinity()?;
+
+        // Established sessions can be looked up directly by the receiver index in the
+        // frame, otherwise fall back to the peer whose socket the frame came in on.
+        let receiver_idx = parse_receiver_index(datagram);
+        let peer_idx = receiver_idx
+            .and_then(|idx| inner.peer_by_receiver_index(idx))
+            .unwrap_or(transport_idx as usize);
+        let tunn = if let Some((_, peer)) = inner.peers.get(peer_idx) {
+            &*peer.tunn
         } else {
             // We haven't initalized tunn yet, just return
             return Ok(());
         };
 
+        let etw_opts = inner.etw_options();
         self.etw_logger
-            .decapsulate_begin(None, buffer.Buffer()?.Length()?);
-
-        // Allocate a buffer for the decapsulate packet
-        let mut decapPacket = channel.GetVpnReceivePacketBuffer()?;
-        let dst = decapPacket.get_buf_mut()?;
+            .decapsulate_begin(Some(&etw_opts), buffer.Buffer()?.Length()?);
 
-        // Get a slice to the datagram we just received from the remote endpoint and try to decap
-        let datagram = buffer.get_buf()?;
-        let res = tunn.decapsulate(None, datagram, dst);
+        // Decapsulate into a scratch buffer first. Only once we know what we got back do we
+        // grab a buffer of the right kind from the platform, so none go unused.
+        let mut scratch = [0u8; PHYSICAL_MTU];
 
-        match res {
-            // Nothing to do with this decap result
-            TunnResult::Done => {
-                // TODO: Return unused `decapPacket` buffer
+        // Queue up a response to send back to the remote endpoint
+        let append_control = |packet: &[u8]| -> Result<()> {
+            if packet.first() == Some(&handshake_debug::COOKIE_REPLY) {
+                self.etw_logger
+                    .cookie_reply_sent(Some(&etw_opts), transport_idx);
             }
+            let mut controlPacket = inner
+                .send_buffers
+                .acquire_or_else(|| channel.GetVpnSendPacketBuffer())?;
+            controlPacket.write_buf(packet)?;
+            controlPacket.SetTransportAffinity(transport_idx)?;
+            controlPackets.Append(controlPacket.into_inner())
+        };
 
-            // Encountered an error while trying to decapsulate
-            TunnResult::Err(err) => {
-                // TODO: Return unused `decapPacket` buffer
-                return Err(Error::new(
-                    E_UNEXPECTED,
-                    format!("encap error: {:?}", err).into(),
-                ));
+        // Queue up a decapsulated data packet to inject into the VPN interface. Just like any
+        // other WireGuard implementation, drop it unless its source falls within the sending
+        // peer's AllowedIPs, lest one peer spoof traffic from another's networks.
+        let append_data = |packet: &[u8]| -> Result<()> {
+            let src = packet_src_addr(packet);
+            if src.and_then(|src| inner.router.longest_match(src)) != Some(peer_idx as u32) {
+                debug_log!(
+                    "Dropping packet from {:?} not allowed for peer {}.",
+                    src,
+                    peer_idx
+                );
+                return Ok(());
+            }
+            let mut decapPacket = inner
+                .recv_buffers
+                .acquire_or_else(|| channel.GetVpnReceivePacketBuffer())?;
+            let new_len = decapPacket.write_buf(packet)?;
+            if let Some(max_mss) = inner.max_mss {
+                tcp_mss_clamp(&mut decapPacket.get_buf_mut()?[..new_len as usize], max_mss);
             }
+            inner
+                .bytes_received
+                .fetch_add(u64::from(new_len), Ordering::Relaxed);
+            inner.packets_received.fetch_add(1, Ordering::Relaxed);
+            decapsulatedPackets.Append(decapPacket.into_inner())
+        };
 
-            // We need to send response back to remote endpoint
-            TunnResult::WriteToNetwork(packet) => {
-                // Make sure to update length on WinRT buffer
-                let new_len = u32::try_from(packet.len()).map_err(|_| Error::from(E_BOUNDS))?;
-                drop(packet);
+        // Try to decap
+        self.etw_logger.tunn_decapsulate_start(None);
+        let res = tunn.decapsulate(None, datagram, &mut scratch);
+        self.etw_logger.tunn_decapsulate_stop(None);
+
+        // A handshake response that didn't error out means our handshake went through
+        if datagram.first() == Some(&handshake_debug::HANDSHAKE_RESPONSE)
+            && !matches!(res, TunnResult::Err(_))
+        {
+            if let Some((_, peer)) = inner.peers.get(peer_idx) {
+                peer.note_handshake_complete(peer_idx, &self.etw_logger);
+            }
+            inner.handshakes.fetch_add(1, Ordering::Relaxed);
+        }
 
-                // TODO: technically, we really should've used `GetVpnSendPacketBuffer` for this
-                //       buffer but boringtun doesn't really have a way to know in advance if it'll
-                //       be giving back control packets instead of data packets.
-                //       We could just use temp buffers and copy as appropriate?
-                let controlPacket = decapPacket;
-                controlPacket.Buffer()?.SetLength(new_len)?;
-
-                // Tack onto `controlPackets` so that they get sent to remote endpoint
-                controlPackets.Append(controlPacket)?;
-
-                // We need to probe for any more packets queued to send
-                loop {
-                    // Allocate a buffer for control packet
-                    let mut controlPacket = channel.GetVpnSendPacketBuffer()?;
-                    let dst = controlPacket.get_buf_mut()?;
-
-                    let res = tunn.decapsulate(None, &[], dst);
-                    if let TunnResult::WriteToNetwork(packet) = res {
-                        // Make sure to update length on WinRT buffer
-                        let new_len =
-                            u32::try_from(packet.len()).map_err(|_| Error::from(E_BOUNDS))?;
-                        drop(packet);
-                        controlPacket.Buffer()?.SetLength(new_len)?;
-                        controlPackets.Append(controlPacket)?;
-                    } else {
-                        // TODO: Return unused `controlPacket` buffer
-                        // Nothing more to do
-                        break;
-                    }
-                }
+        // Anything from the peer that got through authentication means it's still there,
+        // whether it's data (keepalives included) or part of a handshake
+        let authenticated = match (datagram.first().copied(), &res) {
+            (_, TunnResult::Err(_)) => false,
+            // Under load we answer handshakes with a cookie before checking them any further
+            (_, TunnResult::WriteToNetwork(packet))
+                if packet.first() == Some(&handshake_debug::COOKIE_REPLY) =>
+            {
+                false
+            }
+            (Some(handshake_debug::DATA | handshake_debug::HANDSHAKE_RESPONSE), _) => true,
+            // An initiation only gets a response once it checks out
+            (Some(handshake_debug::HANDSHAKE_INIT), TunnResult::WriteToNetwork(packet)) => {
+                packet.first() == Some(&handshake_debug::HANDSHAKE_RESPONSE)
+            }
+            _ => false,
+        };
+        if authenticated {
+            if let Some((_, peer)) = inner.peers.get(peer_idx) {
+                peer.keepalive_failures.store(0, Ordering::Relaxed);
             }
+        }
 
-            // Successfully decapsulated data packet
-            TunnResult::WriteToTunnelV4(packet, _) | TunnResult::WriteToTunnelV6(packet, _) => {
-                // Make sure to update length on WinRT buffer
-                let new_len = u32::try_from(packet.len()).map_err(|_| Error::from(E_BOUNDS))?;
-                drop(packet);
-                decapPacket.Buffer()?.SetLength(new_len)?;
+        if let TunnResult::Err(err) = res {
+            return Err(PluginError::Decap(err).into());
+        }
 
-                // Tack onto `decapsulatedPackets` to inject into VPN interface
-                decapsulatedPackets.Append(decapPacket)?;
-            }
+        // A successfully decapsulated data packet means the session is established, so remember
+        // which peer it belongs to
+        if let (TunnResult::WriteToTunnelV4(..) | TunnResult::WriteToTunnelV6(..), Some(idx)) =
+            (&res, receiver_idx)
+        {
+            inner.note_session(idx, peer_idx);
         }
 
-        self.etw_logger
-            .decapsulate_end(None, decapsulatedPackets.Size()?, controlPackets.Size()?);
+        // boringtun may have more packets queued up for us, keep probing until it's done
+        let mut probe_for_more = dispatch_packet(res, append_control, append_data)?;
+        while probe_for_more {
+            self.etw_logger.tunn_decapsulate_start(None);
+            let res = tunn.decapsulate(None, &[], &mut scratch);
+            self.etw_logger.tunn_decapsulate_stop(None);
+            probe_for_more = dispatch_packet(res, append_control, append_data)?;
+        }
+
+        self.etw_logger.decapsulate_end(
+            Some(&etw_opts),
+            decapsulatedPackets.Size()?,
+            controlPackets.Size()?,
+            inner.bytes_received.load(Ordering::Relaxed),
+        );
 
         Ok(())
     }
@@ -534,58 +2159,1232 @@ impl VpnPlugin {
         channel: &Option<VpnChannel>,
         keepAlivePacket: &mut Option<VpnPacketBuffer>,
     ) -> Result<()> {
-        let channel = channel.as_ref().ok_or(Error::from(E_UNEXPECTED))?;
+        let channel = channel
+            .as_ref()
+            .ok_or(PluginError::MissingArgument("channel"))?;
 
         let inner = self.inner.read().unwrap();
-        let tunn = if let Some(tunn) = &inner.tunn {
-            &**tunn
-        } else {
-            // We haven't initalized tunn yet, just return
-            return Ok(());
-        };
 
         *keepAlivePacket = None;
 
-        // Grab a buffer for the keepalive packet
-        let mut kaPacket = channel.GetVpnSendPacketBuffer()?;
-        let dst = kaPacket.get_buf_mut()?;
+        // Sends can block for a while so they wait until we've let go of the lock
+        let mut outgoing = vec![];
+
+        // We only get to hand back a single packet so it goes to the first peer that needs one
+        for (idx, (sock, peer)) in inner.peers.iter().enumerate() {
+            // Any other peers that need one get it sent out directly over their socket
+            if keepAlivePacket.is_some() {
+                let mut buf = [0u8; HANDSHAKE_INIT_SZ];
+                match peer.tunn.update_timers(&mut buf) {
+                    TunnResult::WriteToNetwork(packet) => {
+                        peer.note_outgoing(idx, packet, &self.etw_logger);
+                        self.etw_logger.keepalive(None, packet.len() as u32);
+                        outgoing.push((idx, sock.clone(), packet.to_vec()));
+                    }
+                    // Don't let one peer keep the others from being serviced
+                    TunnResult::Err(err) => {
+                        peer.note_timers_error(idx, &err, &self.etw_logger);
+                        debug_log!("update_timers error: {:?}", err);
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            // Grab a buffer for the keepalive packet
+            let mut kaPacket = inner
+                .send_buffers
+                .acquire_or_else(|| channel.GetVpnSendPacketBuffer())?;
+            let dst = kaPacket.get_buf_mut()?;
+
+            // Any packets we need to send out?
+            match peer.tunn.update_timers(dst) {
+                // Nothing to do right now
+                // (unused `kaPacket` goes back to the pool)
+                TunnResult::Done => {}
+
+                // Encountered an error, bail out
+                TunnResult::Err(err) => {
+                    peer.note_timers_error(idx, &err, &self.etw_logger);
+                    return Err(PluginError::Timers(err).into());
+                }
+
+                // We got something to send to the remote
+                TunnResult::WriteToNetwork(packet) => {
+                    peer.note_outgoing(idx, packet, &self.etw_logger);
+
+                    // Make sure to update length on WinRT buffer
+                    let new_len = u32::try_from(packet.len()).map_err(|_| Error::from(E_BOUNDS))?;
+                    kaPacket.Buffer()?.SetLength(new_len)?;
+                    kaPacket.SetTransportAffinity(idx as u32)?;
 
-        // Any packets we need to send out?
-        match tunn.update_timers(dst) {
-            // Nothing to do right now
-            TunnResult::Done => {
-                // TODO: Return unused `kaPacket` buffer
+                    self.etw_logger.keepalive(None, new_len);
+
+                    // Place the packet in the out param to send to remote
+                    *keepAlivePacket = Some(kaPacket.into_inner());
+                }
+
+                // Impossible cases for update_timers
+                TunnResult::WriteToTunnelV4(_, _) | TunnResult::WriteToTunnelV6(_, _) => {
+                    panic!("unexpected result from update_timers")
+                }
             }
+        }
 
-            // Encountered an error, bail out
-            TunnResult::Err(err) => {
-                // TODO: Return unused `kaPacket` buffer
-                return Err(Error::new(
-                    // TODO: Better error than `E_UNEXPECTED`?
-                    E_UNEXPECTED,
-                    format!("update_timers error: {:?}", err).into(),
-                ));
+        drop(inner);
+        for (idx, sock, packet) in outgoing {
+            if !send_to_peer(
+                &sock,
+                idx,
+                &packet,
+                HANDSHAKE_SEND_TIMEOUT,
+                &self.etw_logger,
+            )? {
+                debug_log!("Timed out sending keepalive.");
             }
+        }
 
-            // We got something to send to the remote
-            TunnResult::WriteToNetwork(packet) => {
-                // Make sure to update length on WinRT buffer
-                let new_len = u32::try_from(packet.len()).map_err(|_| Error::from(E_BOUNDS))?;
-                drop(packet);
-                kaPacket.Buffer()?.SetLength(new_len)?;
+        Ok(())
+    }
+}
 
-                self.etw_logger.keepalive(None, new_len);
+/// Spawn a thread to drive the tunnels' timers (handshake retries, rekeying, keepalives, etc)
+/// until `stop` is set.
+///
+/// It's also what notices a dead peer: if enabled, once too many keepalives in a row go
+/// unanswered we tear the tunnel down via `cancel`, leaving it to the platform to reconnect.
+///
+/// NOTE: Like any other thread, this gets suspended along with the rest of the plugin while
+///       there's no traffic, so we also update the timers opportunistically in `Encapsulate`.
+fn spawn_timer_thread(
+    inner: Arc<RwLock<Inner>>,
+    stop: Arc<AtomicBool>,
+    etw_logger: Arc<WireGuardUWPEvents>,
+    perf_counters: Option<Arc<PerfCounters>>,
+    cancel: CancelHandle,
+) {
+    std::thread::spawn(move || {
+        // Keep an eye on battery saver, which stretches out the keepalives we let through
+        let saver_on = Arc::new(AtomicBool::new(energy_saver_on()));
+        let saver_token = {
+            let saver_on = saver_on.clone();
+            PowerManager::EnergySaverStatusChanged(EventHandler::new(move |_, _| {
+                saver_on.store(energy_saver_on(), Ordering::Relaxed);
+                Ok(())
+            }))
+        };
+        if let Err(err) = &saver_token {
+            debug_log!("Failed to watch for battery saver: {}", err);
+        }
+        let mut saver_was_on = false;
+        let mut last_keepalive = vec![];
+
+        let mut last_sample = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            let mut dead_peer = false;
+            // Sends can block for a while so they wait until we've let go of the lock
+            let mut outgoing = vec![];
+            {
+                let inner = inner.read().unwrap();
+                if let Some(counters) = &perf_counters {
+                    if last_sample.elapsed() >= PERF_SAMPLE_INTERVAL {
+                        counters.update(&inner.perf_sample());
+                        last_sample = Instant::now();
+                    }
+                }
 
-                // Place the packet in the out param to send to remote
-                *keepAlivePacket = Some(kaPacket);
+                let saver = saver_on.load(Ordering::Relaxed);
+                if saver != saver_was_on {
+                    for (_, peer) in &inner.peers {
+                        if let Some(keepalive) = peer.persistent_keepalive {
+                            let keepalive = if saver {
+                                saver_keepalive(keepalive).unwrap_or(0)
+                            } else {
+                                keepalive
+                            };
+                            etw_logger.power_mode_change(
+                                Some(&inner.etw_options()),
+                                saver,
+                                keepalive,
+                            );
+                        }
+                    }
+                    saver_was_on = saver;
+                }
+                last_keepalive.resize(inner.peers.len(), None);
+
+                for (idx, (sock, peer)) in inner.peers.iter().enumerate() {
+                    etw_logger.timer_tick(None, idx as u32);
+
+                    if !inner.rekey_window.is_zero() {
+                        peer.check_rekey_window(
+                            idx,
+                            inner.rekey_window,
+                            inner.packets_received.load(Ordering::Relaxed),
+                            &inner.etw_options(),
+                            &etw_logger,
+                        );
+                    }
+
+                    let mut buf = [0u8; HANDSHAKE_INIT_SZ];
+                    match peer.tunn.update_timers(&mut buf) {
+                        TunnResult::WriteToNetwork(packet) => {
+                            // A data message here is a keepalive on an established session
+                            let keepalive = packet.first() == Some(&handshake_debug::DATA);
+                            if keepalive {
+                                if saver
+                                    && !saver_keepalive_due(
+                                        peer.persistent_keepalive,
+                                        last_keepalive[idx],
+                                    )
+                                {
+                                    continue;
+                                }
+                                last_keepalive[idx] = Some(Instant::now());
+                            }
+
+                            peer.note_outgoing(idx, packet, &etw_logger);
+
+                            if keepalive {
+                                let failures =
+                                    peer.keepalive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                                if inner.dead_peer_threshold != 0
+                                    && failures >= inner.dead_peer_threshold
+                                {
+                                    etw_logger.dead_peer_detected(
+                                        Some(&inner.etw_options()),
+                                        idx as u32,
+                                        failures,
+                                    );
+                                    dead_peer = true;
+                                }
+                            }
+
+                            outgoing.push((idx, sock.clone(), packet.to_vec()));
+                        }
+                        TunnResult::Err(err) => {
+                            peer.note_timers_error(idx, &err, &etw_logger);
+                            debug_log!("update_timers error: {:?}", err);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            for (idx, sock, packet) in outgoing {
+                if let Err(err) =
+                    send_to_peer(&sock, idx, &packet, HANDSHAKE_SEND_TIMEOUT, &etw_logger)
+                {
+                    debug_log!("Failed to send timer packet: {}", err);
+                }
+            }
+
+            // Tearing down the tunnel needs the write lock so only once we've let go of it
+            if dead_peer {
+                if let Err(err) = cancel.cancel(Some("the server stopped responding")) {
+                    debug_log!("Failed to stop tunnel to dead peer: {}", err);
+                }
+                break;
+            }
+
+            std::thread::sleep(TIMER_INTERVAL);
+        }
+
+        if let Ok(token) = saver_token {
+            let _ = PowerManager::RemoveEnergySaverStatusChanged(token);
+        }
+
+        // The connection's totals start over with the next one anyway
+        if let Some(counters) = &perf_counters {
+            counters.update(&PerfSample::default());
+        }
+    });
+}
+
+/// Whether battery saver is currently on.
+fn energy_saver_on() -> bool {
+    PowerManager::EnergySaverStatus() == Ok(EnergySaverStatus::On)
+}
+
+/// The keepalive interval to use on battery saver for a peer with the given
+/// `PersistentKeepalive`, or `None` if its keepalives should be suspended altogether.
+///
+/// Never more often than configured, but otherwise no more than `SAVER_MAX_KEEPALIVE`.
+fn saver_keepalive(keepalive: u16) -> Option<u16> {
+    if keepalive < SAVER_SUSPEND_KEEPALIVE_BELOW {
+        return None;
+    }
+    let stretched = keepalive.saturating_mul(SAVER_KEEPALIVE_FACTOR);
+    Some(stretched.min(SAVER_MAX_KEEPALIVE.max(keepalive)))
+}
+
+/// Whether to let a keepalive boringtun wants to send go out while on battery saver, given when
+/// we last let one through for the peer.
+fn saver_keepalive_due(keepalive: Option<u16>, last_keepalive: Option<Instant>) -> bool {
+    let keepalive = match keepalive {
+        Some(keepalive) => keepalive,
+        // Without a `PersistentKeepalive`, they only go out in reply to incoming traffic
+        None => return true,
+    };
+    let interval = match saver_keepalive(keepalive) {
+        Some(interval) => Duration::from_secs(interval.into()),
+        None => return false,
+    };
+    // boringtun still wants to send one every `keepalive`, give or take a timer tick, so
+    // allow for some slack rather than skipping an extra one each time
+    let slack = Duration::from_secs(keepalive.into()) / 2;
+    last_keepalive.is_none_or(|at| at.elapsed() + slack >= interval)
+}
+
+/// Work out the NRPT rules for the interface's DNS servers & search domains, as each rule's
+/// namespace along with the DNS servers to resolve it with.
+///
+/// Each peer's search domains get a suffix rule of their own pointing at that peer's DNS
+/// servers (or the interface's if it has none), so that different peers can resolve
+/// different namespaces.
+fn dns_rules<'a>(
+    dns_servers: &'a [IpAddr],
+    search_domains: &'a [String],
+    peers: &'a [PeerConfig],
+) -> Vec<(String, &'a [IpAddr])> {
+    let mut rules = search_domains
+        .iter()
+        .map(|domain| (domain.as_str(), dns_servers))
+        .collect::<Vec<_>>();
+    for peer in peers {
+        let peer_dns_servers = if peer.dns_servers.is_empty() {
+            dns_servers
+        } else {
+            &peer.dns_servers[..]
+        };
+        for domain in &peer.search_domains {
+            let rule = (domain.as_str(), peer_dns_servers);
+            if !rules.contains(&rule) {
+                rules.push(rule);
+            }
+        }
+    }
+
+    // Add the search domains as suffix NRPT rules (prefixed with .) so that
+    // they get added to the virtual interface's
+    // Connection-Specific DNS Suffix Search List.
+    let mut rules = rules
+        .into_iter()
+        .map(|(domain, servers)| (format!(".{}", domain), servers))
+        .collect::<Vec<_>>();
+
+    if !dns_servers.is_empty() {
+        // We set the namespace name to '.' so it applies to everything instead of
+        // a specific set of domains (see NRPT)
+        rules.push((".".into(), dns_servers));
+    }
+
+    rules
+}
+
+/// Create a `HostName` for each of the DNS servers.
+fn dns_host_names(dns_servers: &[IpAddr]) -> Result<Vec<Option<HostName>>> {
+    dns_servers
+        .iter()
+        .map(|server| HostName::CreateHostName(server.to_string()).map(Some))
+        .collect()
+}
+
+/// Build the namespace assignment `VpnChannel::Start` takes from our DNS rules (see `dns_rules`).
+fn build_namespace_assignment(
+    dns_servers: &[IpAddr],
+    search_domains: &[String],
+    peers: &[PeerConfig],
+) -> Result<VpnNamespaceAssignment> {
+    let namespaces = dns_rules(dns_servers, search_domains, peers)
+        .into_iter()
+        .map(|(namespace, servers)| {
+            let servers = Vector::new(dns_host_names(servers)?);
+            VpnNamespaceInfo::CreateVpnNamespaceInfo(namespace, servers, None).map(Some)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let namespace_assignment = VpnNamespaceAssignment::new()?;
+    namespace_assignment.SetNamespaceList(Vector::new(namespaces))?;
+    Ok(namespace_assignment)
+}
+
+/// Build the domain name assignment `VpnChannel::StartWithTrafficFilter` takes instead of a
+/// namespace assignment, from the same DNS rules (see `dns_rules`).
+fn build_domain_name_assignment(
+    dns_servers: &[IpAddr],
+    search_domains: &[String],
+    peers: &[PeerConfig],
+) -> Result<VpnDomainNameAssignment> {
+    let domain_name_assignment = VpnDomainNameAssignment::new()?;
+    let domain_names = domain_name_assignment.DomainNameList()?;
+    for (namespace, servers) in dns_rules(dns_servers, search_domains, peers) {
+        let servers: IVector<HostName> = Vector::new(dns_host_names(servers)?).into();
+        domain_names.Append(VpnDomainNameInfo::CreateVpnDomainNameInfo(
+            namespace,
+            VpnDomainNameType::Suffix,
+            servers,
+            None::<IIterable<HostName>>,
+        )?)?;
+    }
+    Ok(domain_name_assignment)
+}
+
+/// Build the traffic filters limiting the tunnel to the apps listed in any of the peers'
+/// `AppTunnel`s, if there are any.
+fn build_traffic_filters(peers: &[PeerConfig]) -> Result<Option<VpnTrafficFilterAssignment>> {
+    let mut apps = Vec::new();
+    for app in peers.iter().flat_map(|peer| &peer.app_tunnel) {
+        // The platform only matches on package family names so drop the app ID part of an AUMID
+        let package_family_name = app.split('!').next().unwrap_or_default();
+        if !apps.contains(&package_family_name) {
+            apps.push(package_family_name);
+        }
+    }
+    if apps.is_empty() {
+        return Ok(None);
+    }
+
+    let filter_assignment = VpnTrafficFilterAssignment::new()?;
+    let filters = filter_assignment.TrafficFilterList()?;
+    for app in apps {
+        let app_id = VpnAppId::Create(VpnAppIdType::PackageFamilyName, app)?;
+        let filter = VpnTrafficFilter::Create(app_id)?;
+        filter.SetRoutingPolicyType(VpnRoutingPolicyType::SplitRouting)?;
+        filters.Append(filter)?;
+    }
+    Ok(Some(filter_assignment))
+}
+
+/// Parse and validate the config from the VPN profile's custom field.
+fn parse_config(channel: &VpnChannel) -> std::result::Result<WireGuardConfig, PluginError> {
+    // Usually this will be XML but we also accept the usual WireGuard INI format.
+    // The field is most likely set from PowerShell so be lenient about any
+    // leftover here-string artifacts.
+    let custom_field = channel.Configuration()?.CustomField()?.to_string();
+    // The private key may be kept in the Credential Manager rather than the profile itself
+    let custom_field = keystore::resolve_references(&custom_field, &PasswordVaultStore::new()?)
+        .map_err(PluginError::KeyStore)?;
+    let wg_config = WireGuardConfig::from_powershell_splatting(&custom_field)
+        .map_err(PluginError::ConfigParse)?;
+
+    // Catch any obvious mistakes before we try to use it
+    if let Err(errors) = wg_config.validate() {
+        let errors = errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; ");
+        return Err(PluginError::ConfigInvalid(errors));
+    }
+
+    Ok(wg_config)
+}
+
+/// Create a socket registered with the VPN platform and connect it to `server`.
+///
+/// The socket is bound to just the one address family `server` resolves to. A second socket for
+/// the other family would need a transport of its own, and `Start` takes only two, one per peer.
+fn connect_socket(
+    channel: &VpnChannel,
+    server: &HostName,
+    port: u16,
+    listen_port: Option<u16>,
+) -> Result<DatagramSocket> {
+    let sock = DatagramSocket::new()?;
+    channel.AddAndAssociateTransport(&sock, None)?;
+
+    debug_log!("Server: {} Port: {}", server.ToString()?.to_string(), port);
+
+    // We "block" here with the call to `.get()` but given this is a UDP socket
+    // connect isn't actually something that will hang (DNS aside perhaps?).
+    let res = match listen_port {
+        Some(listen_port) => EndpointPair::CreateEndpointPair(
+            None,
+            listen_port.to_string(),
+            server,
+            port.to_string(),
+        )
+        .and_then(|endpoints| sock.ConnectWithEndpointPairAsync(endpoints)?.get()),
+        None => sock
+            .ConnectAsync(server, port.to_string())
+            .and_then(|op| op.get()),
+    };
+
+    match res {
+        Ok(()) => Ok(sock),
+        Err(err) => {
+            // Don't leave the failed socket lingering around
+            let _ = sock.Close();
+            Err(err)
+        }
+    }
+}
+
+/// The MTU to give the tunnel interface: the configured one, unless it doesn't leave room for
+/// our `overhead` on a typical link, in which case we lower it to the largest that does.
+///
+/// Also returns whether the configured MTU had to be lowered.
+fn tunnel_mtu(configured: Option<u16>, overhead: usize) -> (u16, bool) {
+    let max_mtu = PHYSICAL_MTU - overhead;
+    match configured {
+        Some(mtu) if usize::from(mtu) > max_mtu => (max_mtu as u16, true),
+        Some(mtu) => (mtu, false),
+        None => (DEFAULT_MTU, false),
+    }
+}
+
+/// Hand the packet in a decapsulation result to `to_network` or `to_tunnel`, depending on where
+/// it's headed. They're what take a buffer from the platform so results without a packet never
+/// take one.
+///
+/// Returns whether there was a packet, in which case boringtun may have more queued up for us.
+/// An error ends things just like `Done`, callers wanting to report one check for it first.
+fn dispatch_packet(
+    res: TunnResult<'_>,
+    to_network: impl FnOnce(&[u8]) -> Result<()>,
+    to_tunnel: impl FnOnce(&[u8]) -> Result<()>,
+) -> Result<bool> {
+    match res {
+        // We need to send response back to remote endpoint
+        TunnResult::WriteToNetwork(packet) => to_network(packet)?,
+        TunnResult::WriteToTunnelV4(packet, _) | TunnResult::WriteToTunnelV6(packet, _) => {
+            to_tunnel(packet)?
+        }
+        // Nothing (more) to do
+        TunnResult::Done | TunnResult::Err(_) => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Go through `servers` in order, as many as `rounds` times, until `connect` succeeds with one
+/// of them. `connect` is also told which attempt it is, counting from 1.
+///
+/// If none of them work we return the last error, or `None` if there weren't any to try.
+fn first_to_connect<'a, S, T, E>(
+    servers: &'a [S],
+    rounds: u32,
+    mut connect: impl FnMut(&'a S, u32) -> std::result::Result<T, E>,
+) -> std::result::Result<(T, &'a S), Option<E>> {
+    let mut last_err = None;
+    let mut attempt = 0;
+    for _ in 0..rounds {
+        for server in servers {
+            attempt += 1;
+            match connect(server, attempt) {
+                Ok(conn) => return Ok((conn, server)),
+                Err(err) => last_err = Some(err),
+            }
+        }
+    }
+    Err(last_err)
+}
+
+/// Networks, each along with the index of the peer it came from.
+type PeerNetworks = Vec<(usize, IpNetwork)>;
+
+/// The networks picked out of each peer by `networks`, e.g. its AllowedIPs. IPv4 and IPv6
+/// networks are returned separately as each family gets its own set of routes.
+fn peer_routes(
+    peers: &[PeerConfig],
+    networks: impl Fn(&PeerConfig) -> &[IpNetwork],
+) -> (PeerNetworks, PeerNetworks) {
+    peers
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, peer)| networks(peer).iter().map(move |ip| (idx, *ip)))
+        .partition(|(_, ip)| ip.is_ipv4())
+}
+
+/// The lookup table from each peer's `AllowedIPs` to the index of the peer.
+fn peer_router(peers: &[PeerConfig]) -> NetworkPrefixMatcher {
+    let mut router = NetworkPrefixMatcher::new();
+    for (idx, peer) in peers.iter().enumerate() {
+        for ip in &peer.allowed_ips {
+            router.insert(*ip, idx as u32);
+        }
+    }
+    router
+}
+
+/// Create a fresh tunnel to the peer at `idx` using its current settings, except for the
+/// pre-shared key.
+fn new_peer_tunn(
+    idx: usize,
+    peer: &PeerState,
+    preshared_key: Option<[u8; 32]>,
+    private_key: Arc<X25519SecretKey>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+) -> Result<Box<Tunn>> {
+    Tunn::new(
+        private_key,
+        peer.public_key.clone(),
+        preshared_key,
+        peer.persistent_keepalive,
+        idx as u32, // Peer index, must be unique per tunnel
+        rate_limiter,
+    )
+    .map_err(|e| PluginError::TunnCreate(e.into()).into())
+}
+
+/// Create a handshake rate limiter for the public key matching `private_key`.
+fn new_rate_limiter(private_key: &X25519SecretKey, limit: u32) -> Arc<RateLimiter> {
+    Arc::new(RateLimiter::new(
+        &private_key.public_key(),
+        u64::from(limit),
+    ))
+}
+
+/// Tears down a `VpnPlugin`'s tunnel on behalf of whoever is hosting it, e.g. when the
+/// background task gets cancelled by the system.
+pub struct CancelHandle {
+    inner: Arc<RwLock<Inner>>,
+    state: Arc<Mutex<ConnectionState>>,
+    etw_logger: Arc<WireGuardUWPEvents>,
+    notifier: Arc<Notifier>,
+}
+
+impl CancelHandle {
+    /// Stop the tunnel if it's up, giving the peers up to `DISCONNECT_TIMEOUT` to hear about it.
+    ///
+    /// `reason` is passed along to the user in the disconnect notification.
+    pub fn cancel(&self, reason: Option<&str>) -> Result<()> {
+        let channel = self.inner.read().unwrap().channel.clone();
+        let channel = match channel {
+            Some(channel) => channel,
+            // Never connected, nothing to do
+            None => return Ok(()),
+        };
+
+        set_state(
+            &self.state,
+            &self.etw_logger,
+            ConnectionState::Disconnecting,
+        );
+        let res = stop_tunnel(
+            &self.inner,
+            &channel,
+            DISCONNECT_TIMEOUT,
+            &self.etw_logger,
+            reason,
+        );
+        set_state(&self.state, &self.etw_logger, ConnectionState::Idle);
+        self.notifier.disconnected(reason);
+        res
+    }
+}
+
+/// Generate a fresh ETW activity ID to correlate the events of a single connection.
+fn new_activity_id() -> Result<win_etw_provider::GUID> {
+    let guid = unsafe { CoCreateGuid()? };
+    Ok(win_etw_provider::GUID {
+        data1: guid.data1,
+        data2: guid.data2,
+        data3: guid.data3,
+        data4: guid.data4,
+    })
+}
+
+/// Move to a new `ConnectionState`, noting the transition in our traces.
+fn set_state(
+    state: &Mutex<ConnectionState>,
+    etw_logger: &WireGuardUWPEvents,
+    new: ConnectionState,
+) {
+    let old = std::mem::replace(&mut *state.lock().unwrap(), new);
+    if old != new {
+        etw_logger.state_change(None, old as u32, new as u32);
+        app_service::broadcast_state(new);
+    }
+}
+
+/// Let anyone else in the process (i.e. the foreground app) know when we connected, if we are.
+fn publish_connected_at(connected_at: Option<SystemTime>) {
+    let res = CoreApplication::Properties().and_then(|props| match connected_at {
+        Some(time) => props
+            .Insert(
+                CONNECTED_AT_PROPERTY,
+                PropertyValue::CreateDateTime(to_datetime(time))?,
+            )
+            .map(|_| ()),
+        None if props.HasKey(CONNECTED_AT_PROPERTY)? => props.Remove(CONNECTED_AT_PROPERTY),
+        None => Ok(()),
+    });
+    if let Err(err) = res {
+        debug_log!("Failed to publish connection time: {}", err);
+    }
+}
+
+/// Stop the channel and forget all our tunnel state, sending each peer we have a session with a
+/// final keepalive once we no longer hold the lock.
+///
+/// The connection goes into the history as disconnected because of `reason`, if we know it.
+fn stop_tunnel(
+    inner: &RwLock<Inner>,
+    channel: &VpnChannel,
+    timeout: Duration,
+    etw_logger: &WireGuardUWPEvents,
+    reason: Option<&str>,
+) -> Result<()> {
+    let mut inner = inner.write().unwrap();
+
+    // Only worth noting in the history if the tunnel actually came up
+    if let Some(connected_at) = inner.connected_at {
+        let server = inner
+            .peers
+            .first()
+            .and_then(|(_, peer)| peer.endpoint.DisplayName().ok())
+            .map(|server| server.to_string())
+            .unwrap_or_default();
+        record_history(
+            HistoryEntry::new(HistoryEvent::Disconnected, &server, reason).with_stats(
+                connected_at.elapsed(),
+                inner.bytes_sent.load(Ordering::Relaxed),
+                inner.bytes_received.load(Ordering::Relaxed),
+            ),
+        );
+    }
+
+    // With the kill switch on, stop the channel before anything else so the platform takes
+    // all our routes down at once. The peers miss out on a final keepalive in that case.
+    let stopped = if inner.kill_switch {
+        Some(channel.Stop())
+    } else {
+        None
+    };
+
+    inner.timer_stop.store(true, Ordering::Relaxed);
+    inner.activity_id = None;
+    inner.connected_at = None;
+    inner.config = None;
+    inner.private_key = None;
+    inner.network = None;
+    publish_connected_at(None);
+    let peers = std::mem::take(&mut inner.peers);
+    inner.sessions.write().unwrap().clear();
+    inner.channel = None;
+    inner.kill_switch = false;
+
+    // The peers are all ours now, so don't hold up everyone else while we wait on the sends
+    drop(inner);
+
+    if let Some(res) = stopped {
+        return res;
+    }
+
+    let per_peer_timeout = timeout / peers.len().max(1) as u32;
+    for (idx, (sock, peer)) in peers.into_iter().enumerate() {
+        // Without a session we'd just end up queueing the payload and starting a new handshake
+        if peer.tunn.time_since_last_handshake().is_none() {
+            continue;
+        }
+
+        // Encapsulating an empty payload gives us a keepalive on an established session
+        let mut buf = [0u8; 148];
+        if let TunnResult::WriteToNetwork(packet) = peer.tunn.encapsulate(&[], &mut buf) {
+            // Don't let a failed send keep us from stopping the channel
+            match send_to_peer(&sock, idx, packet, per_peer_timeout, etw_logger) {
+                Ok(true) => {}
+                Ok(false) => debug_log!("Timed out sending final keepalive."),
+                Err(err) => debug_log!("Failed to send final keepalive: {}", err),
             }
+        }
+    }
+
+    channel.Stop()?;
+
+    Ok(())
+}
 
-            // Impossible cases for update_timers
-            TunnResult::WriteToTunnelV4(_, _) | TunnResult::WriteToTunnelV6(_, _) => {
-                panic!("unexpected result from update_timers")
+/// Ask the platform to connect the profile whose custom configuration is `custom_field` once
+/// `delay` is up.
+///
+/// The channel doesn't tell us which profile it belongs to, so we look for it by its config.
+fn reconnect_after(custom_field: HSTRING, delay: Duration) -> Result<()> {
+    let handler = TimerElapsedHandler::new(move |_| {
+        let agent = VpnManagementAgent::new()?;
+        for profile in agent.GetProfilesAsync()?.get()? {
+            let config = profile
+                .cast::<VpnPlugInProfile>()
+                .and_then(|profile| profile.CustomConfiguration());
+            if matches!(config, Ok(config) if config == custom_field) {
+                agent.ConnectProfileAsync(&profile)?;
+                break;
             }
         }
+        Ok(())
+    });
+    let delay = TimeSpan {
+        Duration: (delay.as_nanos() / 100) as i64,
+    };
+    ThreadPoolTimer::CreateTimer(handler, delay).map(|_| ())
+}
+
+/// Add an entry to the connection history, there's nothing more to do than log it if that fails.
+fn record_history(entry: HistoryEntry) {
+    if let Err(err) = ConnectionHistory::append(entry) {
+        debug_log!("Failed to update connection history: {}", err);
+    }
+}
+
+/// The first of the servers configured for the channel's profile, if any.
+fn first_server(channel: &VpnChannel) -> Option<String> {
+    let servers = channel.Configuration().ok()?.ServerHostNameList().ok()?;
+    let server = servers.GetAt(0).ok()?;
+    Some(server.DisplayName().ok()?.to_string())
+}
 
+/// `send_with_timeout` to the peer at `peer_idx`, logging any failure.
+fn send_to_peer(
+    sock: &DatagramSocket,
+    peer_idx: usize,
+    data: &[u8],
+    timeout: Duration,
+    etw_logger: &WireGuardUWPEvents,
+) -> Result<bool> {
+    send_with_timeout(sock, data, timeout).inspect_err(|err| {
+        log_socket_error(etw_logger, peer_idx, "send", err);
+    })
+}
+
+/// Log a failed socket operation for the given peer.
+fn log_socket_error(
+    etw_logger: &WireGuardUWPEvents,
+    peer_idx: usize,
+    operation: &str,
+    err: &Error,
+) {
+    etw_logger.socket_error(
+        None,
+        peer_idx as u32,
+        operation,
+        err.code().0,
+        &err.to_string(),
+    );
+}
+
+/// Send `data` out over `sock`, waiting up to `timeout` for the send to complete.
+///
+/// Returns whether the send completed in time.
+fn send_with_timeout(sock: &DatagramSocket, data: &[u8], timeout: Duration) -> Result<bool> {
+    let writer = DataWriter::CreateDataWriter(sock.OutputStream()?)?;
+    writer.WriteBytes(data)?;
+
+    let (tx, rx) = mpsc::channel();
+    let op = writer.StoreAsync()?;
+    op.SetCompleted(AsyncOperationCompletedHandler::new(move |_, status| {
+        let _ = tx.send(status);
         Ok(())
+    }))?;
+
+    match rx.recv_timeout(timeout) {
+        Ok(AsyncStatus::Completed) => Ok(true),
+        Ok(_) => Err(op.ErrorCode()?.into()),
+        Err(_) => {
+            op.Cancel()?;
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_fixtures::load_fixture;
+
+    fn overhead() -> usize {
+        WireGuardConfig::from_str(&load_fixture("valid_single_peer"))
+            .unwrap()
+            .estimate_overhead()
+    }
+
+    #[test]
+    fn default_mtu_fits_physical_mtu() {
+        assert_eq!(tunnel_mtu(None, overhead()), (DEFAULT_MTU, false));
+        assert!(usize::from(DEFAULT_MTU) + overhead() <= PHYSICAL_MTU);
+    }
+
+    #[test]
+    fn configured_mtu_is_used() {
+        assert_eq!(tunnel_mtu(Some(1280), overhead()), (1280, false));
+        let max_mtu = (PHYSICAL_MTU - overhead()) as u16;
+        assert_eq!(tunnel_mtu(Some(max_mtu), overhead()), (max_mtu, false));
+    }
+
+    #[test]
+    fn oversized_mtu_is_lowered() {
+        let max_mtu = (PHYSICAL_MTU - overhead()) as u16;
+        assert_eq!(tunnel_mtu(Some(1500), overhead()), (max_mtu, true));
+        assert_eq!(tunnel_mtu(Some(u16::MAX), overhead()), (max_mtu, true));
+    }
+
+    #[test]
+    fn excluded_ips_become_exclusion_routes() {
+        let xml = load_fixture("full_tunnel").replace(
+            "<ExcludedIPs>",
+            "<ExcludedIPs>192.168.0.0/16</ExcludedIPs><ExcludedIPs>",
+        );
+        let config = WireGuardConfig::from_str(&xml).unwrap();
+        let net = |network: &str| network.parse::<IpNetwork>().unwrap();
+
+        let (allowed_ipv4, allowed_ipv6) = peer_routes(&config.peers, |peer| &peer.allowed_ips);
+        assert_eq!(allowed_ipv4, [(0, net("0.0.0.0/0"))]);
+        assert_eq!(allowed_ipv6, [(0, net("::/0"))]);
+
+        let (excluded_ipv4, excluded_ipv6) = peer_routes(&config.peers, |peer| &peer.excluded_ips);
+        assert_eq!(
+            excluded_ipv4,
+            [(0, net("192.168.0.0/16")), (0, net("10.99.0.0/16"))]
+        );
+        assert!(excluded_ipv6.is_empty());
+    }
+
+    #[test]
+    fn routes_keep_their_peer() {
+        let config = WireGuardConfig::from_str(&load_fixture("valid_multi_peer")).unwrap();
+        let (allowed_ipv4, allowed_ipv6) = peer_routes(&config.peers, |peer| &peer.allowed_ips);
+        for (idx, ip) in allowed_ipv4.iter().chain(&allowed_ipv6) {
+            assert!(config.peers[*idx].allowed_ips.contains(ip));
+        }
+        let count: usize = config.peers.iter().map(|peer| peer.allowed_ips.len()).sum();
+        assert_eq!(allowed_ipv4.len() + allowed_ipv6.len(), count);
+    }
+
+    #[test]
+    fn packets_go_to_peer_by_destination() {
+        let config = WireGuardConfig::from_str(&load_fixture("valid_multi_peer")).unwrap();
+        let mut inner = Inner::new();
+        inner.router = peer_router(&config.peers);
+
+        let to = |dst: [u8; 4]| {
+            let mut packet = ipv4_packet(40);
+            packet[16..20].copy_from_slice(&dst);
+            inner.peer_for_packet(&packet)
+        };
+        assert_eq!(to([10, 0, 0, 1]), Some(0));
+        assert_eq!(to([10, 0, 0, 255]), Some(0));
+        assert_eq!(to([10, 1, 0, 1]), Some(1));
+        assert_eq!(to([10, 2, 0, 200]), Some(1));
+        // Anything outside every peer's AllowedIPs goes nowhere
+        assert_eq!(to([10, 3, 0, 1]), None);
+        assert_eq!(to([192, 168, 0, 1]), None);
+        assert_eq!(inner.peer_for_packet(&[]), None);
+    }
+
+    #[test]
+    fn fails_over_to_next_server() {
+        let servers = ["vpn1.example.com", "vpn2.example.com", "vpn3.example.com"];
+        let mut tried = vec![];
+        let connected = first_to_connect(&servers, 1, |server, attempt| {
+            tried.push((*server, attempt));
+            if *server == "vpn1.example.com" {
+                Err("unreachable")
+            } else {
+                Ok(attempt)
+            }
+        });
+        assert_eq!(connected, Ok((2, &"vpn2.example.com")));
+        assert_eq!(tried, [("vpn1.example.com", 1), ("vpn2.example.com", 2)]);
+    }
+
+    #[test]
+    fn repeats_servers_up_to_rounds() {
+        let servers = ["vpn1.example.com", "vpn2.example.com"];
+        let mut attempts = 0;
+        let connected = first_to_connect(&servers, 3, |server, attempt| {
+            attempts = attempt;
+            Err::<(), _>(format!("{} is down", server))
+        });
+        assert_eq!(connected, Err(Some("vpn2.example.com is down".to_owned())));
+        assert_eq!(attempts, 6);
+
+        // A later round can still get through
+        let connected = first_to_connect(&servers, 3, |_, attempt| match attempt {
+            1..=3 => Err("down"),
+            _ => Ok(()),
+        });
+        assert_eq!(connected, Ok(((), &"vpn2.example.com")));
+    }
+
+    #[test]
+    fn no_servers_to_connect_to() {
+        let servers: [&str; 0] = [];
+        assert_eq!(
+            first_to_connect(&servers, 1, |_, _| Ok::<_, ()>(())),
+            Err(None)
+        );
+        assert_eq!(
+            first_to_connect(&["vpn.example.com"], 0, |_, _| Ok::<_, ()>(())),
+            Err(None)
+        );
+    }
+
+    /// A handshake initiation from `client` to `server`.
+    fn handshake_initiation(client: &Arc<X25519SecretKey>, server: &X25519SecretKey) -> Vec<u8> {
+        let tunn = Tunn::new(
+            client.clone(),
+            Arc::new(server.public_key()),
+            None,
+            None,
+            0,
+            None,
+        )
+        .unwrap();
+        let mut dst = [0u8; PHYSICAL_MTU];
+        match tunn.format_handshake_initiation(&mut dst, false) {
+            TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+            _ => panic!("expected a handshake initiation"),
+        }
+    }
+
+    /// A config with two peers, each serving its own DNS namespace.
+    fn split_dns_config() -> WireGuardConfig {
+        let xml = load_fixture("valid_multi_peer");
+        let mut config = WireGuardConfig::from_str(&xml).unwrap();
+        config.interface.dns_servers = vec!["10.0.0.1".parse().unwrap()];
+        config.interface.search_domains = vec!["example.com".into()];
+        config.peers[0].search_domains = vec!["corp.example.com".into()];
+        config.peers[0].dns_servers = vec!["10.1.0.53".parse().unwrap()];
+        config.peers[1].search_domains = vec!["cloud.example.com".into()];
+        config
+    }
+
+    #[test]
+    fn dns_rules_per_peer() {
+        let config = split_dns_config();
+        let interface = &config.interface;
+        let rules = dns_rules(
+            &interface.dns_servers,
+            &interface.search_domains,
+            &config.peers,
+        );
+        let servers = |servers: &[&str]| {
+            servers
+                .iter()
+                .map(|server| server.parse().unwrap())
+                .collect::<Vec<IpAddr>>()
+        };
+        assert_eq!(
+            rules
+                .iter()
+                .map(|(namespace, servers)| (namespace.as_str(), servers.to_vec()))
+                .collect::<Vec<_>>(),
+            [
+                (".example.com", servers(&["10.0.0.1"])),
+                (".corp.example.com", servers(&["10.1.0.53"])),
+                // Without DNS servers of its own the peer's domains go to the interface's
+                (".cloud.example.com", servers(&["10.0.0.1"])),
+                (".", servers(&["10.0.0.1"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn dns_rules_without_dns_servers() {
+        let mut config = split_dns_config();
+        config.interface.dns_servers.clear();
+        // The same domain served the same way by two peers only needs the one rule
+        config.peers[1].search_domains = vec!["corp.example.com".into()];
+        config.peers[1].dns_servers = config.peers[0].dns_servers.clone();
+
+        let interface = &config.interface;
+        let rules = dns_rules(
+            &interface.dns_servers,
+            &interface.search_domains,
+            &config.peers,
+        );
+        let namespaces = rules
+            .iter()
+            .map(|(namespace, _)| namespace.as_str())
+            .collect::<Vec<_>>();
+        // No catch-all rule without servers to send everything else to
+        assert_eq!(namespaces, [".example.com", ".corp.example.com"]);
+    }
+
+    #[test]
+    fn sessions_keep_latest_index_per_peer() {
+        let inner = Inner::new();
+        inner.note_session(0x100, 0);
+        inner.note_session(0x200, 1);
+        assert_eq!(inner.peer_by_receiver_index(0x100), Some(0));
+        assert_eq!(inner.peer_by_receiver_index(0x200), Some(1));
+
+        // Rekeying replaces the peer's old index rather than piling up another one
+        for idx in 0x101..0x200 {
+            inner.note_session(idx, 0);
+        }
+        assert_eq!(inner.peer_by_receiver_index(0x100), None);
+        assert_eq!(inner.peer_by_receiver_index(0x1ff), Some(0));
+        assert_eq!(inner.peer_by_receiver_index(0x200), Some(1));
+        assert_eq!(inner.perf_sample().active_sessions, 2);
+    }
+
+    /// Two tunnels for either end of a session, once they've completed a handshake.
+    fn established_tunnels() -> (Box<Tunn>, Box<Tunn>) {
+        let (client_key, server_key) = (X25519SecretKey::new(), X25519SecretKey::new());
+        let client_public = Arc::new(client_key.public_key());
+        let server_public = Arc::new(server_key.public_key());
+        let client = Tunn::new(Arc::new(client_key), server_public, None, None, 0, None).unwrap();
+        let server = Tunn::new(Arc::new(server_key), client_public, None, None, 1, None).unwrap();
+
+        let mut buf = [0u8; PHYSICAL_MTU];
+        let mut reply = [0u8; PHYSICAL_MTU];
+        let initiation = match client.format_handshake_initiation(&mut buf, false) {
+            TunnResult::WriteToNetwork(packet) => packet,
+            _ => panic!("expected a handshake initiation"),
+        };
+        let response = match server.decapsulate(None, initiation, &mut reply) {
+            TunnResult::WriteToNetwork(packet) => packet,
+            _ => panic!("expected a handshake response"),
+        };
+        // The client answers the response with a keepalive to confirm the session
+        let mut keepalive = [0u8; PHYSICAL_MTU];
+        let keepalive = match client.decapsulate(None, response, &mut keepalive) {
+            TunnResult::WriteToNetwork(packet) => packet,
+            _ => panic!("expected a keepalive"),
+        };
+        assert!(matches!(
+            server.decapsulate(None, keepalive, &mut buf),
+            TunnResult::Done
+        ));
+        (client, server)
+    }
+
+    #[test]
+    fn decapsulating_fits_scratch_buffer() {
+        let (client, server) = established_tunnels();
+        let mut scratch = [0u8; PHYSICAL_MTU];
+
+        // A data packet as big as the tunnel's MTU allows comes out of a scratch buffer sized
+        // for the physical link
+        let mtu = tunnel_mtu(None, overhead()).0;
+        let packet = ipv4_packet(usize::from(mtu));
+        let mut buf = [0u8; PHYSICAL_MTU];
+        let datagram = match client.encapsulate(&packet, &mut buf) {
+            TunnResult::WriteToNetwork(datagram) => datagram.to_vec(),
+            _ => panic!("expected a data packet"),
+        };
+        match server.decapsulate(None, &datagram, &mut scratch) {
+            TunnResult::WriteToTunnelV4(decapsulated, _) => assert_eq!(decapsulated, &packet[..]),
+            _ => panic!("expected the packet back"),
+        }
+    }
+
+    #[test]
+    fn done_results_take_no_buffers() {
+        let (client, server) = established_tunnels();
+        let taken = std::cell::Cell::new(0);
+        let take = |_: &[u8]| {
+            taken.set(taken.get() + 1);
+            Ok(())
+        };
+
+        let mut scratch = [0u8; PHYSICAL_MTU];
+        for _ in 0..1000 {
+            // An empty packet is a keepalive, there's nothing for us in one
+            let mut buf = [0u8; PHYSICAL_MTU];
+            let keepalive = match client.encapsulate(&[], &mut buf) {
+                TunnResult::WriteToNetwork(keepalive) => keepalive,
+                _ => panic!("expected a keepalive"),
+            };
+            let res = server.decapsulate(None, keepalive, &mut scratch);
+            assert!(!dispatch_packet(res, take, take).unwrap());
+        }
+        assert_eq!(taken.get(), 0);
+
+        // Whereas a reply to go out does take one
+        let mut buf = [0u8; PHYSICAL_MTU];
+        let res = client.format_handshake_initiation(&mut buf, true);
+        assert!(dispatch_packet(res, take, take).unwrap());
+        assert_eq!(taken.get(), 1);
+    }
+
+    #[test]
+    fn packets_take_buffers_for_their_direction() {
+        let (client, server) = established_tunnels();
+        let (to_network, to_tunnel) = (std::cell::Cell::new(0), std::cell::Cell::new(0));
+        let send = |_: &[u8]| {
+            to_network.set(to_network.get() + 1);
+            Ok(())
+        };
+        let receive = |_: &[u8]| {
+            to_tunnel.set(to_tunnel.get() + 1);
+            Ok(())
+        };
+        let mut scratch = [0u8; PHYSICAL_MTU];
+
+        // Data from the peer is headed into the tunnel so it takes a receive buffer
+        let packet = ipv4_packet(100);
+        let mut buf = [0u8; PHYSICAL_MTU];
+        let datagram = match client.encapsulate(&packet, &mut buf) {
+            TunnResult::WriteToNetwork(datagram) => datagram.to_vec(),
+            _ => panic!("expected a data packet"),
+        };
+        let res = server.decapsulate(None, &datagram, &mut scratch);
+        assert!(dispatch_packet(res, send, receive).unwrap());
+        assert_eq!((to_network.get(), to_tunnel.get()), (0, 1));
+
+        // Whereas a handshake response goes back out to the peer in a send buffer
+        let initiation = match client.format_handshake_initiation(&mut buf, true) {
+            TunnResult::WriteToNetwork(initiation) => initiation.to_vec(),
+            _ => panic!("expected a handshake initiation"),
+        };
+        let res = server.decapsulate(None, &initiation, &mut scratch);
+        assert!(dispatch_packet(res, send, receive).unwrap());
+        assert_eq!((to_network.get(), to_tunnel.get()), (1, 1));
+    }
+
+    /// An IPv4 packet of `len` bytes, with just enough of a header for boringtun.
+    fn ipv4_packet(len: usize) -> Vec<u8> {
+        let mut packet = vec![0u8; len];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        packet[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        packet[16..20].copy_from_slice(&[10, 0, 0, 1]);
+        packet
+    }
+
+    #[test]
+    fn rate_limit_is_shared_between_peers() {
+        let server_key = Arc::new(X25519SecretKey::new());
+        let limiter = new_rate_limiter(&server_key, 1);
+        let src = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        let mut dst = [0u8; PHYSICAL_MTU];
+
+        // Each peer gets its own tunnel, all of them sharing the one limiter
+        let peers = (0..2)
+            .map(|idx| {
+                let client_key = Arc::new(X25519SecretKey::new());
+                let tunn = Tunn::new(
+                    server_key.clone(),
+                    Arc::new(client_key.public_key()),
+                    None,
+                    None,
+                    idx,
+                    Some(limiter.clone()),
+                )
+                .unwrap();
+                (client_key, tunn)
+            })
+            .collect::<Vec<_>>();
+
+        // Message type 2 is a handshake response and type 3 a cookie reply
+        let mut reply_type = |(client_key, tunn): &(Arc<X25519SecretKey>, Box<Tunn>)| {
+            let initiation = handshake_initiation(client_key, &server_key);
+            match tunn.decapsulate(src, &initiation, &mut dst) {
+                TunnResult::WriteToNetwork(packet) => packet[0],
+                _ => panic!("expected a reply"),
+            }
+        };
+        assert_eq!(reply_type(&peers[0]), 2);
+        assert_eq!(reply_type(&peers[1]), 3);
+
+        // The plugin resets the count as each frame comes in, boringtun doesn't with a limiter
+        // of ours. That only takes once a second has gone by since the last reset, so doing it
+        // for every frame doesn't let either peer through any sooner.
+        for peer in &peers {
+            limiter.reset_count();
+            assert_eq!(reply_type(peer), 3);
+        }
     }
 }
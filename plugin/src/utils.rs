@@ -1,28 +1,96 @@
 //! Utilities and helper types that don't quite fit anywhere else.
 
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::io;
+use std::net::IpAddr;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicI64, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use windows::{
     self as Windows,
     core::*,
-    Foundation::Collections::{IIterable, IIterator, IVector, IVectorView},
+    Foundation::Collections::{
+        CollectionChange, IIterable, IIterator, IObservableVector, IVector,
+        IVectorChangedEventArgs, IVectorView, VectorChangedEventHandler,
+    },
+    Foundation::{DateTime, EventRegistrationToken},
     Networking::Vpn::VpnPacketBuffer,
-    Win32::Foundation::{E_BOUNDS, E_NOTIMPL},
+    Storage::Streams::{DataReader, IBuffer, IRandomAccessStream, InputStreamOptions},
+    Win32::Foundation::{E_BOUNDS, E_POINTER},
     Win32::System::WinRT::IBufferByteAccess,
 };
 
-/// A simple wrapper around `Vec` which implements the `IVector`, `IVectorView` and
-/// `IIterable` interfaces.
+pub mod handshake_debug;
+pub mod radix_trie;
+pub mod timed_lru;
+
+/// A simple wrapper around `Vec` which implements the `IVector`, `IVectorView`,
+/// `IObservableVector` and `IIterable` interfaces.
+///
+/// The `Vec` sits behind a lock so that it may be modified through any of the `IVector`
+/// methods, from whichever thread. Any `VectorChanged` handlers are called after each
+/// modification, once the lock has been released.
 #[implement(
     Windows::Foundation::Collections::IIterable<T>,
     Windows::Foundation::Collections::IVector<T>,
-    Windows::Foundation::Collections::IVectorView<T>
+    Windows::Foundation::Collections::IVectorView<T>,
+    Windows::Foundation::Collections::IObservableVector<T>
 )]
-pub struct Vector<T: RuntimeType + 'static>(Vec<T::DefaultType>);
+pub struct Vector<T: RuntimeType + 'static> {
+    items: Mutex<Vec<T::DefaultType>>,
+    /// The registered `VectorChanged` handlers along with their tokens
+    handlers: Mutex<Vec<(i64, VectorChangedEventHandler<T>)>>,
+    next_token: AtomicI64,
+}
 
 impl<T: RuntimeType + 'static> Vector<T> {
     pub fn new(v: Vec<T::DefaultType>) -> Vector<T> {
-        Vector(v)
+        Vector {
+            items: Mutex::new(v),
+            handlers: Mutex::new(vec![]),
+            next_token: AtomicI64::new(1),
+        }
+    }
+
+    /// Let any `VectorChanged` handlers know about a modification.
+    fn notify(&self, change: CollectionChange, index: u32) -> Result<()> {
+        // Don't hold the lock while calling out, the handlers may well (un)register themselves
+        let handlers = self
+            .handlers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, handler)| handler.clone())
+            .collect::<Vec<_>>();
+        if handlers.is_empty() {
+            return Ok(());
+        }
+
+        let sender: IObservableVector<T> = self.cast()?;
+        let args: IVectorChangedEventArgs = VectorChangedEventArgs { change, index }.into();
+        for handler in handlers {
+            handler.Invoke(&sender, &args)?;
+        }
+        Ok(())
+    }
+
+    fn VectorChanged(
+        &self,
+        handler: &Option<VectorChangedEventHandler<T>>,
+    ) -> Result<EventRegistrationToken> {
+        let handler = handler.as_ref().ok_or(Error::from(E_POINTER))?;
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        self.handlers.lock().unwrap().push((token, handler.clone()));
+        Ok(EventRegistrationToken { Value: token })
+    }
+
+    fn RemoveVectorChanged(&self, token: &EventRegistrationToken) -> Result<()> {
+        self.handlers
+            .lock()
+            .unwrap()
+            .retain(|(t, _)| *t != token.Value);
+        Ok(())
     }
 
     fn First(&self) -> Result<IIterator<T>> {
@@ -38,7 +106,9 @@ impl<T: RuntimeType + 'static> Vector<T> {
     }
 
     fn GetAt(&self, index: u32) -> Result<T> {
-        self.0
+        self.items
+            .lock()
+            .unwrap()
             .get(index as usize)
             // SAFETY: `DefaultType` is a super trait of `RuntimeType`.
             .map(|el| unsafe { DefaultType::from_default(el) })
@@ -47,11 +117,11 @@ impl<T: RuntimeType + 'static> Vector<T> {
     }
 
     fn Size(&self) -> Result<u32> {
-        u32::try_from(self.0.len()).map_err(|_| Error::from(E_BOUNDS))
+        u32::try_from(self.items.lock().unwrap().len()).map_err(|_| Error::from(E_BOUNDS))
     }
 
     fn IndexOf(&self, value: &T::DefaultType, index: &mut u32) -> Result<bool> {
-        if let Some(idx) = self.0.iter().position(|el| el == value) {
+        if let Some(idx) = self.items.lock().unwrap().iter().position(|el| el == value) {
             *index = u32::try_from(idx).map_err(|_| Error::from(E_BOUNDS))?;
             Ok(true)
         } else {
@@ -60,46 +130,96 @@ impl<T: RuntimeType + 'static> Vector<T> {
     }
 
     fn GetMany(&self, start: u32, items: &mut [T::DefaultType]) -> Result<u32> {
-        let sz = u32::try_from(self.0.len()).map_err(|_| Error::from(E_BOUNDS))?;
+        let vec = self.items.lock().unwrap();
+        let sz = u32::try_from(vec.len()).map_err(|_| Error::from(E_BOUNDS))?;
 
         if start >= sz {
             return Err(Error::from(E_BOUNDS));
         }
 
         let mut count = 0;
-        for (item, el) in items.into_iter().zip(self.0[start as usize..].iter()) {
+        for (item, el) in items.iter_mut().zip(vec[start as usize..].iter()) {
             *item = el.clone();
             count += 1;
         }
         Ok(count)
     }
 
-    fn SetAt(&self, _index: u32, _value: &T::DefaultType) -> Result<()> {
-        Err(E_NOTIMPL.into())
+    fn SetAt(&self, index: u32, value: &T::DefaultType) -> Result<()> {
+        {
+            let mut items = self.items.lock().unwrap();
+            let el = items.get_mut(index as usize).ok_or(Error::from(E_BOUNDS))?;
+            *el = value.clone();
+        }
+        self.notify(CollectionChange::ItemChanged, index)
     }
 
-    fn InsertAt(&self, _index: u32, _value: &T::DefaultType) -> Result<()> {
-        Err(E_NOTIMPL.into())
+    fn InsertAt(&self, index: u32, value: &T::DefaultType) -> Result<()> {
+        {
+            let mut items = self.items.lock().unwrap();
+            // Inserting right at the end is fine
+            if index as usize > items.len() {
+                return Err(Error::from(E_BOUNDS));
+            }
+            items.insert(index as usize, value.clone());
+        }
+        self.notify(CollectionChange::ItemInserted, index)
     }
 
-    fn RemoveAt(&self, _index: u32) -> Result<()> {
-        Err(E_NOTIMPL.into())
+    fn RemoveAt(&self, index: u32) -> Result<()> {
+        {
+            let mut items = self.items.lock().unwrap();
+            if index as usize >= items.len() {
+                return Err(Error::from(E_BOUNDS));
+            }
+            items.remove(index as usize);
+        }
+        self.notify(CollectionChange::ItemRemoved, index)
     }
 
-    fn Append(&self, _value: &T::DefaultType) -> Result<()> {
-        Err(E_NOTIMPL.into())
+    fn Append(&self, value: &T::DefaultType) -> Result<()> {
+        let index = {
+            let mut items = self.items.lock().unwrap();
+            items.push(value.clone());
+            items.len() - 1
+        };
+        self.notify(CollectionChange::ItemInserted, index as u32)
     }
 
     fn RemoveAtEnd(&self) -> Result<()> {
-        Err(E_NOTIMPL.into())
+        let index = {
+            let mut items = self.items.lock().unwrap();
+            items.pop().ok_or(Error::from(E_BOUNDS))?;
+            items.len()
+        };
+        self.notify(CollectionChange::ItemRemoved, index as u32)
     }
 
     fn Clear(&self) -> Result<()> {
-        Err(E_NOTIMPL.into())
+        self.items.lock().unwrap().clear();
+        self.notify(CollectionChange::Reset, 0)
     }
 
-    fn ReplaceAll(&self, _values: &[T::DefaultType]) -> Result<()> {
-        Err(E_NOTIMPL.into())
+    fn ReplaceAll(&self, values: &[T::DefaultType]) -> Result<()> {
+        *self.items.lock().unwrap() = values.to_vec();
+        self.notify(CollectionChange::Reset, 0)
+    }
+}
+
+/// The details of a `VectorChanged` event raised by `Vector`.
+#[implement(Windows::Foundation::Collections::IVectorChangedEventArgs)]
+struct VectorChangedEventArgs {
+    change: CollectionChange,
+    index: u32,
+}
+
+impl VectorChangedEventArgs {
+    fn CollectionChange(&self) -> Result<CollectionChange> {
+        Ok(self.change)
+    }
+
+    fn Index(&self) -> Result<u32> {
+        Ok(self.index)
     }
 }
 
@@ -116,6 +236,14 @@ impl<'a, T: RuntimeType + 'static> IntoParam<'a, IVector<T>> for Vector<T> {
 }
 
 /// `IIterator` wrapper for `Vector`
+///
+/// Thread-safety: each access to the underlying `Vector` goes through its lock so reading
+/// through it from any thread is fine, though it may change between calls. The position is
+/// advanced atomically so concurrent `MoveNext` calls will each step over a distinct element,
+/// never skipping or repeating one. However, a `MoveNext` followed by `Current` is not atomic
+/// as a pair; if multiple threads share one iterator, `Current` may observe another thread's
+/// position. The same goes for `GetMany`, which reads from the current position before
+/// advancing past what it returned.
 #[implement(Windows::Foundation::Collections::IIterator<T>)]
 struct VectorIterator<T: RuntimeType + 'static> {
     /// The underlying object we're iteratoring over
@@ -134,23 +262,63 @@ impl<T: RuntimeType + 'static> VectorIterator<T> {
     fn HasCurrent(&self) -> Result<bool> {
         // SAFETY: We know this must be our `Vector` type
         let vec = unsafe { Vector::to_impl(&self.it) };
-        Ok(vec.0.len() > self.curr.load(Ordering::Relaxed) as usize)
+        Ok(vec.items.lock().unwrap().len() > self.curr.load(Ordering::Relaxed) as usize)
     }
 
     fn MoveNext(&self) -> Result<bool> {
         // SAFETY: We know this must be our `Vector` type
         let vec = unsafe { Vector::to_impl(&self.it) };
-        let old = self.curr.fetch_add(1, Ordering::Relaxed) as usize;
-        Ok(vec.0.len() > old + 1)
+        // Stay put rather than wrap back around to the start once we hit `u32::MAX`
+        let next = |curr: u32| curr.checked_add(1);
+        let old = self
+            .curr
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, next);
+        let old = match old {
+            Ok(old) => old as usize,
+            Err(_) => return Ok(false),
+        };
+        Ok(vec.items.lock().unwrap().len() > old + 1)
     }
 
     fn GetMany(&self, items: &mut [T::DefaultType]) -> Result<u32> {
         // SAFETY: We know this must be our `Vector` type
         let vec = unsafe { Vector::to_impl(&self.it) };
-        vec.GetMany(0, items)
+
+        // Pick up from wherever the iterator currently is, nothing's left once we're past the end
+        let start = self.curr.load(Ordering::Relaxed);
+        if start as usize >= vec.items.lock().unwrap().len() {
+            return Ok(0);
+        }
+
+        let count = vec.GetMany(start, items)?;
+        self.curr.fetch_add(count, Ordering::Relaxed);
+        Ok(count)
+    }
+}
+
+/// Seconds between the WinRT `DateTime` epoch (1601-01-01) and the Unix epoch.
+const DATETIME_UNIX_EPOCH_SECS: u64 = 11_644_473_600;
+
+/// Convert a `SystemTime` into a WinRT `DateTime` (100ns ticks since 1601-01-01 UTC).
+pub fn to_datetime(time: SystemTime) -> DateTime {
+    let since_unix = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let ticks = (since_unix + Duration::from_secs(DATETIME_UNIX_EPOCH_SECS)).as_nanos() / 100;
+    DateTime {
+        UniversalTime: ticks as i64,
     }
 }
 
+/// Convert a WinRT `DateTime` back into a `SystemTime`.
+///
+/// Times before the Unix epoch are clamped to it.
+pub fn from_datetime(time: DateTime) -> SystemTime {
+    let since_1601 = Duration::from_nanos(time.UniversalTime.max(0) as u64 * 100);
+    UNIX_EPOCH
+        + since_1601
+            .checked_sub(Duration::from_secs(DATETIME_UNIX_EPOCH_SECS))
+            .unwrap_or_default()
+}
+
 pub trait IBufferExt {
     /// Get a slice to an `IBuffer`'s underlying buffer.
     ///
@@ -164,30 +332,417 @@ pub trait IBufferExt {
     /// TODO: Is this safe?
     ///       For `VpnPacketBuffer` at least, the buffer should be initialized & zeroed.
     fn get_buf_mut(&mut self) -> Result<&mut [u8]>;
+
+    /// Copy an `IBuffer`'s contents out into a new `Vec`.
+    ///
+    /// Unlike `get_buf`, the result isn't tied to the borrow of the buffer so it can be moved
+    /// into closures and the like. That costs an allocation and a copy though, so stick to
+    /// `get_buf` anywhere performance matters (e.g. the encap/decap path).
+    fn get_buf_copy(&self) -> Result<Vec<u8>> {
+        Ok(self.get_buf()?.to_vec())
+    }
+
+    /// Copy `data` into the start of an `IBuffer`, setting its Length to match.
+    ///
+    /// Fails with `E_BOUNDS` if `data` doesn't fit within the buffer's Capacity.
+    /// Returns the new length.
+    fn write_buf(&mut self, data: &[u8]) -> Result<u32>;
+}
+
+impl IBufferExt for IBuffer {
+    fn get_buf(&self) -> Result<&[u8]> {
+        let len = self.Length()?;
+        // SAFETY: The memory belongs to the buffer which outlives the borrow of `self`.
+        Ok(unsafe { raw_buffer(self, len)? })
+    }
+
+    fn get_buf_mut(&mut self) -> Result<&mut [u8]> {
+        let cap = self.Capacity()?;
+        // SAFETY: The memory belongs to the buffer which outlives the borrow of `self`.
+        unsafe { raw_buffer(self, cap) }
+    }
+
+    fn write_buf(&mut self, data: &[u8]) -> Result<u32> {
+        let new_len = copy_into(self.get_buf_mut()?, data)?;
+        self.SetLength(new_len)?;
+        Ok(new_len)
+    }
 }
 
 impl IBufferExt for VpnPacketBuffer {
     fn get_buf(&self) -> Result<&[u8]> {
-        let buffer = self.Buffer()?;
+        let buffer = self.Buffer()?.cast::<IBuffer>()?;
         let len = buffer.Length()?;
-        let rawBuffer = buffer.cast::<IBufferByteAccess>()?;
-        Ok(unsafe {
-            // SAFETY: Any type that implements `IBuffer` must also implement `IBufferByteAccess`
-            // to return the buffer as an array of bytes.
-            std::slice::from_raw_parts(rawBuffer.Buffer()?, len as usize)
-        })
+        // SAFETY: The `Buffer` we get back is only a new reference to the memory owned by
+        // the `VpnPacketBuffer` itself, which outlives the borrow of `self`.
+        Ok(unsafe { raw_buffer(&buffer, len)? })
     }
 
     fn get_buf_mut(&mut self) -> Result<&mut [u8]> {
-        let buffer = self.Buffer()?;
+        let buffer = self.Buffer()?.cast::<IBuffer>()?;
         let cap = buffer.Capacity()?;
-        let rawBuffer = buffer.cast::<IBufferByteAccess>()?;
-        Ok(unsafe {
-            // SAFETY: Any type that implements `IBuffer` must also implement `IBufferByteAccess`
-            // to return the buffer as an array of bytes.
-            std::slice::from_raw_parts_mut(rawBuffer.Buffer()?, cap as usize)
+        // SAFETY: As above.
+        unsafe { raw_buffer(&buffer, cap) }
+    }
+
+    fn write_buf(&mut self, data: &[u8]) -> Result<u32> {
+        let new_len = copy_into(self.get_buf_mut()?, data)?;
+        self.Buffer()?.SetLength(new_len)?;
+        Ok(new_len)
+    }
+}
+
+/// Copy `data` into the start of `dst`, returning the number of bytes copied.
+fn copy_into(dst: &mut [u8], data: &[u8]) -> Result<u32> {
+    if dst.len() < data.len() {
+        return Err(Error::from(E_BOUNDS));
+    }
+    dst[..data.len()].copy_from_slice(data);
+    u32::try_from(data.len()).map_err(|_| Error::from(E_BOUNDS))
+}
+
+/// Get a slice of the first `len` bytes of an `IBuffer`'s underlying memory.
+///
+/// SAFETY: The caller picks the lifetime of the slice and so must make sure the underlying
+/// memory lives at least that long. `len` must not exceed the buffer's capacity.
+unsafe fn raw_buffer<'a>(buffer: &IBuffer, len: u32) -> Result<&'a mut [u8]> {
+    // Any type that implements `IBuffer` must also implement `IBufferByteAccess`
+    // to return the buffer as an array of bytes.
+    let rawBuffer = buffer.cast::<IBufferByteAccess>()?;
+    Ok(std::slice::from_raw_parts_mut(
+        rawBuffer.Buffer()?,
+        len as usize,
+    ))
+}
+
+/// Adapts a WinRT `IRandomAccessStream` to `std::io::Read`.
+///
+/// NOTE: Reads block on the underlying async operations so avoid using this on the UI thread.
+pub struct IRandomAccessStreamReader(DataReader);
+
+impl IRandomAccessStreamReader {
+    /// Create a reader starting from the beginning of the given stream.
+    pub fn new(stream: &IRandomAccessStream) -> Result<Self> {
+        let reader = DataReader::CreateDataReader(stream.GetInputStreamAt(0)?)?;
+        // Don't wait for the full requested amount if less is available
+        reader.SetInputStreamOptions(InputStreamOptions::Partial)?;
+        Ok(Self(reader))
+    }
+}
+
+impl io::Read for IRandomAccessStreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let to_io_err = |e: Error| io::Error::other(e);
+
+        // Only go back to the stream once we've consumed everything already loaded
+        let mut available = self.0.UnconsumedBufferLength().map_err(to_io_err)?;
+        if available == 0 {
+            let count = u32::try_from(buf.len()).unwrap_or(u32::MAX);
+            available = self
+                .0
+                .LoadAsync(count)
+                .map_err(to_io_err)?
+                .get()
+                .map_err(to_io_err)?;
+        }
+
+        // `available` will be 0 here if we've hit the end of the stream
+        let len = buf.len().min(available as usize);
+        self.0.ReadBytes(&mut buf[..len]).map_err(to_io_err)?;
+        Ok(len)
+    }
+}
+
+/// Adapts a `VpnPacketBuffer` to `std::io::Write`, appending from the start of the buffer.
+///
+/// The WinRT side is only told how much was written (via `SetLength`) on `flush`.
+pub struct WinRtBufWriter<'a> {
+    buf: &'a mut VpnPacketBuffer,
+    pos: usize,
+}
+
+impl<'a> WinRtBufWriter<'a> {
+    pub fn new(buf: &'a mut VpnPacketBuffer) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// The number of bytes written so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl io::Write for WinRtBufWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let to_io_err = |e: Error| io::Error::other(e);
+
+        // Writes are limited by the buffer's capacity, once full we just write nothing
+        let dst = &mut self.buf.get_buf_mut().map_err(to_io_err)?[self.pos..];
+        let len = dst.len().min(data.len());
+        dst[..len].copy_from_slice(&data[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let to_io_err = |e: Error| io::Error::other(e);
+
+        // `pos` can never exceed the buffer's u32 capacity
+        self.buf
+            .Buffer()
+            .and_then(|buffer| buffer.SetLength(self.pos as u32))
+            .map_err(to_io_err)
+    }
+}
+
+/// A pool of `VpnPacketBuffer`s allocated upfront so we needn't ask the platform for a fresh
+/// one for every packet.
+///
+/// Buffers handed off to the platform are gone for good but any we end up not needing are
+/// put back into the pool when dropped.
+pub struct BufferPool {
+    buffers: Mutex<Vec<VpnPacketBuffer>>,
+    capacity: usize,
+}
+
+impl BufferPool {
+    /// Create an empty pool that will hold on to at most `capacity` buffers.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Top up the pool to its capacity with buffers from `alloc`.
+    pub fn fill(&self, mut alloc: impl FnMut() -> Result<VpnPacketBuffer>) -> Result<()> {
+        let mut buffers = self.buffers.lock().unwrap();
+        while buffers.len() < self.capacity {
+            buffers.push(alloc()?);
+        }
+        Ok(())
+    }
+
+    /// Take a buffer from the pool, if there are any left.
+    pub fn try_acquire(&self) -> Option<PooledBuffer<'_>> {
+        let buffer = self.buffers.lock().unwrap().pop()?;
+        Some(PooledBuffer {
+            pool: self,
+            buffer: Some(buffer),
         })
     }
+
+    /// Take a buffer from the pool or fall back to `alloc` if it's been exhausted.
+    pub fn acquire_or_else(
+        &self,
+        alloc: impl FnOnce() -> Result<VpnPacketBuffer>,
+    ) -> Result<PooledBuffer<'_>> {
+        match self.try_acquire() {
+            Some(buffer) => Ok(buffer),
+            None => Ok(PooledBuffer {
+                pool: self,
+                buffer: Some(alloc()?),
+            }),
+        }
+    }
+
+    /// Put an unused buffer back, unless we're already full.
+    fn release(&self, buffer: VpnPacketBuffer) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.capacity {
+            buffers.push(buffer);
+        }
+    }
+}
+
+/// A `VpnPacketBuffer` borrowed from a `BufferPool`.
+///
+/// Use `into_inner` to keep the buffer (e.g. to hand it off to the platform), otherwise
+/// it's returned to the pool on drop.
+pub struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buffer: Option<VpnPacketBuffer>,
+}
+
+impl PooledBuffer<'_> {
+    /// Take the buffer out of the pool for good.
+    pub fn into_inner(mut self) -> VpnPacketBuffer {
+        self.buffer.take().unwrap()
+    }
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = VpnPacketBuffer;
+
+    fn deref(&self) -> &Self::Target {
+        self.buffer.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.buffer.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            self.pool.release(buffer);
+        }
+    }
+}
+
+/// Describe common networking failures in a more human-friendly way.
+///
+/// Winsock & WinHTTP errors usually make their way to us wrapped as HRESULTs
+/// (i.e. `HRESULT_FROM_WIN32(WSAECONNREFUSED)` = `0x8007274D`).
+pub fn hresult_to_net_error(hr: HRESULT) -> Option<&'static str> {
+    Some(match hr.0 {
+        // Winsock errors (WSABASEERR = 10000)
+        0x8007271D => "permission denied (WSAEACCES)",
+        0x80072740 => "address already in use (WSAEADDRINUSE)",
+        0x80072741 => "address not available (WSAEADDRNOTAVAIL)",
+        0x80072742 => "network is down (WSAENETDOWN)",
+        0x80072743 => "network is unreachable (WSAENETUNREACH)",
+        0x80072744 => "network dropped connection on reset (WSAENETRESET)",
+        0x80072745 => "connection aborted (WSAECONNABORTED)",
+        0x80072746 => "connection reset by peer (WSAECONNRESET)",
+        0x80072747 => "no buffer space available (WSAENOBUFS)",
+        0x80072749 => "socket is not connected (WSAENOTCONN)",
+        0x8007274C => "connection timed out (WSAETIMEDOUT)",
+        0x8007274D => "connection refused (WSAECONNREFUSED)",
+        0x80072750 => "host is down (WSAEHOSTDOWN)",
+        0x80072751 => "no route to host (WSAEHOSTUNREACH)",
+        0x80072AF9 => "host not found (WSAHOST_NOT_FOUND)",
+        0x80072AFA => "temporary failure in name resolution (WSATRY_AGAIN)",
+        0x80072AFC => "no address found for host (WSANO_DATA)",
+
+        // WinHTTP errors (WINHTTP_ERROR_BASE = 12000)
+        0x80072EE2 => "operation timed out (ERROR_WINHTTP_TIMEOUT)",
+        0x80072EE7 => "name not resolved (ERROR_WINHTTP_NAME_NOT_RESOLVED)",
+        0x80072EFD => "cannot connect (ERROR_WINHTTP_CANNOT_CONNECT)",
+        0x80072EFE => "connection error (ERROR_WINHTTP_CONNECTION_ERROR)",
+
+        _ => return None,
+    })
+}
+
+/// Extract the destination address from the header of an L3 IP packet.
+///
+/// Returns `None` if the packet is too short or isn't an IPv4 or IPv6 packet.
+pub fn packet_dst_addr(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => {
+            let addr: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            Some(addr.into())
+        }
+        6 => {
+            let addr: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            Some(addr.into())
+        }
+        _ => None,
+    }
+}
+
+/// Extract the source address from the header of an L3 IP packet.
+///
+/// Returns `None` if the packet is too short or isn't an IPv4 or IPv6 packet.
+pub fn packet_src_addr(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => {
+            let addr: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            Some(addr.into())
+        }
+        6 => {
+            let addr: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            Some(addr.into())
+        }
+        _ => None,
+    }
+}
+
+/// Clamp the MSS option of a TCP SYN (or SYN-ACK) in an L3 IP packet to at most `max_mss`,
+/// fixing up the TCP checksum to match.
+///
+/// `max_mss` is what fits in an IPv4 packet; IPv6 packets are clamped a further 20 bytes to
+/// account for the larger header. IPv6 extension headers and IPv4 fragments aren't looked into.
+/// Returns whether the packet was modified.
+pub fn tcp_mss_clamp(pkt: &mut [u8], max_mss: u16) -> bool {
+    const TCP: u8 = 6;
+    const SYN: u8 = 0x02;
+    const MSS_OPTION: u8 = 2;
+
+    let (tcp_start, max_mss) = match pkt.first().map(|b| b >> 4) {
+        Some(4) if pkt.len() >= 20 => {
+            let is_first_fragment = pkt[6] & 0x1F == 0 && pkt[7] == 0;
+            if pkt[9] != TCP || !is_first_fragment {
+                return false;
+            }
+            (usize::from(pkt[0] & 0x0F) * 4, max_mss)
+        }
+        Some(6) if pkt.len() >= 40 => {
+            if pkt[6] != TCP {
+                return false;
+            }
+            (40, max_mss.saturating_sub(20))
+        }
+        _ => return false,
+    };
+
+    let tcp = match pkt.get_mut(tcp_start..) {
+        Some(tcp) if tcp.len() >= 20 && tcp[13] & SYN != 0 => tcp,
+        _ => return false,
+    };
+    let options_end = (usize::from(tcp[12] >> 4) * 4).min(tcp.len());
+
+    // Walk the options looking for the MSS
+    let mut pos = 20;
+    while pos < options_end {
+        match tcp[pos] {
+            // End of options
+            0 => break,
+            // No-op
+            1 => pos += 1,
+            kind => {
+                let len = match tcp.get(pos + 1) {
+                    Some(&len) if len >= 2 => usize::from(len),
+                    _ => return false,
+                };
+                if kind == MSS_OPTION && len == 4 && pos + 4 <= options_end {
+                    let mss = u16::from_be_bytes([tcp[pos + 2], tcp[pos + 3]]);
+                    if mss <= max_mss {
+                        return false;
+                    }
+                    tcp[pos + 2..pos + 4].copy_from_slice(&max_mss.to_be_bytes());
+
+                    // A field at an odd offset straddles two of the checksum's 16-bit words,
+                    // which works out the same as if it were byte swapped (RFC 1071)
+                    let (old, new) = if pos % 2 == 0 {
+                        (mss, max_mss)
+                    } else {
+                        (mss.swap_bytes(), max_mss.swap_bytes())
+                    };
+                    let checksum = u16::from_be_bytes([tcp[16], tcp[17]]);
+                    let checksum = checksum_adjust(checksum, old, new);
+                    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+                    return true;
+                }
+                pos += len;
+            }
+        }
+    }
+
+    false
+}
+
+/// Incrementally update an internet checksum for a 16-bit word changing from `old` to `new`
+/// (RFC 1624).
+fn checksum_adjust(checksum: u16, old: u16, new: u16) -> u16 {
+    let sum = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    let sum = (sum & 0xFFFF) + (sum >> 16);
+    let sum = (sum & 0xFFFF) + (sum >> 16);
+    !(sum as u16)
 }
 
 macro_rules! debug_log {
@@ -210,3 +765,376 @@ macro_rules! debug_log {
 }
 
 pub(crate) use debug_log;
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    use super::*;
+
+    /// An iterator shared between the test's threads.
+    struct SharedIterator(IIterator<i32>);
+
+    // SAFETY: `VectorIterator` only touches its position atomically and the `Vector` through its
+    // lock, and the object's reference count is atomic.
+    unsafe impl Send for SharedIterator {}
+    unsafe impl Sync for SharedIterator {}
+
+    impl Deref for SharedIterator {
+        type Target = IIterator<i32>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn vector_iterator_concurrent_access() {
+        const THREADS: usize = 4;
+        const MOVES: usize = 25;
+
+        let vec: IIterable<i32> = Vector::new((0..100).collect()).into();
+        let it = SharedIterator(vec.First().unwrap());
+        let moved = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..MOVES {
+                        if it.MoveNext().unwrap() {
+                            moved.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+
+        // Each element after the first became current exactly once and only the move off the end
+        // failed. Had two threads stepped over the same element we'd have ended up short of it.
+        assert_eq!(moved.into_inner(), THREADS * MOVES - 1);
+        assert!(!it.HasCurrent().unwrap());
+    }
+
+    #[test]
+    fn vector_iterator_get_many_from_current() {
+        let vec: IIterable<i32> = Vector::new(vec![10, 11, 12, 13, 14]).into();
+        let it = vec.First().unwrap();
+        assert!(it.MoveNext().unwrap());
+        assert!(it.MoveNext().unwrap());
+
+        let mut items = [0; 8];
+        assert_eq!(it.GetMany(&mut items).unwrap(), 3);
+        assert_eq!(items[..3], [12, 13, 14]);
+
+        // Everything's been handed out so the iterator's now past the end
+        assert!(!it.HasCurrent().unwrap());
+        assert_eq!(it.GetMany(&mut items).unwrap(), 0);
+    }
+
+    #[test]
+    fn vector_iterator_get_many_in_chunks() {
+        let vec: IIterable<i32> = Vector::new(vec![10, 11, 12, 13, 14]).into();
+        let it = vec.First().unwrap();
+
+        let mut items = [0; 2];
+        assert_eq!(it.GetMany(&mut items).unwrap(), 2);
+        assert_eq!(items, [10, 11]);
+        assert_eq!(it.GetMany(&mut items).unwrap(), 2);
+        assert_eq!(items, [12, 13]);
+        assert_eq!(it.GetMany(&mut items).unwrap(), 1);
+        assert_eq!(items[0], 14);
+    }
+
+    /// A minimal IPv4 header (no options) from `src` to `dst` carrying `payload`.
+    fn ipv4_packet(protocol: u8, src: [u8; 4], dst: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut pkt = vec![0; 20];
+        pkt[0] = 0x45;
+        pkt[2..4].copy_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+        pkt[8] = 64;
+        pkt[9] = protocol;
+        pkt[12..16].copy_from_slice(&src);
+        pkt[16..20].copy_from_slice(&dst);
+        pkt.extend_from_slice(payload);
+        pkt
+    }
+
+    /// A minimal IPv6 header (no extension headers) from `src` to `dst` carrying `payload`.
+    fn ipv6_packet(next_header: u8, src: Ipv6Addr, dst: Ipv6Addr, payload: &[u8]) -> Vec<u8> {
+        let mut pkt = vec![0; 40];
+        pkt[0] = 0x60;
+        pkt[4..6].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        pkt[6] = next_header;
+        pkt[7] = 64;
+        pkt[8..24].copy_from_slice(&src.octets());
+        pkt[24..40].copy_from_slice(&dst.octets());
+        pkt.extend_from_slice(payload);
+        pkt
+    }
+
+    #[test]
+    fn packet_dst_addr_v4() {
+        let pkt = ipv4_packet(17, [10, 0, 0, 2], [192, 168, 1, 1], &[0; 8]);
+        assert_eq!(
+            packet_dst_addr(&pkt),
+            Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)))
+        );
+    }
+
+    #[test]
+    fn packet_dst_addr_v6() {
+        let src = "fd00::2".parse().unwrap();
+        let dst = "2001:db8::1".parse().unwrap();
+        let pkt = ipv6_packet(17, src, dst, &[0; 8]);
+        assert_eq!(packet_dst_addr(&pkt), Some(IpAddr::V6(dst)));
+    }
+
+    #[test]
+    fn packet_src_addr_v4_and_v6() {
+        let pkt = ipv4_packet(17, [10, 0, 0, 2], [192, 168, 1, 1], &[0; 8]);
+        assert_eq!(
+            packet_src_addr(&pkt),
+            Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)))
+        );
+
+        let src = "fd00::2".parse().unwrap();
+        let pkt = ipv6_packet(17, src, "2001:db8::1".parse().unwrap(), &[0; 8]);
+        assert_eq!(packet_src_addr(&pkt), Some(IpAddr::V6(src)));
+    }
+
+    #[test]
+    fn packet_src_addr_rejects_bad_packets() {
+        assert_eq!(packet_src_addr(&[]), None);
+        let pkt = ipv4_packet(17, [10, 0, 0, 2], [192, 168, 1, 1], &[]);
+        assert_eq!(packet_src_addr(&pkt[..15]), None);
+        let pkt = ipv6_packet(17, Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST, &[]);
+        assert_eq!(packet_src_addr(&pkt[..23]), None);
+        // Just long enough for the source doesn't need the destination too
+        assert_eq!(
+            packet_src_addr(&pkt[..24]),
+            Some(IpAddr::V6(Ipv6Addr::LOCALHOST))
+        );
+        assert_eq!(packet_src_addr(&[0x55; 40]), None);
+    }
+
+    #[test]
+    fn packet_dst_addr_rejects_bad_packets() {
+        assert_eq!(packet_dst_addr(&[]), None);
+        // Truncated before the end of the destination address
+        let pkt = ipv4_packet(17, [10, 0, 0, 2], [192, 168, 1, 1], &[]);
+        assert_eq!(packet_dst_addr(&pkt[..19]), None);
+        let pkt = ipv6_packet(17, Ipv6Addr::LOCALHOST, Ipv6Addr::LOCALHOST, &[]);
+        assert_eq!(packet_dst_addr(&pkt[..39]), None);
+        // Not an IP version we know of
+        let mut pkt = ipv4_packet(17, [10, 0, 0, 2], [192, 168, 1, 1], &[]);
+        pkt[0] = 0x55;
+        assert_eq!(packet_dst_addr(&pkt), None);
+    }
+
+    const TCP_PROTOCOL: u8 = 6;
+    const SYN: u8 = 0x02;
+    const ACK: u8 = 0x10;
+    /// MSS 1460 (kind 2, length 4)
+    const MSS_1460: [u8; 4] = [2, 4, 0x05, 0xB4];
+
+    /// A TCP header with the given flags & options (padded out to a multiple of 4 bytes).
+    fn tcp_header(flags: u8, options: &[u8]) -> Vec<u8> {
+        let mut tcp = vec![0; 20];
+        tcp[0..2].copy_from_slice(&50000u16.to_be_bytes());
+        tcp[2..4].copy_from_slice(&443u16.to_be_bytes());
+        tcp[13] = flags;
+        tcp[14..16].copy_from_slice(&64240u16.to_be_bytes());
+        tcp.extend_from_slice(options);
+        while !tcp.len().is_multiple_of(4) {
+            tcp.push(0);
+        }
+        tcp[12] = ((tcp.len() / 4) as u8) << 4;
+        tcp
+    }
+
+    /// The one's complement sum of `data` as 16-bit big endian words, not yet folded.
+    fn sum_words(data: &[u8]) -> u32 {
+        data.chunks(2)
+            .map(|word| (u32::from(word[0]) << 8) | u32::from(*word.get(1).unwrap_or(&0)))
+            .sum()
+    }
+
+    /// Work out the TCP checksum of `pkt` from scratch, pseudo-header and all.
+    fn tcp_checksum(pkt: &[u8]) -> u16 {
+        let (addrs, tcp) = if pkt[0] >> 4 == 4 {
+            (&pkt[12..20], &pkt[usize::from(pkt[0] & 0x0F) * 4..])
+        } else {
+            (&pkt[8..40], &pkt[40..])
+        };
+        let mut sum = sum_words(addrs) + u32::from(TCP_PROTOCOL) + tcp.len() as u32;
+        sum += sum_words(&tcp[..16]) + sum_words(&tcp[18..]);
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    /// Fill in `pkt`'s TCP checksum.
+    fn with_checksum(mut pkt: Vec<u8>) -> Vec<u8> {
+        let checksum = tcp_checksum(&pkt);
+        let at = pkt.len() - tcp_len(&pkt) + 16;
+        pkt[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+        pkt
+    }
+
+    fn tcp_len(pkt: &[u8]) -> usize {
+        if pkt[0] >> 4 == 4 {
+            pkt.len() - usize::from(pkt[0] & 0x0F) * 4
+        } else {
+            pkt.len() - 40
+        }
+    }
+
+    /// The checksum as it is in `pkt`.
+    fn stored_checksum(pkt: &[u8]) -> u16 {
+        let at = pkt.len() - tcp_len(pkt) + 16;
+        u16::from_be_bytes([pkt[at], pkt[at + 1]])
+    }
+
+    fn syn_v4(options: &[u8]) -> Vec<u8> {
+        let tcp = tcp_header(SYN, options);
+        with_checksum(ipv4_packet(
+            TCP_PROTOCOL,
+            [10, 0, 0, 2],
+            [192, 0, 2, 1],
+            &tcp,
+        ))
+    }
+
+    /// The MSS in the first MSS option of `pkt`.
+    fn mss_of(pkt: &[u8]) -> u16 {
+        let tcp = &pkt[pkt.len() - tcp_len(pkt)..];
+        let pos = tcp[20..].windows(2).position(|w| w == [2, 4]).unwrap() + 20;
+        u16::from_be_bytes([tcp[pos + 2], tcp[pos + 3]])
+    }
+
+    #[test]
+    fn mss_clamp_v4() {
+        let mut pkt = syn_v4(&MSS_1460);
+        assert!(tcp_mss_clamp(&mut pkt, 1380));
+        assert_eq!(mss_of(&pkt), 1380);
+        assert_eq!(stored_checksum(&pkt), tcp_checksum(&pkt));
+
+        // A SYN-ACK gets the same treatment
+        let tcp = tcp_header(SYN | ACK, &MSS_1460);
+        let mut pkt = with_checksum(ipv4_packet(
+            TCP_PROTOCOL,
+            [10, 0, 0, 2],
+            [10, 0, 0, 1],
+            &tcp,
+        ));
+        assert!(tcp_mss_clamp(&mut pkt, 1380));
+        assert_eq!(mss_of(&pkt), 1380);
+        assert_eq!(stored_checksum(&pkt), tcp_checksum(&pkt));
+    }
+
+    #[test]
+    fn mss_clamp_v6() {
+        let src = "fd00::2".parse().unwrap();
+        let dst = "2001:db8::1".parse().unwrap();
+        let tcp = tcp_header(SYN, &MSS_1460);
+        let mut pkt = with_checksum(ipv6_packet(TCP_PROTOCOL, src, dst, &tcp));
+        // The IPv6 header takes up another 20 bytes
+        assert!(tcp_mss_clamp(&mut pkt, 1380));
+        assert_eq!(mss_of(&pkt), 1360);
+        assert_eq!(stored_checksum(&pkt), tcp_checksum(&pkt));
+    }
+
+    #[test]
+    fn mss_clamp_at_odd_offset() {
+        // A single no-op puts the MSS value at an odd offset, straddling the checksum's words
+        let options = [&[1][..], &MSS_1460, &[1, 1, 1]].concat();
+        for mss in [1380, 1200, 536, 0x0100, 0x00FF] {
+            let mut pkt = syn_v4(&options);
+            assert!(tcp_mss_clamp(&mut pkt, mss));
+            assert_eq!(mss_of(&pkt), mss);
+            assert_eq!(stored_checksum(&pkt), tcp_checksum(&pkt), "MSS {}", mss);
+        }
+    }
+
+    #[test]
+    fn mss_clamp_after_other_options() {
+        // SACK permitted, window scale & a timestamp ahead of the MSS
+        let options = [
+            &[4, 2, 3, 3, 7][..],
+            &[8, 10, 0, 0, 0, 1, 0, 0, 0, 0],
+            &MSS_1460,
+        ]
+        .concat();
+        let mut pkt = syn_v4(&options);
+        assert!(tcp_mss_clamp(&mut pkt, 1380));
+        assert_eq!(mss_of(&pkt), 1380);
+        assert_eq!(stored_checksum(&pkt), tcp_checksum(&pkt));
+    }
+
+    #[test]
+    fn mss_clamp_leaves_other_packets_alone() {
+        let untouched = |mut pkt: Vec<u8>| {
+            let original = pkt.clone();
+            assert!(!tcp_mss_clamp(&mut pkt, 1380));
+            assert_eq!(pkt, original);
+        };
+
+        // Already small enough
+        untouched(syn_v4(&[2, 4, 0x05, 0x00]));
+        // No MSS option at all
+        untouched(syn_v4(&[]));
+        // Not a SYN
+        let tcp = tcp_header(ACK, &MSS_1460);
+        untouched(ipv4_packet(
+            TCP_PROTOCOL,
+            [10, 0, 0, 2],
+            [10, 0, 0, 1],
+            &tcp,
+        ));
+        // Not TCP
+        untouched(ipv4_packet(
+            17,
+            [10, 0, 0, 2],
+            [10, 0, 0, 1],
+            &tcp_header(SYN, &MSS_1460),
+        ));
+        // A later fragment only looks like a TCP header
+        let mut fragment = syn_v4(&MSS_1460);
+        fragment[6..8].copy_from_slice(&0x00B9u16.to_be_bytes());
+        untouched(fragment);
+        // Options running past the end of the packet
+        let pkt = syn_v4(&MSS_1460);
+        untouched(pkt[..pkt.len() - 2].to_vec());
+        // An option claiming to be too short to hold its own length
+        untouched(syn_v4(&[3, 1, 0, 0]));
+        untouched(vec![]);
+        untouched(vec![0x45; 10]);
+    }
+
+    #[test]
+    fn checksum_adjust_matches_recomputing() {
+        let mut words = [
+            0x4500u16, 0x0073, 0x0000, 0x4000, 0x4011, 0xc0a8, 0x0001, 0xc0a8,
+        ];
+        let checksum = |words: &[u16]| {
+            let bytes = words
+                .iter()
+                .flat_map(|w| w.to_be_bytes())
+                .collect::<Vec<_>>();
+            let mut sum = sum_words(&bytes);
+            while sum > 0xFFFF {
+                sum = (sum & 0xFFFF) + (sum >> 16);
+            }
+            !(sum as u16)
+        };
+
+        for (idx, new) in [(1, 0x0074), (3, 0x0000), (5, 0xFFFF), (7, 0x0000)] {
+            let before = checksum(&words);
+            let old = words[idx];
+            words[idx] = new;
+            assert_eq!(checksum_adjust(before, old, new), checksum(&words));
+        }
+    }
+}
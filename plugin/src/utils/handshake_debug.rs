@@ -0,0 +1,25 @@
+//! Helpers for picking apart raw WireGuard messages.
+
+/// Handshake initiation message type
+pub const HANDSHAKE_INIT: u8 = 1;
+/// Handshake response message type
+pub const HANDSHAKE_RESPONSE: u8 = 2;
+/// Cookie reply message type
+pub const COOKIE_REPLY: u8 = 3;
+/// Transport data message type
+pub const DATA: u8 = 4;
+
+/// Extract the receiver index from a WireGuard message.
+///
+/// This is the index the remote uses to refer to our side of the session. Handshake
+/// initiations don't carry one (there's no session yet) so this returns `None` for those
+/// as well as for anything too short or otherwise malformed.
+pub fn parse_receiver_index(frame: &[u8]) -> Option<u32> {
+    let range = match *frame.first()? {
+        HANDSHAKE_RESPONSE => 8..12,
+        COOKIE_REPLY | DATA => 4..8,
+        _ => return None,
+    };
+    let idx = frame.get(range)?.try_into().ok()?;
+    Some(u32::from_le_bytes(idx))
+}
@@ -0,0 +1,151 @@
+//! A binary radix trie for longest-prefix matching of IP addresses.
+
+use std::net::IpAddr;
+
+use ipnetwork::IpNetwork;
+
+/// A single node in the trie, one level per bit of the address.
+#[derive(Default)]
+struct Node {
+    /// The subtries for when the next bit is 0 or 1 respectively
+    children: [Option<Box<Node>>; 2],
+    /// The value associated with the prefix ending at this node, if any
+    value: Option<u32>,
+}
+
+impl Node {
+    fn insert(&mut self, key: u128, prefix: u8, value: u32) {
+        let mut node = self;
+        for i in 0..prefix {
+            node = node.children[bit_at(key, i)]
+                .get_or_insert_with(Box::default)
+                .as_mut();
+        }
+        node.value = Some(value);
+    }
+
+    fn longest_match(&self, key: u128, len: u8) -> Option<u32> {
+        let mut node = self;
+        let mut best = node.value;
+        for i in 0..len {
+            match node.children[bit_at(key, i)].as_deref() {
+                Some(child) => node = child,
+                None => break,
+            }
+            best = node.value.or(best);
+        }
+        best
+    }
+}
+
+/// Returns the `i`th most significant bit of `key`.
+fn bit_at(key: u128, i: u8) -> usize {
+    ((key >> (127 - i)) & 1) as usize
+}
+
+/// Left-align the address bits so IPv4 & IPv6 can share the same trie logic.
+fn to_key(addr: IpAddr) -> (u128, u8) {
+    match addr {
+        IpAddr::V4(addr) => ((u32::from(addr) as u128) << 96, 32),
+        IpAddr::V6(addr) => (u128::from(addr), 128),
+    }
+}
+
+/// Maps IP network prefixes to values (e.g. peer indices) and supports
+/// looking up the value for the most specific prefix containing an address.
+///
+/// Lookups walk at most one node per address bit so they're independent of
+/// the number of prefixes inserted.
+#[derive(Default)]
+pub struct NetworkPrefixMatcher {
+    v4: Node,
+    v6: Node,
+}
+
+impl NetworkPrefixMatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Associate `value` with `prefix`, replacing any value previously set for the same prefix.
+    pub fn insert(&mut self, prefix: IpNetwork, value: u32) {
+        let (key, _) = to_key(prefix.network());
+        match prefix {
+            IpNetwork::V4(_) => self.v4.insert(key, prefix.prefix(), value),
+            IpNetwork::V6(_) => self.v6.insert(key, prefix.prefix(), value),
+        }
+    }
+
+    /// Find the value associated with the longest prefix containing `addr`, if any.
+    pub fn longest_match(&self, addr: IpAddr) -> Option<u32> {
+        let (key, len) = to_key(addr);
+        match addr {
+            IpAddr::V4(_) => self.v4.longest_match(key, len),
+            IpAddr::V6(_) => self.v6.longest_match(key, len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(prefixes: &[(&str, u32)]) -> NetworkPrefixMatcher {
+        let mut matcher = NetworkPrefixMatcher::new();
+        for &(prefix, value) in prefixes {
+            matcher.insert(prefix.parse().unwrap(), value);
+        }
+        matcher
+    }
+
+    fn lookup(matcher: &NetworkPrefixMatcher, addr: &str) -> Option<u32> {
+        matcher.longest_match(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let matcher = matcher(&[("10.0.0.0/8", 0), ("10.1.0.0/16", 1), ("10.1.2.0/24", 2)]);
+        assert_eq!(lookup(&matcher, "10.2.3.4"), Some(0));
+        assert_eq!(lookup(&matcher, "10.1.3.4"), Some(1));
+        assert_eq!(lookup(&matcher, "10.1.2.4"), Some(2));
+        assert_eq!(lookup(&matcher, "11.0.0.1"), None);
+    }
+
+    #[test]
+    fn insertion_order_does_not_matter() {
+        let matcher = matcher(&[("10.1.2.0/24", 2), ("10.0.0.0/8", 0)]);
+        assert_eq!(lookup(&matcher, "10.1.2.4"), Some(2));
+        assert_eq!(lookup(&matcher, "10.1.3.4"), Some(0));
+    }
+
+    #[test]
+    fn default_routes() {
+        let matcher = matcher(&[("0.0.0.0/0", 0), ("::/0", 1), ("192.168.1.1/32", 2)]);
+        assert_eq!(lookup(&matcher, "8.8.8.8"), Some(0));
+        assert_eq!(lookup(&matcher, "192.168.1.1"), Some(2));
+        assert_eq!(lookup(&matcher, "192.168.1.2"), Some(0));
+        assert_eq!(lookup(&matcher, "2001:db8::1"), Some(1));
+    }
+
+    #[test]
+    fn families_are_kept_apart() {
+        // ::/96 covers the same bits as all of IPv4 once left-aligned
+        let matcher = matcher(&[("::/96", 0), ("fd00::/8", 1)]);
+        assert_eq!(lookup(&matcher, "0.0.0.1"), None);
+        assert_eq!(lookup(&matcher, "::1"), Some(0));
+        assert_eq!(lookup(&matcher, "fd12::1"), Some(1));
+        assert_eq!(lookup(&matcher, "fe80::1"), None);
+    }
+
+    #[test]
+    fn host_bits_of_prefix_are_ignored() {
+        let matcher = matcher(&[("10.1.2.3/16", 0)]);
+        assert_eq!(lookup(&matcher, "10.1.200.1"), Some(0));
+    }
+
+    #[test]
+    fn insert_replaces_value() {
+        let matcher = matcher(&[("10.0.0.0/8", 0), ("10.0.0.0/8", 1)]);
+        assert_eq!(lookup(&matcher, "10.0.0.1"), Some(1));
+    }
+}
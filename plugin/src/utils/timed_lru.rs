@@ -0,0 +1,178 @@
+//! A small LRU cache whose entries also expire after a given time-to-live.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// A cached value along with the bookkeeping needed to expire or evict it.
+struct Entry<V> {
+    value: V,
+    /// When the entry stops being valid
+    expires: Instant,
+    /// The tick at which the entry was last accessed
+    last_used: u64,
+}
+
+/// A cache holding at most `capacity` entries, evicting the least recently used entry
+/// once full. Entries are also dropped once their TTL has elapsed, even if there's room.
+///
+/// Caches are expected to hold a handful of entries (e.g. resolved peer endpoints) so
+/// finding the least recently used entry is just a linear scan.
+///
+/// Wrap it in a `Mutex` to share it between threads.
+pub struct TimedLru<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    capacity: usize,
+    /// Monotonically increasing counter used to order accesses
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> TimedLru<K, V> {
+    /// Create an empty cache which will hold at most `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::with_capacity(capacity),
+            capacity: capacity.max(1),
+            tick: 0,
+        }
+    }
+
+    /// Returns the cached value for `key`, calling `f` to create it (valid for `ttl`)
+    /// if it's missing or has expired.
+    pub fn get_or_insert_with(&mut self, key: K, ttl: Duration, f: impl FnOnce() -> V) -> &V {
+        let now = Instant::now();
+        self.tick += 1;
+
+        let expired = self
+            .entries
+            .get(&key)
+            .is_some_and(|entry| entry.expires <= now);
+        if expired {
+            self.entries.remove(&key);
+        }
+        if !self.entries.contains_key(&key) {
+            self.make_room(now);
+        }
+
+        let tick = self.tick;
+        let entry = self.entries.entry(key).or_insert_with(|| Entry {
+            value: f(),
+            expires: now + ttl,
+            last_used: tick,
+        });
+        entry.last_used = tick;
+        &entry.value
+    }
+
+    /// The number of entries in the cache, including any which may have expired.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Make sure there's space for at least one more entry.
+    fn make_room(&mut self, now: Instant) {
+        // Anything expired can go first
+        self.entries.retain(|_, entry| entry.expires > now);
+
+        // Then fall back to the least recently used entry
+        if self.entries.len() >= self.capacity {
+            let lru = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(lru) = lru {
+                self.entries.remove(&lru);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::thread;
+
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn caches_value() {
+        let mut cache = TimedLru::new(4);
+        assert_eq!(*cache.get_or_insert_with("a", TTL, || 1), 1);
+        assert_eq!(*cache.get_or_insert_with("a", TTL, || 2), 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn expired_entry_is_replaced() {
+        let mut cache = TimedLru::new(4);
+        cache.get_or_insert_with("a", Duration::ZERO, || 1);
+        assert_eq!(*cache.get_or_insert_with("a", TTL, || 2), 2);
+        assert_eq!(*cache.get_or_insert_with("a", TTL, || 3), 2);
+    }
+
+    #[test]
+    fn expired_entries_make_room_first() {
+        let mut cache = TimedLru::new(2);
+        cache.get_or_insert_with("a", TTL, || 1);
+        cache.get_or_insert_with("b", Duration::ZERO, || 2);
+        cache.get_or_insert_with("c", TTL, || 3);
+
+        // "b" had expired so the older "a" got to stay
+        assert_eq!(cache.len(), 2);
+        assert_eq!(*cache.get_or_insert_with("a", TTL, || 4), 1);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = TimedLru::new(2);
+        cache.get_or_insert_with("a", TTL, || 1);
+        cache.get_or_insert_with("b", TTL, || 2);
+        // Touch "a" so "b" is now the least recently used
+        cache.get_or_insert_with("a", TTL, || 0);
+        cache.get_or_insert_with("c", TTL, || 3);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(*cache.get_or_insert_with("a", TTL, || 0), 1);
+        assert_eq!(*cache.get_or_insert_with("c", TTL, || 0), 3);
+        assert_eq!(*cache.get_or_insert_with("b", TTL, || 4), 4);
+    }
+
+    #[test]
+    fn zero_capacity_still_holds_one() {
+        let mut cache = TimedLru::new(0);
+        assert_eq!(*cache.get_or_insert_with("a", TTL, || 1), 1);
+        assert_eq!(*cache.get_or_insert_with("a", TTL, || 2), 1);
+    }
+
+    #[test]
+    fn concurrent_access() {
+        let cache = Mutex::new(TimedLru::new(4));
+        let created = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for i in 0..100 {
+                        let mut cache = cache.lock().unwrap();
+                        let value = *cache.get_or_insert_with(i % 4, TTL, || {
+                            created.fetch_add(1, Ordering::Relaxed);
+                            i % 4
+                        });
+                        assert_eq!(value, i % 4);
+                    }
+                });
+            }
+        });
+
+        // Everything fit so each value was only ever created the once
+        assert_eq!(created.into_inner(), 4);
+    }
+}